@@ -6,80 +6,332 @@ use ratatui::{
     widgets::Paragraph,
     Terminal,
 };
-use rune_core::{
-    cmd::register::register_create_home,
-    crypto::keyring::{Keyring, KeyringEncryptor},
-};
+use rune_core::client::Profile;
+use rune_core::{passphrase::weakness, Error, RuneClient};
+use zeroize::Zeroizing;
 
-use crate::theme;
+use crate::theme::Theme;
 
+// Asks for a username and a passphrase for its keyring. A weak passphrase, a
+// confirmation that doesn't match or a failed registration is shown and asked
+// again; Esc goes back to the menu.
 pub fn handle_register(
     term: &mut Terminal<CrosstermBackend<Stderr>>,
-) -> Result<(), std::io::Error> {
-    let username_input_field = crate::widgets::InputField::new("Enter username")
-        .style(theme::prompt_field_style())
-        .block(theme::prompt_field_block());
+    client: &RuneClient,
+) -> rune_core::Result<()> {
+    let theme = Theme::named(client.config().theme.as_deref());
+    let mut error = None;
+
+    loop {
+        let Some(user) = prompt(term, &theme, "Enter username", false, error.take())? else {
+            return Ok(());
+        };
+        let pass = loop {
+            let passphrase = prompt(
+                term,
+                &theme,
+                "Enter passphrase to encrypt keyring",
+                true,
+                error.take(),
+            )?;
+            let Some(pass) = passphrase.map(Zeroizing::new) else {
+                return Ok(());
+            };
+            if let Some(weakness) = weakness(&pass) {
+                error = Some(format!("passphrase too weak, {}", weakness));
+                continue;
+            }
+            let Some(confirm) =
+                prompt(term, &theme, "Repeat to confirm", true, None)?.map(Zeroizing::new)
+            else {
+                return Ok(());
+            };
+            if *confirm != *pass {
+                error = Some("passphrases don't match".to_string());
+                continue;
+            }
+            break pass;
+        };
+
+        match client.register(&user, &pass) {
+            Ok(_) => return Ok(()),
+            Err(e) => error = Some(e.to_string()),
+        }
+    }
+}
+
+// Ask for a line of input in the middle of the screen, after `error` if there
+// is one. None if it's left empty or Esc is pressed.
+fn prompt(
+    term: &mut Terminal<CrosstermBackend<Stderr>>,
+    theme: &Theme,
+    label: &str,
+    masked: bool,
+    error: Option<String>,
+) -> rune_core::Result<Option<String>> {
+    let label = match error {
+        Some(error) => format!("{}. {}", error, label),
+        None => label.to_string(),
+    };
+    let mut field = crate::widgets::InputField::new(&label).themed(theme);
+    if masked {
+        field = field.masked();
+    }
+
+    term.draw(|frame| {
+        let area = super::util::centered(frame.size(), field.width(), field.height());
+        frame.render_widget(field.clone(), area);
+    })?;
+
+    Ok(super::util::read_input(term, field))
+}
+
+// `username`'s keyring from rune-agent, or else ask for the passphrase
+pub fn unlock(
+    term: &mut Terminal<CrosstermBackend<Stderr>>,
+    client: &RuneClient,
+    username: &str,
+) -> rune_core::Result<Profile> {
+    if let Some(profile) = client.unlock_from_agent(username) {
+        return Ok(profile);
+    }
 
+    let theme = Theme::named(client.config().theme.as_deref());
+    let passphrase_field =
+        crate::widgets::InputField::new(&format!("Passphrase for {}'s keyring", username))
+            .masked()
+            .themed(&theme);
     term.draw(|frame| {
         let area = frame.size();
 
-        let centered_x = ((area.width.saturating_sub(username_input_field.width())) / 2)
-            .min(area.width - username_input_field.width());
-        let centered_y = ((area.height.saturating_sub(username_input_field.height())) / 2)
-            .min(area.height - username_input_field.height());
+        let centered_x = ((area.width.saturating_sub(passphrase_field.width())) / 2)
+            .min(area.width - passphrase_field.width());
+        let centered_y = ((area.height.saturating_sub(passphrase_field.height())) / 2)
+            .min(area.height - passphrase_field.height());
         let area = Rect::new(
             centered_x,
             centered_y,
-            username_input_field.width(),
-            username_input_field.height(),
+            passphrase_field.width(),
+            passphrase_field.height(),
         );
 
-        frame.render_widget(username_input_field.clone(), area);
+        frame.render_widget(passphrase_field.clone(), area);
     })?;
 
-    let username = super::util::read_input(term, username_input_field);
+    let passphrase = super::util::read_input(term, passphrase_field)
+        .map(Zeroizing::new)
+        .ok_or_else(|| Error::Invalid("no passphrase entered".to_string()))?;
+    client.unlock(username, &passphrase)
+}
 
-    if let Some(user) = username {
-        register_create_home(&user)?;
-        let keypair = Keyring::generate();
-        keypair.save_public_key(&user)?;
+pub fn handle_view_key(
+    term: &mut Terminal<CrosstermBackend<Stderr>>,
+    client: &RuneClient,
+) -> rune_core::Result<()> {
+    let report = client
+        .whoami(None)
+        .and_then(|username| client.fingerprint(&username, None))
+        .unwrap_or_else(|e| format!("No key to show: {}\n", e));
+    let theme = Theme::named(client.config().theme.as_deref());
+    show_report(term, &theme, "View Key", &report)
+}
 
-        let passphrase_field =
-            crate::widgets::InputField::new("Enter passphrase to encrypt keyring").default_style();
-        let passphrase = super::util::read_input(term, passphrase_field);
+// Asks who to send a message to and what it says, and sends it from the
+// active profile. Esc at either prompt goes back to the menu.
+pub fn handle_send(
+    term: &mut Terminal<CrosstermBackend<Stderr>>,
+    client: &mut RuneClient,
+) -> rune_core::Result<()> {
+    let theme = Theme::named(client.config().theme.as_deref());
+    let Some(recipient) = prompt(term, &theme, "Send to", false, None)? else {
+        return Ok(());
+    };
+    let Some(message) = prompt(
+        term,
+        &theme,
+        &format!("Message to {}", recipient),
+        false,
+        None,
+    )?
+    else {
+        return Ok(());
+    };
 
-        if let Some(pass) = passphrase {
-            let encryptor = KeyringEncryptor::from(keypair);
-            let home_dir = dirs::home_dir().unwrap();
-            let path_to_keyring =
-                format!("{}/.config/rune/{}/keyring.enc", home_dir.display(), user);
-            std::fs::create_dir_all(format!("{}/.config/rune/{}", home_dir.display(), user))?;
-            encryptor.encrypt(&path_to_keyring, &pass)?;
-            return Ok(());
-        } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "No passphrase entered",
-            ));
+    let sent = client.whoami(None).and_then(|username| {
+        let profile = unlock(term, client, &username)?;
+        client.send(&profile, &recipient, &message, false)
+    });
+    let report = match sent {
+        Ok(()) => format!("Sent to {}\n", recipient),
+        Err(e) => format!("Couldn't send to {}: {}\n", recipient, e),
+    };
+    show_report(term, &theme, "Send Message", &report)
+}
+
+// Fetches new messages for the active profile and shows the unread ones,
+// marking them read
+pub fn handle_receive(
+    term: &mut Terminal<CrosstermBackend<Stderr>>,
+    client: &mut RuneClient,
+) -> rune_core::Result<()> {
+    use rune_core::cmd::inbox::AfterRead;
+    use std::fmt::Write;
+
+    let theme = Theme::named(client.config().theme.as_deref());
+    let opened = client.whoami(None).and_then(|username| {
+        let profile = unlock(term, client, &username)?;
+        client.fetch(&profile)?;
+        let unread = client.inbox(&username, false)?;
+        client.read(&profile, unread, AfterRead::MarkRead)
+    });
+    let report = match opened {
+        Ok(opened) if opened.received.is_empty() && opened.skipped.is_empty() => {
+            Zeroizing::new("No unread messages\n".to_string())
         }
-    } else {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "No username entered",
-        ));
-    }
+        Ok(opened) => {
+            // it holds the decrypted messages
+            let mut report = Zeroizing::new(String::new());
+            for skipped in &opened.skipped {
+                let _ = writeln!(report, "{}", skipped);
+            }
+            for msg in &opened.received {
+                let _ = match &msg.group {
+                    Some(group) => {
+                        writeln!(report, "{} in {}: {}", msg.sender, group, msg.body.as_str())
+                    }
+                    None => writeln!(report, "{}: {}", msg.sender, msg.body.as_str()),
+                };
+            }
+            report
+        }
+        Err(e) => Zeroizing::new(format!("Couldn't receive messages: {}\n", e)),
+    };
+    show_report(term, &theme, "Receive Message", report.as_str())
 }
 
-pub fn handle_view_key(
+// Show `report` under `title` until a key is pressed
+fn show_report(
     term: &mut Terminal<CrosstermBackend<Stderr>>,
-) -> Result<(), std::io::Error> {
+    theme: &Theme,
+    title: &str,
+    report: &str,
+) -> rune_core::Result<()> {
+    use crossterm::event::{self, Event};
+
     term.draw(|frame| {
         let area = frame.size();
         frame.render_widget(
-            Paragraph::new("View Key").style(Style::default().white()),
+            Paragraph::new(format!("{}\nPress any key to go back", report))
+                .style(Style::default().white())
+                .block(theme.report_block().title(title.to_string())),
             area,
         );
     })?;
 
-    todo!();
+    loop {
+        if let Event::Key(_) = event::read()? {
+            return Ok(());
+        }
+    }
+}
+
+// Lists your groups and their members, Enter sends a message to the selected one
+pub fn handle_groups(
+    term: &mut Terminal<CrosstermBackend<Stderr>>,
+    client: &mut RuneClient,
+) -> rune_core::Result<()> {
+    use crossterm::event::{self, Event, KeyCode};
+    use ratatui::layout::{Constraint, Direction, Layout};
+    use ratatui::widgets::{Block, Borders, List, ListItem};
+
+    let theme = Theme::named(client.config().theme.as_deref());
+    let username = client.whoami(None)?;
+    let profile = unlock(term, client, &username)?;
+    let mut groups = client.groups(&profile)?;
+    let mut selected = 0usize;
+    let mut status = String::from("Enter to send to a group, q to go back");
+
+    loop {
+        term.draw(|frame| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(3), Constraint::Length(3)])
+                .split(frame.size());
+
+            let items: Vec<ListItem> = match groups.is_empty() {
+                true => vec![
+                    ListItem::new("No groups, create one with `rune group create`")
+                        .style(theme.list_item_default()),
+                ],
+                false => groups
+                    .iter()
+                    .enumerate()
+                    .map(|(i, group)| {
+                        let info = &group.info;
+                        let members = match info.is_member(&username) {
+                            true => info
+                                .members
+                                .iter()
+                                .map(|m| m.username.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                            false => "you were removed".to_string(),
+                        };
+                        ListItem::new(format!("{}  ({})", info.name, members)).style(
+                            match i == selected {
+                                true => theme.list_item_selected(),
+                                false => theme.list_item_default(),
+                            },
+                        )
+                    })
+                    .collect(),
+            };
+            frame.render_widget(
+                List::new(items).block(Block::default().borders(Borders::ALL).title("Groups")),
+                chunks[0],
+            );
+            frame.render_widget(
+                Paragraph::new(status.as_str())
+                    .style(Style::default().white())
+                    .block(Block::default().borders(Borders::ALL)),
+                chunks[1],
+            );
+        })?;
+
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Up => selected = selected.saturating_sub(1),
+                KeyCode::Down if selected + 1 < groups.len() => selected += 1,
+                KeyCode::Enter if !groups.is_empty() => {
+                    let name = groups[selected].info.name.clone();
+                    let field = crate::widgets::InputField::new(&format!("Message to {}", name))
+                        .themed(&theme);
+                    term.draw(|frame| {
+                        let area = frame.size();
+
+                        let centered_x = ((area.width.saturating_sub(field.width())) / 2)
+                            .min(area.width - field.width());
+                        let centered_y = ((area.height.saturating_sub(field.height())) / 2)
+                            .min(area.height - field.height());
+                        let area = Rect::new(centered_x, centered_y, field.width(), field.height());
+
+                        frame.render_widget(field.clone(), area);
+                    })?;
+
+                    let id = groups[selected].info.id_hex();
+                    status = match super::util::read_input(term, field) {
+                        Some(message) => match client.send_to_group(&profile, &id, &message) {
+                            Ok(()) => format!("Sent to {}", name),
+                            Err(e) => format!("Couldn't send to {}: {}", name, e),
+                        },
+                        None => "Nothing sent".to_string(),
+                    };
+                    // sending may have handed out a new sender key
+                    groups = client.groups(&profile)?;
+                }
+                KeyCode::Esc | KeyCode::Char('q') => return Ok(()),
+                _ => {}
+            }
+        }
+    }
 }
@@ -1,5 +1,3 @@
-#![allow(dead_code)]
-
 use crossterm::{
     event::{self, KeyCode},
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
@@ -10,7 +8,8 @@ use ratatui::{
     style::Style,
     widgets::{List, ListItem, Paragraph},
 };
-use std::io::{stderr, Result};
+use rune_core::{Result, RuneClient};
+use std::io::{stderr, Stderr};
 
 mod handlers;
 mod statics;
@@ -21,18 +20,33 @@ mod widgets;
 use statics::ASCII_ART as LOGO;
 
 fn main() -> Result<()> {
+    let mut client = RuneClient::from_env(None)?;
+
     stderr().execute(EnterAlternateScreen)?;
-    enable_raw_mode()?;
+    let result = enable_raw_mode()
+        .and_then(|_| Terminal::new(CrosstermBackend::new(stderr())))
+        .map_err(Into::into)
+        .and_then(|mut terminal| run(&mut terminal, &mut client));
+
+    // put the terminal back however the menu ended, so an error is readable
+    let restored =
+        disable_raw_mode().and_then(|_| stderr().execute(LeaveAlternateScreen).map(|_| ()));
+    result?;
+    restored?;
+    Ok(())
+}
 
-    let mut terminal = Terminal::new(CrosstermBackend::new(stderr()))?;
+fn run(terminal: &mut Terminal<CrosstermBackend<Stderr>>, client: &mut RuneClient) -> Result<()> {
+    let theme = theme::Theme::named(client.config().theme.as_deref());
     terminal.clear()?;
 
     let mut selected_option = 0usize;
-    let options = vec![
+    let options = [
         "Register",
         "View Key",
         "Send Message",
         "Receive Message",
+        "Groups",
         "Quit",
     ];
 
@@ -49,9 +63,9 @@ fn main() -> Result<()> {
                 .map(|option| {
                     ListItem::new(option.to_string()).style(
                         if option == &options[selected_option] {
-                            theme::list_item_selected()
+                            theme.list_item_selected()
                         } else {
-                            theme::list_item_default()
+                            theme.list_item_default()
                         },
                     )
                 })
@@ -68,45 +82,26 @@ fn main() -> Result<()> {
         if let event::Event::Key(key) = event::read()? {
             match key.code {
                 KeyCode::Up => {
-                    if selected_option > 0 {
-                        selected_option -= 1;
-                    }
+                    selected_option = selected_option.saturating_sub(1);
                 }
-                KeyCode::Down => {
-                    if selected_option < options.len() - 1 {
-                        selected_option += 1;
-                    }
+                KeyCode::Down if selected_option < options.len() - 1 => {
+                    selected_option += 1;
                 }
                 KeyCode::Enter => match options[selected_option] {
                     "Register" => {
-                        handlers::handle_register(&mut terminal)?;
+                        handlers::handle_register(terminal, client)?;
                     }
                     "View Key" => {
-                        terminal.draw(|frame| {
-                            let area = frame.size();
-                            frame.render_widget(
-                                Paragraph::new("View Key").style(Style::default().white()),
-                                area,
-                            );
-                        })?;
+                        handlers::handle_view_key(terminal, client)?;
                     }
                     "Send Message" => {
-                        terminal.draw(|frame| {
-                            let area = frame.size();
-                            frame.render_widget(
-                                Paragraph::new("Send Message").style(Style::default().white()),
-                                area,
-                            );
-                        })?;
+                        handlers::handle_send(terminal, client)?;
+                    }
+                    "Receive Message" => {
+                        handlers::handle_receive(terminal, client)?;
                     }
-                    "Read Message" => {
-                        terminal.draw(|frame| {
-                            let area = frame.size();
-                            frame.render_widget(
-                                Paragraph::new("Read Message").style(Style::default().white()),
-                                area,
-                            );
-                        })?;
+                    "Groups" => {
+                        handlers::handle_groups(terminal, client)?;
                     }
                     "Quit" => {
                         break;
@@ -119,7 +114,5 @@ fn main() -> Result<()> {
         }
     }
 
-    stderr().execute(LeaveAlternateScreen)?;
-    disable_raw_mode()?;
     Ok(())
 }
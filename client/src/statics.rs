@@ -1,4 +1,4 @@
-pub static ASCII_ART: &'static str = r#"
+pub static ASCII_ART: &str = r#"
          /\    \                 /\    \                  /\    \                  /\    \         
         /::\    \               /::\____\                /::\____\                /::\    \        
        /::::\    \             /:::/    /               /::::|   |               /::::\    \       
@@ -1,33 +1,80 @@
 use ratatui::{
-    style::{Color, Modifier, Style, Stylize},
+    style::{Color, Modifier, Style},
     widgets::{Block, BorderType, Borders},
 };
 
-const PRIMARY: Color = Color::DarkGray;
-const SECONDARY: Color = Color::Cyan;
-const BACKGROUND: Color = Color::Black;
-const ACCENT: Color = Color::Yellow;
-const TEXT: Color = Color::White;
-
-pub fn list_item_default() -> Style {
-    Style::default().fg(TEXT).bg(PRIMARY)
+// Colors for the TUI, picked by the `theme` setting in rune.toml
+#[derive(Debug, Clone, Copy)]
+pub struct Theme {
+    primary: Color,
+    secondary: Color,
+    background: Color,
+    accent: Color,
+    text: Color,
+    prompt: Color,
 }
 
-pub fn list_item_selected() -> Style {
-    Style::default()
-        .fg(BACKGROUND)
-        .bg(SECONDARY)
-        .add_modifier(Modifier::BOLD)
-}
+const DARK: Theme = Theme {
+    primary: Color::DarkGray,
+    secondary: Color::Cyan,
+    background: Color::Black,
+    accent: Color::Yellow,
+    text: Color::White,
+    prompt: Color::Blue,
+};
+
+const LIGHT: Theme = Theme {
+    primary: Color::Gray,
+    secondary: Color::Blue,
+    background: Color::White,
+    accent: Color::Magenta,
+    text: Color::Black,
+    prompt: Color::LightBlue,
+};
 
-pub fn prompt_field_style() -> Style {
-    Style::default().fg(Color::White).bg(Color::Blue)
+impl Default for Theme {
+    fn default() -> Self {
+        DARK
+    }
 }
 
-pub fn prompt_field_block() -> Block<'static> {
-    Block::default()
-        .borders(Borders::LEFT | Borders::RIGHT)
-        .border_style(Style::default().fg(Color::White))
-        .border_type(BorderType::Rounded)
-        .style(Style::default().bg(Color::Blue))
+impl Theme {
+    // The theme called `name`, or the default one for unknown names
+    pub fn named(name: Option<&str>) -> Self {
+        match name {
+            None | Some("dark") => DARK,
+            Some("light") => LIGHT,
+            Some(_) => Self::default(),
+        }
+    }
+
+    pub fn list_item_default(&self) -> Style {
+        Style::default().fg(self.text).bg(self.primary)
+    }
+
+    pub fn list_item_selected(&self) -> Style {
+        Style::default()
+            .fg(self.background)
+            .bg(self.secondary)
+            .add_modifier(Modifier::BOLD)
+    }
+
+    pub fn prompt_field_style(&self) -> Style {
+        Style::default().fg(Color::White).bg(self.prompt)
+    }
+
+    pub fn prompt_field_block(&self) -> Block<'static> {
+        Block::default()
+            .borders(Borders::LEFT | Borders::RIGHT)
+            .border_style(Style::default().fg(Color::White))
+            .border_type(BorderType::Rounded)
+            .style(Style::default().bg(self.prompt))
+    }
+
+    // Around a screen of text, like the one View Key shows
+    pub fn report_block(&self) -> Block<'static> {
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(self.accent))
+    }
 }
@@ -2,7 +2,6 @@ use crate::widgets::InputField;
 use crossterm::event::{self, KeyCode};
 use ratatui::{
     prelude::{CrosstermBackend, Rect},
-    terminal::CompletedFrame,
     Terminal,
 };
 use std::io::Stderr;
@@ -29,6 +28,18 @@ pub fn get_areas(frame_area: Rect) -> (LogoArea, ListArea) {
     (logo_area, list_area)
 }
 
+// A `width` by `height` area in the middle of `area`, cut down to fit
+pub fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let (width, height) = (width.min(area.width), height.min(area.height));
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+// What's typed into `field`, or None if it's left empty or Esc is pressed
 pub fn read_input(
     term: &mut Terminal<CrosstermBackend<Stderr>>,
     mut field: InputField,
@@ -51,11 +62,10 @@ pub fn read_input(
                         return Some(input);
                     }
                 }
-                KeyCode::Backspace => {
-                    if field.get_cursor() > 0 {
-                        input.remove(field.get_cursor() - 1);
-                        field.decrement_cursor();
-                    }
+                KeyCode::Esc => return None,
+                KeyCode::Backspace if field.get_cursor() > 0 => {
+                    input.remove(field.get_cursor() - 1);
+                    field.decrement_cursor();
                 }
                 KeyCode::Left => field.decrement_cursor(),
                 KeyCode::Right => field.increment_cursor(),
@@ -65,19 +75,17 @@ pub fn read_input(
             term.draw(|frame| {
                 let area = frame.size();
 
-                let centered_x = ((area.width.saturating_sub(field.width())) / 2)
-                    .min(area.width - field.width());
-                let centered_y = ((area.height.saturating_sub(field.height())) / 2)
-                    .min(area.height - field.height());
-                let area = Rect::new(centered_x, centered_y, field.width(), field.height());
+                let area = centered(area, field.width(), field.height());
 
                 let input_display = if input.is_empty() {
-                    placeholder
+                    placeholder.to_owned()
+                } else if field.is_masked() {
+                    "*".repeat(input.chars().count())
                 } else {
-                    &input
+                    input.clone()
                 };
 
-                field.set_value(input_display.to_owned());
+                field.set_value(input_display);
                 frame.render_widget(field.clone(), area);
             })
             .unwrap();
@@ -2,7 +2,7 @@ use ratatui::style::{Modifier, Style};
 use ratatui::widgets::Block;
 use ratatui::{buffer::Buffer, layout::Rect, widgets::Widget};
 
-use crate::theme;
+use crate::theme::Theme;
 
 #[derive(Clone)]
 pub struct InputField {
@@ -11,6 +11,8 @@ pub struct InputField {
     cursor: usize,
     style: Style,
     block: Option<Block<'static>>,
+    // show what's typed as `*`, for passphrases
+    masked: bool,
 }
 
 impl InputField {
@@ -21,21 +23,14 @@ impl InputField {
             cursor: 0usize,
             style: Style::default(),
             block: None,
+            masked: false,
         }
     }
 
-    pub fn value(&self) -> &str {
-        &self.value
-    }
-
     pub fn set_value(&mut self, value: String) {
         self.value = value;
     }
 
-    pub fn set_cursor(&mut self, pos: usize) {
-        self.cursor = pos;
-    }
-
     pub fn get_cursor(&self) -> usize {
         self.cursor
     }
@@ -70,9 +65,18 @@ impl InputField {
         self
     }
 
-    pub fn default_style(self) -> Self {
-        self.style(theme::prompt_field_style())
-            .block(theme::prompt_field_block())
+    pub fn masked(mut self) -> Self {
+        self.masked = true;
+        self
+    }
+
+    pub fn is_masked(&self) -> bool {
+        self.masked
+    }
+
+    pub fn themed(self, theme: &Theme) -> Self {
+        self.style(theme.prompt_field_style())
+            .block(theme.prompt_field_block())
     }
 }
 
@@ -85,11 +89,14 @@ impl Widget for InputField {
         let value = format!("{}: {}", self.prompt, self.value);
         buf.set_string(area.left(), area.top(), &value, self.style);
 
+        // the field may have been cut down to fit the screen
         let cursor_x = area.left() + self.prompt.len() as u16 + self.cursor as u16 + 2;
-        let cursor_rect = Rect::new(cursor_x, area.top(), 1, 1);
-        buf.set_style(
-            cursor_rect,
-            Style::default().add_modifier(Modifier::REVERSED),
-        );
+        if cursor_x < area.right() {
+            let cursor_rect = Rect::new(cursor_x, area.top(), 1, 1);
+            buf.set_style(
+                cursor_rect,
+                Style::default().add_modifier(Modifier::REVERSED),
+            );
+        }
     }
 }
@@ -0,0 +1,478 @@
+// rune-agent, which keeps unlocked keyrings so passphrases aren't asked for
+// on every command
+//
+// The agent listens on a Unix socket in the data directory (agent.sock next
+// to the profiles) and speaks the same frames as the relay, see
+// `crate::relay`, with its own `Request` and `Response`. `rune agent unlock`
+// hands it a passphrase, the agent decrypts the keyring itself and holds it
+// until the timeout runs out or `rune agent lock` is used.
+//
+// The secret key never leaves the agent. A keyring the agent holds is used
+// through `Keyring::held_by_agent`, which asks the agent to sign, do
+// Diffie-Hellman with the identity key and derive the keys for local state.
+// Only the socket's owner can connect, and anyone who can is trusted with
+// all of that, just like ssh-agent.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::config::Paths;
+use crate::crypto::e2ee::CryptoError;
+use crate::crypto::keyring::{Keyring, KeyringEncryptor};
+use crate::crypto::sign::SIGNATURE_LEN;
+use crate::error::Error;
+use crate::relay::{read_frame, write_frame};
+
+// How long a keyring stays unlocked when neither rune.toml, rune-agent
+// --timeout nor `rune agent unlock --timeout` say otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// Longest anyone can ask for, which also keeps expiry times from overflowing
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// `timeout` if it's no longer than `MAX_TIMEOUT`
+pub fn check_timeout(timeout: Duration) -> crate::Result<Duration> {
+    match timeout <= MAX_TIMEOUT {
+        true => Ok(timeout),
+        false => Err(Error::Invalid(format!(
+            "timeout of {}s is longer than the most rune-agent allows, {}s",
+            timeout.as_secs(),
+            MAX_TIMEOUT.as_secs()
+        ))),
+    }
+}
+
+// Neither side derives `Debug`: requests carry passphrases and responses
+// carry keys derived from the identity key
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    // Decrypt `username`'s keyring with `passphrase` and hold it for
+    // `timeout` seconds, or the agent's default
+    Unlock {
+        username: String,
+        passphrase: String,
+        timeout: Option<u64>,
+    },
+    // Forget `username`'s keyring, or every keyring
+    Lock {
+        username: Option<String>,
+    },
+    Status,
+    // The identity key of `username`'s keyring, if it's unlocked
+    Public {
+        username: String,
+    },
+    Sign {
+        username: String,
+        message: Vec<u8>,
+    },
+    // The identity key times `point`
+    DiffieHellman {
+        username: String,
+        point: [u8; 32],
+    },
+    // The key for local state labelled `purpose`, see `crypto::storage`
+    StorageKey {
+        username: String,
+        purpose: Vec<u8>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Response {
+    Unlocked { expires_in: u64 },
+    Locked { count: usize },
+    Status(Vec<Unlocked>),
+    Public([u8; 32]),
+    Signature(Vec<u8>),
+    SharedSecret([u8; 32]),
+    StorageKey([u8; 32]),
+    // the keyring asked for isn't unlocked, or its time ran out
+    NotUnlocked,
+    BadPassphrase,
+    Error(String),
+}
+
+// A keyring the agent holds, and how many seconds it has left
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Unlocked {
+    pub username: String,
+    pub expires_in: u64,
+}
+
+#[cfg(unix)]
+type Stream = std::os::unix::net::UnixStream;
+#[cfg(not(unix))]
+type Stream = std::net::TcpStream;
+
+#[cfg(unix)]
+fn connect(socket: &Path) -> std::io::Result<Stream> {
+    Stream::connect(socket)
+}
+
+#[cfg(not(unix))]
+fn connect(_socket: &Path) -> std::io::Result<Stream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "rune-agent needs Unix sockets",
+    ))
+}
+
+fn unexpected() -> Error {
+    Error::Agent("unexpected response from rune-agent".to_string())
+}
+
+// A connection to a running agent
+pub struct AgentClient {
+    stream: Stream,
+}
+
+impl AgentClient {
+    // Fails if no agent is listening on `paths`' socket
+    pub fn connect(paths: &Paths) -> crate::Result<Self> {
+        Self::connect_to(&paths.agent_socket())
+    }
+
+    fn connect_to(socket: &Path) -> crate::Result<Self> {
+        let stream = connect(socket).map_err(|e| {
+            Error::Agent(format!(
+                "can't reach rune-agent on {}: {}",
+                socket.display(),
+                e
+            ))
+        })?;
+        Ok(Self { stream })
+    }
+
+    pub fn request(&mut self, request: &Request) -> crate::Result<Response> {
+        write_frame(&mut self.stream, request)?;
+        match read_frame(&mut self.stream)? {
+            Some(Response::Error(e)) => Err(Error::Agent(e)),
+            Some(Response::BadPassphrase) => Err(Error::BadPassphrase),
+            Some(response) => Ok(response),
+            None => Err(Error::Agent("rune-agent closed the connection".to_string())),
+        }
+    }
+
+    // Have the agent decrypt `username`'s keyring and hold it for `timeout`,
+    // or its default; returns how long it'll be held
+    pub fn unlock(
+        &mut self,
+        username: &str,
+        passphrase: &str,
+        timeout: Option<Duration>,
+    ) -> crate::Result<Duration> {
+        match self.request(&Request::Unlock {
+            username: username.to_string(),
+            passphrase: passphrase.to_string(),
+            timeout: timeout.map(|timeout| timeout.as_secs()),
+        })? {
+            Response::Unlocked { expires_in } => Ok(Duration::from_secs(expires_in)),
+            _ => Err(unexpected()),
+        }
+    }
+
+    // Forget `username`'s keyring, or all of them; returns how many there were
+    pub fn lock(&mut self, username: Option<&str>) -> crate::Result<usize> {
+        match self.request(&Request::Lock {
+            username: username.map(str::to_string),
+        })? {
+            Response::Locked { count } => Ok(count),
+            _ => Err(unexpected()),
+        }
+    }
+
+    pub fn status(&mut self) -> crate::Result<Vec<Unlocked>> {
+        match self.request(&Request::Status)? {
+            Response::Status(unlocked) => Ok(unlocked),
+            _ => Err(unexpected()),
+        }
+    }
+
+    // `username`'s keyring as held by the agent, if it's unlocked there
+    pub fn keyring(&mut self, paths: &Paths, username: &str) -> crate::Result<Option<Keyring>> {
+        let public = match self.request(&Request::Public {
+            username: username.to_string(),
+        })? {
+            Response::Public(public) => public,
+            Response::NotUnlocked => return Ok(None),
+            _ => return Err(unexpected()),
+        };
+        let public = CompressedEdwardsY(public)
+            .decompress()
+            .ok_or_else(|| Error::Agent(format!("rune-agent has a bad key for {}", username)))?;
+        let key = AgentKey {
+            socket: paths.agent_socket(),
+            username: username.to_string(),
+        };
+        Ok(Some(Keyring::held_by_agent(public, key)))
+    }
+}
+
+// Where a keyring held by the agent is, used by `Keyring` for everything
+// that needs its secret key
+pub struct AgentKey {
+    socket: PathBuf,
+    username: String,
+}
+
+impl AgentKey {
+    fn request(&self, request: &Request) -> Result<Response, CryptoError> {
+        let response = AgentClient::connect_to(&self.socket)
+            .and_then(|mut agent| agent.request(request))
+            .map_err(|e| CryptoError::Agent(e.to_string()))?;
+        match response {
+            Response::NotUnlocked => Err(CryptoError::Agent(format!(
+                "{}'s keyring is no longer unlocked in rune-agent",
+                self.username
+            ))),
+            response => Ok(response),
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<[u8; SIGNATURE_LEN], CryptoError> {
+        match self.request(&Request::Sign {
+            username: self.username.clone(),
+            message: message.to_vec(),
+        })? {
+            Response::Signature(signature) => signature
+                .try_into()
+                .map_err(|_| CryptoError::Agent("bad signature from rune-agent".to_string())),
+            _ => Err(CryptoError::Agent(unexpected().to_string())),
+        }
+    }
+
+    pub fn diffie_hellman(
+        &self,
+        point: &MontgomeryPoint,
+    ) -> Result<Zeroizing<MontgomeryPoint>, CryptoError> {
+        match self.request(&Request::DiffieHellman {
+            username: self.username.clone(),
+            point: point.to_bytes(),
+        })? {
+            Response::SharedSecret(shared) => Ok(Zeroizing::new(MontgomeryPoint(shared))),
+            _ => Err(CryptoError::Agent(unexpected().to_string())),
+        }
+    }
+
+    pub fn storage_key(&self, purpose: &[u8]) -> Result<Zeroizing<[u8; 32]>, CryptoError> {
+        match self.request(&Request::StorageKey {
+            username: self.username.clone(),
+            purpose: purpose.to_vec(),
+        })? {
+            Response::StorageKey(key) => Ok(Zeroizing::new(key)),
+            _ => Err(CryptoError::Agent(unexpected().to_string())),
+        }
+    }
+}
+
+struct Held {
+    keyring: Keyring,
+    expires: Instant,
+}
+
+// The agent itself, see the rune-agent binary
+pub struct Agent {
+    paths: Paths,
+    timeout: Duration,
+    keyrings: Mutex<HashMap<String, Held>>,
+}
+
+impl Agent {
+    // An agent for the keyrings under `paths`, holding them for `timeout`
+    // unless an unlock asks for another
+    pub fn new(paths: Paths, timeout: Duration) -> Self {
+        Self {
+            paths,
+            timeout,
+            keyrings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Drop keyrings whose time is up, which wipes them
+    fn expire(&self) {
+        let now = Instant::now();
+        let mut keyrings = self.keyrings.lock().unwrap();
+        keyrings.retain(|username, held| {
+            let keep = held.expires > now;
+            if !keep {
+                log::info!("{}'s keyring timed out", username);
+            }
+            keep
+        });
+    }
+
+    fn with_keyring(
+        &self,
+        username: &str,
+        f: impl FnOnce(&Keyring) -> Result<Response, CryptoError>,
+    ) -> Response {
+        self.expire();
+        let keyrings = self.keyrings.lock().unwrap();
+        match keyrings.get(username) {
+            Some(held) => f(&held.keyring).unwrap_or_else(|e| Response::Error(e.to_string())),
+            None => Response::NotUnlocked,
+        }
+    }
+
+    pub fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Unlock {
+                username,
+                passphrase,
+                timeout,
+            } => {
+                let passphrase = Zeroizing::new(passphrase);
+                // worked out before taking the lock, so a bad timeout can't
+                // poison it
+                let timeout = timeout.map_or(self.timeout, Duration::from_secs);
+                let expires = match check_timeout(timeout).and_then(|timeout| {
+                    Instant::now()
+                        .checked_add(timeout)
+                        .ok_or_else(|| Error::Invalid("timeout too long".to_string()))
+                }) {
+                    Ok(expires) => expires,
+                    Err(e) => return Response::Error(e.to_string()),
+                };
+                let path = KeyringEncryptor::path(&self.paths, &username);
+                if !path.exists() {
+                    return Response::Error(Error::UnknownProfile(username).to_string());
+                }
+                match KeyringEncryptor::decrypt(&path, &passphrase) {
+                    Ok(keyring) => {
+                        log::info!("unlocked {}'s keyring for {}s", username, timeout.as_secs());
+                        self.keyrings
+                            .lock()
+                            .unwrap()
+                            .insert(username, Held { keyring, expires });
+                        Response::Unlocked {
+                            expires_in: timeout.as_secs(),
+                        }
+                    }
+                    Err(Error::BadPassphrase) => Response::BadPassphrase,
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            }
+            Request::Lock { username } => {
+                let mut keyrings = self.keyrings.lock().unwrap();
+                let count = match username {
+                    Some(username) => keyrings.remove(&username).map_or(0, |_| 1),
+                    None => keyrings.drain().count(),
+                };
+                log::info!("locked {} keyrings", count);
+                Response::Locked { count }
+            }
+            Request::Status => {
+                self.expire();
+                let now = Instant::now();
+                let mut unlocked: Vec<_> = self
+                    .keyrings
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(username, held)| Unlocked {
+                        username: username.clone(),
+                        expires_in: held.expires.saturating_duration_since(now).as_secs(),
+                    })
+                    .collect();
+                unlocked.sort_by(|a, b| a.username.cmp(&b.username));
+                Response::Status(unlocked)
+            }
+            Request::Public { username } => self.with_keyring(&username, |keyring| {
+                Ok(Response::Public(keyring.public.compress().to_bytes()))
+            }),
+            Request::Sign { username, message } => self.with_keyring(&username, |keyring| {
+                Ok(Response::Signature(keyring.sign(&message)?.to_vec()))
+            }),
+            Request::DiffieHellman { username, point } => self.with_keyring(&username, |keyring| {
+                let shared = keyring.diffie_hellman(&MontgomeryPoint(point))?;
+                Ok(Response::SharedSecret(shared.to_bytes()))
+            }),
+            Request::StorageKey { username, purpose } => self.with_keyring(&username, |keyring| {
+                Ok(Response::StorageKey(*keyring.storage_key(&purpose)?))
+            }),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Agent {
+    // Listen on `paths`' socket and serve connections, each on its own
+    // thread, until the process exits
+    pub fn run(self) -> crate::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::UnixListener;
+
+        let socket = self.paths.agent_socket();
+        if socket.exists() {
+            if Stream::connect(&socket).is_ok() {
+                return Err(Error::Agent(format!(
+                    "rune-agent is already running on {}",
+                    socket.display()
+                )));
+            }
+            // left behind by an agent that didn't shut down cleanly
+            std::fs::remove_file(&socket)?;
+        }
+        std::fs::create_dir_all(self.paths.data_dir())?;
+        // the socket is created 0600, so nobody else can connect before
+        // its permissions are set
+        // SAFETY: umask only swaps the process's file mode mask
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&socket);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))?;
+        log::info!("rune-agent listening on {}", socket.display());
+
+        let agent = Arc::new(self);
+        // wipe keyrings as they time out, not just when they're next asked for
+        {
+            let agent = Arc::clone(&agent);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(Duration::from_secs(1));
+                agent.expire();
+            });
+        }
+
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let agent = Arc::clone(&agent);
+            std::thread::spawn(move || loop {
+                match read_frame(&mut stream) {
+                    Ok(Some(request)) => {
+                        if let Err(e) = write_frame(&mut stream, &agent.handle(request)) {
+                            log::debug!("failed to answer: {}", e);
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::debug!("bad request: {}", e);
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+impl Agent {
+    pub fn run(self) -> crate::Result<()> {
+        Err(Error::Agent("rune-agent needs Unix sockets".to_string()))
+    }
+}
@@ -0,0 +1,258 @@
+// ASCII armor for moving keys around as text
+//
+// An armored block looks like
+//
+//   -----BEGIN RUNE PUBLIC KEY-----
+//   Username: alice
+//
+//   <base64 of the data, wrapped at 64 columns>
+//   =<base64 of the checksum>
+//   -----END RUNE PUBLIC KEY-----
+//
+// The label is "RUNE PUBLIC KEY" or "RUNE SECRET KEY". Header lines are `Key: value`
+// pairs followed by a blank line, and are optional. The checksum is the first
+// 4 bytes of SHA-256 over the decoded data. It only catches copy and paste
+// mistakes; whether a key really belongs to someone is up to fingerprints.
+//
+// Text before the BEGIN line and after the END line is ignored, so a block
+// can be pasted from an email or chat message as is.
+//
+// A public key block holds the 32 byte compressed identity key. A secret key
+// block holds a keyring file (see `crypto::keyring`), encrypted under the
+// passphrase chosen when it was exported.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+const LINE_WIDTH: usize = 64;
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArmorKind {
+    PublicKey,
+    SecretKey,
+}
+
+impl ArmorKind {
+    fn label(self) -> &'static str {
+        match self {
+            ArmorKind::PublicKey => "RUNE PUBLIC KEY",
+            ArmorKind::SecretKey => "RUNE SECRET KEY",
+        }
+    }
+
+    fn from_label(label: &str) -> Option<Self> {
+        [ArmorKind::PublicKey, ArmorKind::SecretKey]
+            .into_iter()
+            .find(|kind| kind.label() == label)
+    }
+}
+
+#[derive(Debug)]
+pub enum ArmorError {
+    MissingBegin,
+    MissingEnd,
+    UnknownKind(String),
+    BadHeader(String),
+    BadBase64,
+    MissingChecksum,
+    BadChecksum,
+}
+
+impl std::fmt::Display for ArmorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArmorError::MissingBegin => write!(f, "no armored block found"),
+            ArmorError::MissingEnd => write!(f, "armored block is missing its END line"),
+            ArmorError::UnknownKind(label) => write!(f, "unknown armored block {:?}", label),
+            ArmorError::BadHeader(line) => write!(f, "bad armor header {:?}", line),
+            ArmorError::BadBase64 => write!(f, "armored block contains invalid base64"),
+            ArmorError::MissingChecksum => write!(f, "armored block is missing its checksum"),
+            ArmorError::BadChecksum => {
+                write!(
+                    f,
+                    "armored block checksum doesn't match, was it copied fully?"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArmorError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Armored {
+    pub kind: ArmorKind,
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(data);
+    digest[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+impl Armored {
+    pub fn new(kind: ArmorKind, data: Vec<u8>) -> Self {
+        Self {
+            kind,
+            headers: Vec::new(),
+            data,
+        }
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn encode(&self) -> String {
+        let label = self.kind.label();
+        let mut out = format!("-----BEGIN {}-----\n", label);
+        for (key, value) in &self.headers {
+            out.push_str(&format!("{}: {}\n", key, value));
+        }
+        if !self.headers.is_empty() {
+            out.push('\n');
+        }
+
+        let body = STANDARD.encode(&self.data);
+        for line in body.as_bytes().chunks(LINE_WIDTH) {
+            out.push_str(std::str::from_utf8(line).unwrap());
+            out.push('\n');
+        }
+        out.push_str(&format!("={}\n", STANDARD.encode(checksum(&self.data))));
+        out.push_str(&format!("-----END {}-----\n", label));
+        out
+    }
+
+    // Decode the first armored block in `text`
+    pub fn decode(text: &str) -> Result<Self, ArmorError> {
+        let mut lines = text.lines().map(str::trim);
+
+        let label = lines
+            .by_ref()
+            .find_map(|line| {
+                line.strip_prefix("-----BEGIN ")
+                    .and_then(|rest| rest.strip_suffix("-----"))
+            })
+            .ok_or(ArmorError::MissingBegin)?;
+        let kind = ArmorKind::from_label(label)
+            .ok_or_else(|| ArmorError::UnknownKind(label.to_string()))?;
+        let end = format!("-----END {}-----", label);
+
+        let mut block = Vec::new();
+        loop {
+            match lines.next() {
+                Some(line) if line == end => break,
+                Some(line) => block.push(line),
+                None => return Err(ArmorError::MissingEnd),
+            }
+        }
+
+        // headers run up to the first blank line, if there are any
+        let mut headers = Vec::new();
+        let mut body = block.as_slice();
+        if let Some(blank) = block.iter().position(|line| line.is_empty()) {
+            for line in &block[..blank] {
+                let (key, value) = line
+                    .split_once(':')
+                    .ok_or_else(|| ArmorError::BadHeader(line.to_string()))?;
+                headers.push((key.trim().to_string(), value.trim().to_string()));
+            }
+            body = &block[blank + 1..];
+        }
+
+        let (sum, body) = match body.split_last() {
+            Some((last, body)) if last.starts_with('=') && last.len() > 1 => (&last[1..], body),
+            _ => return Err(ArmorError::MissingChecksum),
+        };
+        let data = STANDARD
+            .decode(body.concat())
+            .map_err(|_| ArmorError::BadBase64)?;
+        let sum = STANDARD.decode(sum).map_err(|_| ArmorError::BadBase64)?;
+        if sum != checksum(&data) {
+            return Err(ArmorError::BadChecksum);
+        }
+
+        Ok(Self {
+            kind,
+            headers,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> Armored {
+        Armored::new(ArmorKind::SecretKey, (0..=255).collect()).with_header("Username", "alice")
+    }
+
+    #[test]
+    fn round_trip_within_surrounding_text() {
+        let armored = block();
+        let text = armored.encode();
+        assert!(text.lines().all(|line| line.len() <= LINE_WIDTH));
+
+        let pasted = format!("here's my key:\n\n{}\nthanks\n", text.replace('\n', "\r\n"));
+        let decoded = Armored::decode(&pasted).unwrap();
+        assert_eq!(decoded, armored);
+        assert_eq!(decoded.header("username"), Some("alice"));
+
+        let bare = Armored::new(ArmorKind::PublicKey, vec![7; 32]);
+        assert_eq!(Armored::decode(&bare.encode()).unwrap(), bare);
+    }
+
+    #[test]
+    fn changed_data_fails_the_checksum() {
+        let text = block().encode();
+        // the first data line follows the header and the blank line
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let line = &mut lines[3];
+        let flipped = if line.starts_with('A') { "B" } else { "A" };
+        line.replace_range(..1, flipped);
+        assert!(matches!(
+            Armored::decode(&lines.join("\n")),
+            Err(ArmorError::BadChecksum)
+        ));
+
+        let truncated: Vec<_> = text
+            .lines()
+            .filter(|line| line.len() != LINE_WIDTH)
+            .collect();
+        assert!(Armored::decode(&truncated.join("\n")).is_err());
+    }
+
+    #[test]
+    fn malformed_blocks_are_rejected() {
+        let text = block().encode();
+        assert!(matches!(
+            Armored::decode("no key here"),
+            Err(ArmorError::MissingBegin)
+        ));
+        assert!(matches!(
+            Armored::decode(text.trim_end().rsplit_once('\n').unwrap().0),
+            Err(ArmorError::MissingEnd)
+        ));
+        assert!(matches!(
+            Armored::decode(&text.replace("SECRET KEY", "PRIVATE KEY")),
+            Err(ArmorError::UnknownKind(_))
+        ));
+        let unsummed: Vec<_> = text.lines().filter(|line| !line.starts_with('=')).collect();
+        assert!(matches!(
+            Armored::decode(&unsummed.join("\n")),
+            Err(ArmorError::MissingChecksum)
+        ));
+    }
+}
@@ -0,0 +1,409 @@
+// Files sent with `rune send --file`
+//
+// A file is encrypted with STREAM (see `crypto::stream`) under a key made for
+// it alone, and the ciphertext is cut into parts of about a megabyte, each
+// delivered as an envelope of its own with the `FileChunk` suite. Once every
+// part is on its way, an `Attachment` holding the file's name, size, SHA-256
+// hash and key goes to the recipient as an ordinary session message, which
+// wraps the key for them and is signed like any other; its payload has the
+// `Attachment` content type. Parts aren't signed,
+// they're only any use to someone with the key.
+//
+// Parts are left out of the inbox. Reading the attachment message gives the
+// recipient its key, and `save` decrypts the parts from the mailbox into a
+// file, checks its size and hash, and removes them. Neither side ever holds
+// more than a part in memory, however large the file.
+
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::config::Paths;
+use crate::crypto::stream::{self, SEALED_CHUNK_LEN};
+use crate::envelope::{CipherSuite, ContentType, Envelope, Payload};
+use crate::error::Error;
+use crate::mailbox::{Mailbox, MailboxEntry};
+use crate::transport::Transport;
+
+// Ciphertext per part, a whole number of STREAM chunks
+pub const PART_LEN: usize = 16 * SEALED_CHUNK_LEN;
+// Enough of a part envelope to read its header with `Envelope::peek`
+pub const PEEK_LEN: usize = 128;
+
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct Attachment {
+    pub id: [u8; 16],
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+    pub parts: u32,
+    key: [u8; 32],
+}
+
+// The header of a part envelope
+#[derive(Serialize, Deserialize)]
+struct PartHeader {
+    file: [u8; 16],
+    index: u32,
+}
+
+// Passes data through while counting and hashing it
+struct Hashing<T> {
+    inner: T,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T: Read> Read for Hashing<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Hashing<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Delivers the ciphertext written to it a part at a time
+struct PartWriter<'a> {
+    transport: &'a mut dyn Transport,
+    recipient: &'a str,
+    file: [u8; 16],
+    part: Vec<u8>,
+    parts: u32,
+}
+
+impl PartWriter<'_> {
+    fn deliver(&mut self) -> std::io::Result<()> {
+        let header = bincode::serialize(&PartHeader {
+            file: self.file,
+            index: self.parts,
+        })
+        .expect("part header serialization is infallible");
+        let envelope = Envelope::file_part(header, std::mem::take(&mut self.part));
+        self.transport
+            .deliver(self.recipient, &envelope.to_bytes())?;
+        self.parts += 1;
+        Ok(())
+    }
+}
+
+impl Write for PartWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(PART_LEN - self.part.len());
+        self.part.extend_from_slice(&buf[..n]);
+        if self.part.len() == PART_LEN {
+            self.deliver()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Safe to create in a directory of the recipient's choosing: a plain file
+// name, no directories
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+        && Path::new(name).file_name() == Some(name.as_ref())
+}
+
+impl Attachment {
+    // Encrypt `input` and deliver its parts to `recipient`, returning the
+    // attachment to send them once they're all out
+    pub fn upload(
+        name: &str,
+        input: &mut dyn Read,
+        recipient: &str,
+        transport: &mut dyn Transport,
+    ) -> crate::Result<Self> {
+        if !valid_name(name) {
+            return Err(Error::Invalid(format!(
+                "can't send a file named {:?}",
+                name
+            )));
+        }
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut *key);
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+
+        let mut input = Hashing::new(input);
+        let mut parts = PartWriter {
+            transport,
+            recipient,
+            file: id,
+            part: Vec::with_capacity(PART_LEN),
+            parts: 0,
+        };
+        stream::encrypt(&key, &mut input, &mut parts)?;
+        // the stream always ends with a tag, so there's at least one part
+        if !parts.part.is_empty() {
+            parts.deliver()?;
+        }
+        log::debug!("delivered {} in {} parts", name, parts.parts);
+
+        Ok(Self {
+            id,
+            name: name.to_string(),
+            size: input.len,
+            sha256: input.hasher.finalize().into(),
+            parts: parts.parts,
+            key: *key,
+        })
+    }
+
+    // The message to send once the parts have been delivered
+    pub fn to_payload(&self) -> Zeroizing<Vec<u8>> {
+        let body = Zeroizing::new(
+            bincode::serialize(self).expect("attachment serialization is infallible"),
+        );
+        Payload::new(ContentType::Attachment, &body).to_bytes()
+    }
+
+    // The attachment in the body of an `Attachment` payload
+    pub fn from_body(body: &[u8]) -> crate::Result<Self> {
+        let attachment: Self = bincode::deserialize(body)?;
+        if !valid_name(&attachment.name) {
+            return Err(Error::Malformed(format!(
+                "attachment has an unsafe file name {:?}",
+                attachment.name
+            )));
+        }
+        // the part count is the sender's to choose, so only the one its size
+        // calls for is taken
+        let expected = stream::sealed_len(attachment.size).map(|len| len.div_ceil(PART_LEN as u64));
+        if expected != Some(attachment.parts as u64) {
+            return Err(Error::Malformed(format!(
+                "{} bytes don't make {} parts",
+                attachment.size, attachment.parts
+            )));
+        }
+        Ok(attachment)
+    }
+
+    // A line for listings, e.g. "report.pdf (52318 bytes)"
+    pub fn describe(&self) -> String {
+        format!("{} ({} bytes)", self.name, self.size)
+    }
+
+    // The parts of this attachment in `mailbox`, in order
+    fn parts(&self, mailbox: &Mailbox) -> crate::Result<Vec<MailboxEntry>> {
+        let mut parts = BTreeMap::new();
+        for entry in mailbox.list()? {
+            let Ok((CipherSuite::FileChunk, header)) =
+                Envelope::peek(&mailbox.read_prefix(&entry, PEEK_LEN)?)
+            else {
+                continue;
+            };
+            let Ok(header) = bincode::deserialize::<PartHeader>(&header) else {
+                continue;
+            };
+            if header.file == self.id && header.index < self.parts {
+                parts.entry(header.index).or_insert(entry);
+            }
+        }
+
+        if parts.len() < self.parts as usize {
+            return Err(Error::Malformed(format!(
+                "only {} of {} parts of {} have arrived",
+                parts.len(),
+                self.parts,
+                self.name
+            )));
+        }
+        Ok(parts.into_values().collect())
+    }
+
+    // Decrypt this attachment from `owner`'s mailbox into a file of the same
+    // name in `dir`, returning its path. The file only appears once its size
+    // and hash check out, and then the parts are removed.
+    pub fn save(&self, paths: &Paths, owner: &str, dir: &Path) -> crate::Result<PathBuf> {
+        let path = dir.join(&self.name);
+        if path.exists() {
+            return Err(Error::Invalid(format!("{} already exists", path.display())));
+        }
+        let mailbox = Mailbox::open(paths, owner)?;
+        let parts = self.parts(&mailbox)?;
+
+        let partial = dir.join(format!(".{}.rune-partial", hex::encode(self.id)));
+        let result = (|| {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&partial)?;
+            let mut output = Hashing::new(BufWriter::new(file));
+            let mut input = PartReader {
+                mailbox: &mailbox,
+                file: self.id,
+                parts: parts.iter(),
+                index: 0,
+                part: Vec::new(),
+                pos: 0,
+            };
+            stream::decrypt(&self.key, &mut input, &mut output)?;
+            output.flush()?;
+
+            if output.len != self.size {
+                return Err(Error::Malformed(format!(
+                    "{} is {} bytes, expected {}",
+                    self.name, output.len, self.size
+                )));
+            }
+            if <[u8; 32]>::from(output.hasher.finalize()) != self.sha256 {
+                return Err(Error::Malformed(format!(
+                    "{} doesn't match its SHA-256 hash",
+                    self.name
+                )));
+            }
+            output
+                .inner
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?;
+            // unlike a rename, a link never replaces a file created meanwhile
+            std::fs::hard_link(&partial, &path).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::AlreadyExists {
+                    Error::Invalid(format!("{} already exists", path.display()))
+                } else {
+                    e.into()
+                }
+            })
+        })();
+        let _ = std::fs::remove_file(&partial);
+        result?;
+
+        for part in &parts {
+            mailbox.remove(part)?;
+        }
+        log::debug!("saved {} to {}", self.name, path.display());
+        Ok(path)
+    }
+
+    pub fn sha256_hex(&self) -> String {
+        hex::encode(self.sha256)
+    }
+}
+
+// Reads the ciphertext back out of the parts, one at a time
+struct PartReader<'a> {
+    mailbox: &'a Mailbox,
+    file: [u8; 16],
+    parts: std::slice::Iter<'a, MailboxEntry>,
+    index: u32,
+    part: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for PartReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let malformed = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        while self.pos == self.part.len() {
+            let Some(entry) = self.parts.next() else {
+                return Ok(0);
+            };
+            let envelope = Envelope::from_bytes(&self.mailbox.read(entry)?)
+                .map_err(|e| malformed(e.to_string()))?;
+            let header: PartHeader =
+                bincode::deserialize(&envelope.header).map_err(|e| malformed(e.to_string()))?;
+            if envelope.suite != CipherSuite::FileChunk
+                || header.file != self.file
+                || header.index != self.index
+            {
+                return Err(malformed(format!(
+                    "message {} isn't the expected part",
+                    entry.id
+                )));
+            }
+            self.index += 1;
+            self.part = envelope.ciphertext;
+            self.pos = 0;
+        }
+
+        let n = buf.len().min(self.part.len() - self.pos);
+        buf[..n].copy_from_slice(&self.part[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::stream::CHUNK_LEN;
+    use crate::transport::MemoryTransport;
+
+    fn upload(len: usize) -> Attachment {
+        let data = vec![7u8; len];
+        let mut transport = MemoryTransport::default();
+        Attachment::upload("data.bin", &mut data.as_slice(), "bob", &mut transport).unwrap()
+    }
+
+    #[test]
+    fn only_the_part_count_the_size_calls_for_is_taken() {
+        for len in [
+            0,
+            1,
+            CHUNK_LEN,
+            PART_LEN - 16,
+            PART_LEN,
+            PART_LEN * 2 + 12345,
+        ] {
+            let attachment = upload(len);
+            let body = bincode::serialize(&attachment).unwrap();
+            assert_eq!(
+                Attachment::from_body(&body).unwrap().parts,
+                attachment.parts
+            );
+
+            for parts in [attachment.parts + 1, u32::MAX] {
+                let mut forged: Attachment = bincode::deserialize(&body).unwrap();
+                forged.parts = parts;
+                assert!(matches!(
+                    Attachment::from_body(&bincode::serialize(&forged).unwrap()),
+                    Err(Error::Malformed(_))
+                ));
+            }
+        }
+
+        // nor a size too large to have been sent at all
+        let mut forged = upload(1);
+        forged.size = u64::MAX;
+        assert!(matches!(
+            Attachment::from_body(&bincode::serialize(&forged).unwrap()),
+            Err(Error::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,35 @@
+use clap::{App, Arg};
+
+use rune_core::agent::{check_timeout, Agent};
+use rune_core::config::Config;
+
+fn main() {
+    pretty_env_logger::try_init().ok();
+
+    let matches = App::new("rune-agent")
+        .about("holds unlocked rune keyrings so passphrases aren't asked for every time")
+        .version("0.1.0")
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("DURATION")
+                .help("How long to hold keyrings, e.g. 30m or 2h [default: agent.timeout in rune.toml, or 10m]"),
+        )
+        .get_matches();
+
+    let run = || -> rune_core::Result<()> {
+        let config = Config::from_env()?;
+        let timeout = match matches.value_of("timeout") {
+            Some(timeout) => humantime::parse_duration(timeout)
+                .map_err(|e| rune_core::Error::Invalid(format!("bad timeout: {}", e)))
+                .and_then(check_timeout)?,
+            None => config.agent_timeout,
+        };
+        Agent::new(config.paths, timeout).run()
+    };
+    if let Err(e) = run() {
+        eprintln!("rune-agent: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,536 @@
+// Everything a rune frontend does, behind one type
+//
+// `RuneClient` ties together a `Config`, the transport messages go through
+// and the files under `Paths`. It never prompts or prints: frontends ask for
+// usernames and passphrases however suits them and pass them in. The CLI in
+// main.rs and the ratatui client are both built on it.
+//
+//   let mut client = RuneClient::from_env(None)?;
+//   let alice = client.unlock(&client.whoami(None)?, &passphrase)?;
+//   client.send(&alice, "bob", "hello", false)?;
+
+use std::path::{Path, PathBuf};
+
+use curve25519_dalek::edwards::EdwardsPoint;
+use rand::{rngs::OsRng, RngCore};
+
+use crate::agent::AgentClient;
+use crate::armor::Armored;
+use crate::attachment::Attachment;
+use crate::cmd::inbox::{AfterRead, InboxItem, Opened};
+use crate::config::{Config, Paths};
+use crate::contacts::ContactBook;
+use crate::crypto::keyring::{Keyring, KeyringEncryptor};
+use crate::crypto::prekey::{PrekeyBundle, PrekeyStore, ONE_TIME_PREKEYS};
+use crate::error::Error;
+use crate::group::{Group, GroupInfo};
+use crate::mailbox::MessageId;
+use crate::relay::RelayClient;
+use crate::transport::{Transport, TransportSpec};
+use crate::{cmd, profile};
+
+pub struct RuneClient {
+    config: Config,
+    spec: TransportSpec,
+    // opened on first use, so commands that stay local work offline
+    transport: Option<Box<dyn Transport>>,
+}
+
+// A profile whose keyring has been decrypted
+pub struct Profile {
+    username: String,
+    keyring: Keyring,
+}
+
+impl Profile {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+}
+
+impl RuneClient {
+    pub fn new(config: Config, spec: TransportSpec) -> Self {
+        Self {
+            config,
+            spec,
+            transport: None,
+        }
+    }
+
+    // Configuration from the environment, with `transport` (as given to
+    // --transport) taking precedence over $RUNE_TRANSPORT and rune.toml
+    pub fn from_env(transport: Option<&str>) -> crate::Result<Self> {
+        let config = Config::from_env()?;
+        let spec = TransportSpec::resolve(transport, &config)?;
+        Ok(Self::new(config, spec))
+    }
+
+    // Deliver and fetch through `transport` rather than one opened from the
+    // spec, which still decides where usernames are registered and looked up
+    pub fn with_transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn paths(&self) -> &Paths {
+        &self.config.paths
+    }
+
+    pub fn transport_spec(&self) -> &TransportSpec {
+        &self.spec
+    }
+
+    fn transport(&mut self) -> crate::Result<&mut dyn Transport> {
+        let transport = match self.transport.take() {
+            Some(transport) => transport,
+            None => self.spec.open(&self.config.paths)?,
+        };
+        Ok(self.transport.insert(transport).as_mut())
+    }
+
+    // `username` if given, otherwise the active profile
+    pub fn whoami(&self, username: Option<&str>) -> crate::Result<String> {
+        profile::resolve(self.paths(), username)
+    }
+
+    pub fn profiles(&self) -> crate::Result<Vec<String>> {
+        profile::list(self.paths())
+    }
+
+    pub fn use_profile(&self, username: &str) -> crate::Result<()> {
+        profile::set_active(self.paths(), username)
+    }
+
+    pub fn remove_profile(&self, username: &str) -> crate::Result<()> {
+        profile::remove(self.paths(), username)
+    }
+
+    // Create a keyring for `username` protected by `passphrase`, claim the
+    // username with the directory if there is one, publish prekeys and make
+    // it the active profile
+    pub fn register(&self, username: &str, passphrase: &str) -> crate::Result<Profile> {
+        if !crate::relay::valid_username(username) {
+            return Err(Error::InvalidUsername(username.to_string()));
+        }
+        let paths = self.paths();
+        if paths.user(username).exists() {
+            return Err(Error::UsernameTaken(username.to_string()));
+        }
+
+        // the home is built out of sight and only moved into place once the
+        // directory has given us the username, so a failure on either side
+        // leaves neither a half-built home nor a claim for a lost key
+        let staging = Paths::at(paths.data_dir().join(format!(
+            ".{}.{:016x}.registering",
+            username,
+            OsRng.next_u64()
+        )));
+        let result = (|| -> crate::Result<KeyringEncryptor> {
+            let encryptor = self.create_home(&staging, username, passphrase)?;
+            if let Some(server) = self.spec.directory() {
+                let mut relay = RelayClient::connect(server)?;
+                relay.register(username, encryptor.keyring())?;
+                log::debug!("registered {} with the directory on {}", username, server);
+                if let Some(bundle) = PrekeyBundle::load(&staging, username)? {
+                    relay.authenticate(username, encryptor.keyring())?;
+                    relay.publish_prekeys(username, &bundle)?;
+                }
+            }
+            std::fs::rename(staging.user(username), paths.user(username))?;
+            Ok(encryptor)
+        })();
+        let _ = std::fs::remove_dir_all(staging.data_dir());
+        let encryptor = result?;
+        log::debug!("created {}", paths.user(username).display());
+        profile::set_active(paths, username)?;
+
+        Ok(Profile {
+            username: username.to_string(),
+            keyring: encryptor.into_keyring(),
+        })
+    }
+
+    // Write a new keyring for `username` and its prekeys under `paths`
+    fn create_home(
+        &self,
+        paths: &Paths,
+        username: &str,
+        passphrase: &str,
+    ) -> crate::Result<KeyringEncryptor> {
+        cmd::register::register_create_home(paths, username)?;
+        let keyring = Keyring::generate();
+        keyring.save_public_key(paths, username)?;
+        // publish prekeys so others can start sessions while we're offline
+        let (prekeys, bundle) = PrekeyStore::generate(&keyring, ONE_TIME_PREKEYS)?;
+        prekeys.save(paths, username, &keyring)?;
+        bundle.save(paths, username)?;
+
+        let encryptor = KeyringEncryptor::from(keyring);
+        let path = KeyringEncryptor::path(paths, username);
+        encryptor.encrypt_with(&path, passphrase, self.config.kdf)?;
+        Ok(encryptor)
+    }
+
+    // Decrypt `username`'s keyring
+    pub fn unlock(&self, username: &str, passphrase: &str) -> crate::Result<Profile> {
+        let path = KeyringEncryptor::path(self.paths(), username);
+        if !path.exists() {
+            return Err(Error::UnknownProfile(username.to_string()));
+        }
+        Ok(Profile {
+            username: username.to_string(),
+            keyring: KeyringEncryptor::decrypt(&path, passphrase)?,
+        })
+    }
+
+    // `username`'s keyring from rune-agent, if it's running and holds it
+    pub fn unlock_from_agent(&self, username: &str) -> Option<Profile> {
+        let keyring = AgentClient::connect(self.paths())
+            .and_then(|mut agent| agent.keyring(self.paths(), username));
+        match keyring {
+            Ok(keyring) => keyring.map(|keyring| Profile {
+                username: username.to_string(),
+                keyring,
+            }),
+            Err(e) => {
+                log::debug!("not using rune-agent: {}", e);
+                None
+            }
+        }
+    }
+
+    // A connection to rune-agent, to unlock and lock keyrings in it
+    pub fn agent(&self) -> crate::Result<AgentClient> {
+        AgentClient::connect(self.paths())
+    }
+
+    // Whether `username`'s keyring is in the old format, which
+    // `change_passphrase` with the same passphrase upgrades
+    pub fn is_legacy(&self, username: &str) -> crate::Result<bool> {
+        Ok(KeyringEncryptor::is_legacy(KeyringEncryptor::path(
+            self.paths(),
+            username,
+        ))?)
+    }
+
+    // Re-encrypt `username`'s keyring under `new`, with the configured KDF
+    pub fn change_passphrase(&self, username: &str, old: &str, new: &str) -> crate::Result<()> {
+        let path = KeyringEncryptor::path(self.paths(), username);
+        KeyringEncryptor::rekey(&path, old, new, self.config.kdf)
+    }
+
+    pub fn send(
+        &mut self,
+        from: &Profile,
+        recipient: &str,
+        message: &str,
+        header_encryption: bool,
+    ) -> crate::Result<()> {
+        let directory = self.spec.directory().map(str::to_string);
+        let paths = self.config.paths.clone();
+        cmd::send::send_message(
+            &paths,
+            &from.username,
+            &from.keyring,
+            recipient,
+            message,
+            header_encryption,
+            self.transport()?,
+            directory.as_deref(),
+        )
+    }
+
+    // One message for several people, in a single envelope
+    pub fn send_to_many(
+        &mut self,
+        from: &Profile,
+        recipients: &[&str],
+        message: &str,
+        hide_recipients: bool,
+    ) -> crate::Result<()> {
+        let directory = self.spec.directory().map(str::to_string);
+        let paths = self.config.paths.clone();
+        cmd::send::send_to_many(
+            &paths,
+            &from.username,
+            &from.keyring,
+            recipients,
+            message,
+            hide_recipients,
+            self.transport()?,
+            directory.as_deref(),
+        )
+    }
+
+    // Send the file at `path`, encrypted a chunk at a time so it never has to
+    // fit in memory
+    pub fn send_file(
+        &mut self,
+        from: &Profile,
+        recipient: &str,
+        path: &Path,
+        header_encryption: bool,
+    ) -> crate::Result<Attachment> {
+        let directory = self.spec.directory().map(str::to_string);
+        let paths = self.config.paths.clone();
+        cmd::send::send_file(
+            &paths,
+            &from.username,
+            &from.keyring,
+            recipient,
+            path,
+            header_encryption,
+            self.transport()?,
+            directory.as_deref(),
+        )
+    }
+
+    // Decrypt an attachment `read` returned into `dir`, under the name it was
+    // sent with, once its size and hash check out
+    pub fn save_attachment(
+        &self,
+        owner: &str,
+        attachment: &Attachment,
+        dir: &Path,
+    ) -> crate::Result<PathBuf> {
+        attachment.save(self.paths(), owner, dir)
+    }
+
+    // Move messages waiting on the transport into `owner`'s mailbox,
+    // returning how many there were. A relay only hands them over once
+    // `owner` has proved who they are with their keyring.
+    pub fn fetch(&mut self, owner: &Profile) -> crate::Result<usize> {
+        let paths = self.config.paths.clone();
+        Ok(crate::transport::fetch_into_mailbox(
+            self.transport()?,
+            &paths,
+            &owner.username,
+            &owner.keyring,
+        )?)
+    }
+
+    // Messages in `username`'s mailbox, with `include_read` those already read
+    pub fn inbox(&self, username: &str, include_read: bool) -> crate::Result<Vec<InboxItem>> {
+        cmd::inbox::list_inbox(self.paths(), username, include_read, self.spec.directory())
+    }
+
+    pub fn find_messages(
+        &self,
+        username: &str,
+        ids: &[MessageId],
+    ) -> crate::Result<Vec<InboxItem>> {
+        cmd::inbox::find_messages(self.paths(), username, ids, self.spec.directory())
+    }
+
+    // Unread messages from `sender`
+    pub fn unread_from(&self, username: &str, sender: &str) -> crate::Result<Vec<InboxItem>> {
+        cmd::send::unread_from(self.paths(), username, sender, self.spec.directory())
+    }
+
+    // Decrypt `items` from `to`'s mailbox
+    pub fn read(
+        &self,
+        to: &Profile,
+        items: Vec<InboxItem>,
+        after: AfterRead,
+    ) -> crate::Result<Opened> {
+        cmd::inbox::open_messages(self.paths(), &to.username, &to.keyring, items, after)
+    }
+
+    // The groups `owner` is or was in, by name
+    pub fn groups(&self, owner: &Profile) -> crate::Result<Vec<Group>> {
+        Group::list(self.paths(), &owner.username, &owner.keyring)
+    }
+
+    // A new group of `members` and `owner`, who becomes its admin
+    pub fn create_group(
+        &mut self,
+        owner: &Profile,
+        name: &str,
+        members: &[&str],
+    ) -> crate::Result<GroupInfo> {
+        let directory = self.spec.directory().map(str::to_string);
+        let paths = self.config.paths.clone();
+        cmd::group::create_group(
+            &paths,
+            &owner.username,
+            &owner.keyring,
+            name,
+            members,
+            self.transport()?,
+            directory.as_deref(),
+        )
+    }
+
+    // Add `members` to `group`, as admins too with `as_admins`
+    pub fn add_to_group(
+        &mut self,
+        admin: &Profile,
+        group: &str,
+        members: &[&str],
+        as_admins: bool,
+    ) -> crate::Result<GroupInfo> {
+        let directory = self.spec.directory().map(str::to_string);
+        let paths = self.config.paths.clone();
+        cmd::group::add_members(
+            &paths,
+            &admin.username,
+            &admin.keyring,
+            group,
+            members,
+            as_admins,
+            self.transport()?,
+            directory.as_deref(),
+        )
+    }
+
+    // Remove `members` from `group`, rotating its keys
+    pub fn remove_from_group(
+        &mut self,
+        admin: &Profile,
+        group: &str,
+        members: &[&str],
+    ) -> crate::Result<GroupInfo> {
+        let directory = self.spec.directory().map(str::to_string);
+        let paths = self.config.paths.clone();
+        cmd::group::remove_members(
+            &paths,
+            &admin.username,
+            &admin.keyring,
+            group,
+            members,
+            self.transport()?,
+            directory.as_deref(),
+        )
+    }
+
+    pub fn send_to_group(
+        &mut self,
+        from: &Profile,
+        group: &str,
+        message: &str,
+    ) -> crate::Result<()> {
+        let directory = self.spec.directory().map(str::to_string);
+        let paths = self.config.paths.clone();
+        cmd::group::send_to_group(
+            &paths,
+            &from.username,
+            &from.keyring,
+            group,
+            message,
+            self.transport()?,
+            directory.as_deref(),
+        )
+    }
+
+    pub fn contacts(&self, owner: &str) -> crate::Result<ContactBook> {
+        ContactBook::load(self.paths(), owner)
+    }
+
+    // Pin `identity` for `username`, or the key the directory (or this
+    // machine) has for them if not given
+    pub fn add_contact(
+        &self,
+        owner: &str,
+        username: &str,
+        display_name: Option<&str>,
+        identity: Option<&EdwardsPoint>,
+        force: bool,
+    ) -> crate::Result<()> {
+        let identity = match identity {
+            Some(identity) => *identity,
+            None => {
+                crate::directory::resolve_identity(self.paths(), username, self.spec.directory())?
+            }
+        };
+        cmd::contact::add_contact(
+            self.paths(),
+            owner,
+            username,
+            display_name,
+            &identity,
+            force,
+        )
+    }
+
+    pub fn remove_contact(&self, owner: &str, username: &str) -> crate::Result<()> {
+        cmd::contact::remove_contact(self.paths(), owner, username)
+    }
+
+    pub fn verify_contact(&self, owner: &str, username: &str) -> crate::Result<()> {
+        cmd::contact::verify_contact(self.paths(), owner, username)
+    }
+
+    // The safety number `owner` shares with the contact `username`
+    pub fn safety_number(&self, owner: &str, username: &str) -> crate::Result<String> {
+        cmd::contact::safety_number_report(self.paths(), owner, username)
+    }
+
+    // `username`'s fingerprint, and with `contact` theirs and the safety
+    // number to compare with them
+    pub fn fingerprint(&self, username: &str, contact: Option<&str>) -> crate::Result<String> {
+        cmd::key::fingerprint_report(self.paths(), username, contact, self.spec.directory())
+    }
+
+    pub fn export_public(&self, username: &str) -> crate::Result<String> {
+        cmd::key::export_public(self.paths(), username)
+    }
+
+    // `username`'s keyring, re-encrypted under `export_passphrase`
+    pub fn export_secret(
+        &self,
+        username: &str,
+        passphrase: &str,
+        export_passphrase: &str,
+    ) -> crate::Result<String> {
+        cmd::key::export_secret(&self.config, username, passphrase, export_passphrase)
+    }
+
+    // Store an armored public key, for `username` or the one in its header,
+    // returning who it was stored for
+    pub fn import_public(
+        &self,
+        armored: &Armored,
+        username: Option<&str>,
+        force: bool,
+    ) -> crate::Result<String> {
+        let username = cmd::key::import_username(armored, username)?;
+        cmd::key::import_public(self.paths(), armored, &username, force)?;
+        Ok(username)
+    }
+
+    // Set up a profile from an armored keyring, publishing its new prekeys to
+    // the directory if there is one, and make it the active one if there
+    // isn't one yet
+    pub fn import_secret(
+        &self,
+        armored: &Armored,
+        username: Option<&str>,
+        passphrase: &str,
+        force: bool,
+    ) -> crate::Result<String> {
+        let username = cmd::key::import_username(armored, username)?;
+        cmd::key::import_secret(self.paths(), armored, &username, passphrase, force)?;
+        // the prekeys published before are gone with the old secrets
+        if let Some(server) = self.spec.directory() {
+            let profile = self.unlock(&username, passphrase)?;
+            if let Some(bundle) = PrekeyBundle::load(self.paths(), &username)? {
+                let mut relay = RelayClient::connect(server)?;
+                relay.authenticate(&username, &profile.keyring)?;
+                relay.publish_prekeys(&username, &bundle)?;
+            }
+        }
+        if profile::active(self.paths()).is_err() {
+            profile::set_active(self.paths(), &username)?;
+        }
+        Ok(username)
+    }
+}
@@ -0,0 +1,56 @@
+// control rune-agent, which keeps keyrings unlocked for a while
+//
+
+use clap::{App, Arg, SubCommand};
+
+use crate::agent::Unlocked;
+
+pub struct AgentCmd(pub App<'static>);
+
+impl Default for AgentCmd {
+    fn default() -> Self {
+        Self(
+            SubCommand::with_name("agent")
+                .about("keep keyrings unlocked in rune-agent so passphrases aren't asked for every time")
+                .subcommand_required(true)
+                .subcommand(
+                    SubCommand::with_name("unlock")
+                        .about("hand a keyring to rune-agent")
+                        .arg(
+                            Arg::with_name("timeout")
+                                .long("timeout")
+                                .takes_value(true)
+                                .value_name("DURATION")
+                                .help("How long to keep it unlocked, e.g. 30m or 2h [default: agent.timeout in rune.toml, or 10m]"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("lock")
+                        .about("make rune-agent forget keyrings")
+                        .arg(
+                            Arg::with_name("username")
+                                .help("identity to lock, every one if not given")
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("show whether rune-agent is running and what it holds"),
+                ),
+        )
+    }
+}
+
+// One line per unlocked keyring with the time it has left
+pub fn print_unlocked(unlocked: &[Unlocked]) {
+    if unlocked.is_empty() {
+        println!("No keyrings unlocked");
+    }
+    for keyring in unlocked {
+        println!(
+            "{} unlocked for another {}",
+            keyring.username,
+            humantime::format_duration(std::time::Duration::from_secs(keyring.expires_in))
+        );
+    }
+}
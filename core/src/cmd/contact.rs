@@ -0,0 +1,182 @@
+// manage contacts and their pinned keys
+//
+
+use crate::config::Paths;
+use crate::contacts::{ContactBook, KeyChanged, Trust};
+use crate::crypto::fingerprint::Fingerprint;
+use crate::crypto::keyring::Keyring;
+use crate::error::Error;
+use clap::{App, Arg, SubCommand};
+use curve25519_dalek::edwards::EdwardsPoint;
+
+pub struct ContactCmd(pub App<'static>);
+
+impl Default for ContactCmd {
+    fn default() -> Self {
+        Self(
+            SubCommand::with_name("contact")
+                .about("manage contacts and their identity keys")
+                .subcommand_required(true)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("add a contact, pinning their current identity key")
+                        .arg(
+                            Arg::with_name("username")
+                                .help("contact's username")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("name")
+                                .long("name")
+                                .takes_value(true)
+                                .help("display name for the contact"),
+                        )
+                        .arg(
+                            Arg::with_name("key")
+                                .long("key")
+                                .takes_value(true)
+                                .help("armored public key file, instead of looking the key up"),
+                        )
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("replace a different key already pinned for the contact"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("list your contacts"))
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("remove a contact and their pinned key")
+                        .arg(
+                            Arg::with_name("username")
+                                .help("contact's username")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("compare safety numbers with a contact and mark them verified")
+                        .arg(
+                            Arg::with_name("username")
+                                .help("contact's username")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("yes")
+                                .long("yes")
+                                .short('y')
+                                .help("don't ask whether the safety numbers match"),
+                        ),
+                ),
+        )
+    }
+}
+
+// The public key in an armored block
+pub fn read_public_key(text: &str) -> crate::Result<EdwardsPoint> {
+    use crate::armor::{ArmorKind, Armored};
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+
+    let armored = Armored::decode(text)?;
+    if armored.kind != ArmorKind::PublicKey {
+        return Err(Error::Invalid("expected a public key block".to_string()));
+    }
+    CompressedEdwardsY::from_slice(&armored.data)
+        .ok()
+        .and_then(|compressed| compressed.decompress())
+        .ok_or_else(|| Error::Malformed("the block doesn't contain a valid public key".to_string()))
+}
+
+// Pin `identity` for `username` in `owner`'s contacts. A key that differs
+// from the pinned one is only replaced with `force`.
+pub fn add_contact(
+    paths: &Paths,
+    owner: &str,
+    username: &str,
+    display_name: Option<&str>,
+    identity: &EdwardsPoint,
+    force: bool,
+) -> crate::Result<()> {
+    if !crate::relay::valid_username(username) {
+        return Err(Error::InvalidUsername(username.to_string()));
+    }
+
+    let mut contacts = ContactBook::load(paths, owner)?;
+    if let Some(pinned) = contacts.get(username).and_then(|c| c.fingerprint()) {
+        let presented = Fingerprint::of(identity);
+        if pinned != presented && !force {
+            return Err(KeyChanged {
+                username: username.to_string(),
+                pinned,
+                presented,
+            }
+            .into());
+        }
+    }
+    contacts.add(username, display_name, identity, Trust::Unverified);
+    Ok(contacts.save(paths, owner)?)
+}
+
+pub fn remove_contact(paths: &Paths, owner: &str, username: &str) -> crate::Result<()> {
+    let mut contacts = ContactBook::load(paths, owner)?;
+    contacts
+        .remove(username)
+        .ok_or_else(|| Error::UnknownContact(username.to_string()))?;
+    Ok(contacts.save(paths, owner)?)
+}
+
+pub fn print_contacts(contacts: &ContactBook) {
+    println!(
+        "{:<16}  {:<20}  {:<10}  {:<20}  FINGERPRINT",
+        "USERNAME", "NAME", "TRUST", "FIRST SEEN"
+    );
+    for contact in contacts.list() {
+        let first_seen = std::time::UNIX_EPOCH + std::time::Duration::from_secs(contact.first_seen);
+        println!(
+            "{:<16}  {:<20}  {:<10}  {:<20}  {}",
+            contact.username,
+            contact.display_name.as_deref().unwrap_or("-"),
+            contact.trust,
+            humantime::format_rfc3339_seconds(first_seen),
+            contact
+                .fingerprint()
+                .map(|f| f.hex())
+                .unwrap_or_else(|| "invalid key".to_string())
+        );
+    }
+}
+
+// The safety number between `owner` and the key pinned for `username`
+pub fn safety_number_report(paths: &Paths, owner: &str, username: &str) -> crate::Result<String> {
+    use crate::crypto::fingerprint::SafetyNumber;
+
+    let contacts = ContactBook::load(paths, owner)?;
+    let identity = contacts
+        .get(username)
+        .and_then(|c| c.identity())
+        .ok_or_else(|| Error::UnknownContact(username.to_string()))?;
+    let public = Keyring::load_public_key(paths, owner)?;
+
+    let safety_number = SafetyNumber::between(owner, &public, username, &identity);
+    Ok(format!(
+        "Safety number with {}:\n{}\n",
+        username,
+        safety_number
+            .to_string()
+            .lines()
+            .map(|row| format!("  {}", row))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))
+}
+
+pub fn verify_contact(paths: &Paths, owner: &str, username: &str) -> crate::Result<()> {
+    let mut contacts = ContactBook::load(paths, owner)?;
+    if !contacts.set_trust(username, Trust::Verified) {
+        return Err(Error::UnknownContact(username.to_string()));
+    }
+    Ok(contacts.save(paths, owner)?)
+}
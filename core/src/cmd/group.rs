@@ -0,0 +1,356 @@
+// create groups, manage their members and send to them
+//
+
+use clap::{App, Arg, SubCommand};
+
+use super::send::{recipient_identity, send_payload};
+use crate::config::Paths;
+use crate::crypto::keyring::Keyring;
+use crate::envelope::Payload;
+use crate::error::Error;
+use crate::group::{Control, Group, GroupInfo, Member};
+use crate::transport::Transport;
+
+pub struct GroupCmd(pub App<'static>);
+
+impl Default for GroupCmd {
+    fn default() -> Self {
+        let group = || {
+            Arg::with_name("group")
+                .help("group's name, or its id from `rune group list`")
+                .required(true)
+                .index(1)
+        };
+        let usernames = || {
+            Arg::with_name("usernames")
+                .help("members' usernames")
+                .required(true)
+                .multiple_values(true)
+                .index(2)
+        };
+
+        Self(
+            SubCommand::with_name("group")
+                .about("create groups, manage their members and send to them")
+                .subcommand_required(true)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("create a group, with you as its admin")
+                        .arg(
+                            Arg::with_name("name")
+                                .help("group's name")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("usernames")
+                                .help("members' usernames, besides you")
+                                .multiple_values(true)
+                                .index(2),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("add members to a group you're an admin of")
+                        .arg(group())
+                        .arg(usernames())
+                        .arg(
+                            Arg::with_name("admin").long("admin").help(
+                                "make them admins too, whether or not they're members already",
+                            ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("remove members from a group you're an admin of, rotating its keys")
+                        .arg(group())
+                        .arg(usernames()),
+                )
+                .subcommand(
+                    SubCommand::with_name("send")
+                        .about("send a message to everyone in a group")
+                        .arg(group())
+                        .arg(
+                            Arg::with_name("message")
+                                .help("message to send")
+                                .required(true)
+                                .index(2),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list").about("list your groups and their members"),
+                ),
+        )
+    }
+}
+
+// Send `control` to each of `recipients` over our sessions with them
+fn distribute(
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+    recipients: &[&str],
+    control: &Control,
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<()> {
+    let payload = control.to_payload();
+    for &recipient in recipients {
+        let identity = recipient_identity(paths, owner, recipient, directory)?;
+        send_payload(
+            paths, owner, keyring, recipient, &identity, &payload, false, transport, directory,
+        )?;
+    }
+    Ok(())
+}
+
+// Members of `info` other than `owner`
+fn others<'a>(info: &'a GroupInfo, owner: &str) -> Vec<&'a str> {
+    info.members
+        .iter()
+        .map(|m| m.username.as_str())
+        .filter(|&username| username != owner)
+        .collect()
+}
+
+// Look up and pin the identity keys of `usernames`
+fn resolve_members(
+    paths: &Paths,
+    owner: &str,
+    usernames: &[&str],
+    directory: Option<&str>,
+) -> crate::Result<Vec<Member>> {
+    let mut members = Vec::new();
+    for &username in usernames {
+        let identity = recipient_identity(paths, owner, username, directory)?;
+        members.push(Member::new(username, &identity));
+    }
+    Ok(members)
+}
+
+// Save the new version of a group and tell everyone in `recipients`
+#[allow(clippy::too_many_arguments)]
+fn publish(
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+    mut group: Group,
+    info: GroupInfo,
+    recipients: &[&str],
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<GroupInfo> {
+    group.update(info.clone());
+    group.save(paths, owner, keyring)?;
+    distribute(
+        paths,
+        owner,
+        keyring,
+        recipients,
+        &Control::Update(info.clone()),
+        transport,
+        directory,
+    )?;
+    Ok(info)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn create_group(
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+    name: &str,
+    usernames: &[&str],
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<GroupInfo> {
+    let mut invited: Vec<&str> = Vec::new();
+    for &username in usernames {
+        if username != owner && !invited.contains(&username) {
+            invited.push(username);
+        }
+    }
+    let mut members = vec![Member::new(owner, &keyring.public)];
+    members.extend(resolve_members(paths, owner, &invited, directory)?);
+
+    let info = GroupInfo::new(name, owner, keyring, members)?;
+    log::debug!("created group {} ({})", info.name, info.id_hex());
+    publish(
+        paths,
+        owner,
+        keyring,
+        Group::new(info.clone()),
+        info,
+        &invited,
+        transport,
+        directory,
+    )
+}
+
+// Add `usernames` to `group`, or with `admin` make them admins whether or
+// not they were members before
+#[allow(clippy::too_many_arguments)]
+pub fn add_members(
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+    group: &str,
+    usernames: &[&str],
+    admin: bool,
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<GroupInfo> {
+    let group = Group::find(paths, owner, keyring, group)?;
+    let mut members = group.info.members.clone();
+    let mut admins = group.info.admins.clone();
+    for &username in usernames {
+        match members.iter().any(|m| m.username == username) {
+            true if !admin => {
+                return Err(Error::Invalid(format!(
+                    "{} is already in {}",
+                    username, group.info.name
+                )))
+            }
+            true => {}
+            false => members.extend(resolve_members(paths, owner, &[username], directory)?),
+        }
+        if admin && !admins.iter().any(|a| a == username) {
+            admins.push(username.to_string());
+        }
+    }
+
+    let info = group.info.next(owner, keyring, members, admins)?;
+    let recipients = others(&info, owner);
+    publish(
+        paths,
+        owner,
+        keyring,
+        group,
+        info.clone(),
+        &recipients,
+        transport,
+        directory,
+    )
+}
+
+// Remove `usernames` from `group`. The group moves to a new epoch, so they
+// can't read anything sent to it from now on.
+#[allow(clippy::too_many_arguments)]
+pub fn remove_members(
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+    group: &str,
+    usernames: &[&str],
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<GroupInfo> {
+    let group = Group::find(paths, owner, keyring, group)?;
+    for &username in usernames {
+        if username == owner {
+            return Err(Error::Invalid(format!(
+                "you can't remove yourself from {}",
+                group.info.name
+            )));
+        }
+        if !group.info.is_member(username) {
+            return Err(Error::Invalid(format!(
+                "{} isn't in {}",
+                username, group.info.name
+            )));
+        }
+    }
+
+    let mut members = group.info.members.clone();
+    members.retain(|m| !usernames.contains(&m.username.as_str()));
+    let info = group
+        .info
+        .next(owner, keyring, members, group.info.admins.clone())?;
+    // those removed hear about it too, and nothing after
+    let mut recipients = others(&info, owner);
+    recipients.extend_from_slice(usernames);
+    publish(
+        paths,
+        owner,
+        keyring,
+        group,
+        info.clone(),
+        &recipients,
+        transport,
+        directory,
+    )
+}
+
+// Send `message` to everyone else in `group`, handing them our sender key
+// first if we haven't since the group last changed
+#[allow(clippy::too_many_arguments)]
+pub fn send_to_group(
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+    group: &str,
+    message: &str,
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<()> {
+    let mut group = Group::find(paths, owner, keyring, group)?;
+    if !group.info.is_member(owner) {
+        return Err(Error::Invalid(format!(
+            "you're no longer a member of {}",
+            group.info.name
+        )));
+    }
+    let info = group.info.clone();
+    let recipients = others(&info, owner);
+
+    if group.needs_sender_key() {
+        log::debug!("handing out a new sender key for {}", info.name);
+        let control = group.new_sender_key();
+        distribute(
+            paths,
+            owner,
+            keyring,
+            &recipients,
+            &control,
+            transport,
+            directory,
+        )?;
+    }
+    let mut envelope = group.encrypt(&Payload::text(message).to_bytes())?;
+    group.save(paths, owner, keyring)?;
+    envelope.sign(owner, keyring)?;
+
+    let bytes = envelope.to_bytes();
+    for recipient in recipients {
+        let id = transport.deliver(recipient, &bytes)?;
+        log::debug!("delivered {} to {} in {}", id, recipient, info.name);
+    }
+    Ok(())
+}
+
+pub fn print_groups(groups: &[Group], owner: &str) {
+    println!("{:<20}  {:<32}  {:>5}  MEMBERS", "NAME", "ID", "EPOCH");
+    for group in groups {
+        let info = &group.info;
+        let members = match info.is_member(owner) {
+            // admins are marked with a *
+            true => info
+                .members
+                .iter()
+                .map(|m| match info.is_admin(&m.username) {
+                    true => format!("{}*", m.username),
+                    false => m.username.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            false => "(you were removed)".to_string(),
+        };
+        println!(
+            "{:<20}  {:<32}  {:>5}  {}",
+            info.name,
+            info.id_hex(),
+            info.epoch,
+            members
+        );
+    }
+}
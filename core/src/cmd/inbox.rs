@@ -0,0 +1,508 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use clap::{App, Arg, SubCommand};
+use curve25519_dalek::edwards::EdwardsPoint;
+use zeroize::Zeroizing;
+
+use crate::attachment::{self, Attachment};
+use crate::config::Paths;
+use crate::contacts::ContactBook;
+use crate::crypto::keyring::Keyring;
+use crate::envelope::{CipherSuite, ContentType, Envelope, Payload};
+use crate::group::{Control, GroupCache};
+use crate::mailbox::{Mailbox, MailboxEntry, MessageId, MessageState};
+
+pub struct InboxCmd(pub App<'static>);
+
+impl Default for InboxCmd {
+    fn default() -> Self {
+        Self(
+            SubCommand::with_name("inbox")
+                .about("List, read and delete messages in your mailbox")
+                .arg(
+                    Arg::with_name("ids")
+                        .help("Messages to read; reads every listed message with --read")
+                        .multiple_values(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .short('a')
+                        .help("Include messages that have already been read"),
+                )
+                .arg(
+                    Arg::with_name("read")
+                        .long("read")
+                        .short('r')
+                        .help("Decrypt and print the listed messages"),
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .help("Delete messages once they've been read")
+                        .conflicts_with("archive"),
+                )
+                .arg(
+                    Arg::with_name("archive")
+                        .long("archive")
+                        .help("Move messages to the archive once they've been read"),
+                )
+                .arg(
+                    Arg::with_name("output-dir")
+                        .long("output-dir")
+                        .short('o')
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Where to save files sent with `rune send --file` [default: .]"),
+                ),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    // signed by the identity key of the claimed sender
+    Verified,
+    // the signature doesn't match the claimed sender's identity key
+    Forged,
+    // signed by a key for the claimed sender other than the one we pinned
+    KeyChanged,
+    // we don't have the claimed sender's identity key
+    UnknownSender,
+    // anonymous envelope, from `Envelope::seal`
+    Unsigned,
+    // not a readable envelope
+    Malformed,
+}
+
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verification::Verified => write!(f, "verified"),
+            Verification::Forged => write!(f, "FORGED"),
+            Verification::KeyChanged => write!(f, "KEY CHANGED"),
+            Verification::UnknownSender => write!(f, "unknown sender"),
+            Verification::Unsigned => write!(f, "unsigned"),
+            Verification::Malformed => write!(f, "malformed"),
+        }
+    }
+}
+
+// A message in the mailbox, checked but not yet decrypted
+pub struct InboxItem {
+    pub entry: MailboxEntry,
+    pub sender: Option<String>,
+    pub verification: Verification,
+    envelope: Option<Envelope>,
+    // the key the signature was checked against, when it verified
+    sender_public: Option<EdwardsPoint>,
+}
+
+// What to do with a message once it has been decrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AfterRead {
+    MarkRead,
+    Delete,
+    Archive,
+}
+
+pub struct ReceivedMessage {
+    pub id: MessageId,
+    pub sender: String,
+    pub body: Zeroizing<String>,
+    // everyone a message to several people went to, unless the sender hid
+    // them; empty otherwise
+    pub to: Vec<String>,
+    // the group a message was sent to, or that a notice is about
+    pub group: Option<String>,
+    // a file sent with `rune send --file`, still to be saved with
+    // `Attachment::save`; `body` describes it
+    pub attachment: Option<Attachment>,
+}
+
+// A message `open_messages` left where it was, and why
+pub struct Skipped {
+    pub id: MessageId,
+    pub sender: Option<String>,
+    pub verification: Verification,
+    // why a verified message couldn't be read
+    pub error: Option<String>,
+}
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.sender, &self.error) {
+            (None, _) => write!(f, "skipping message {}: {}", self.id, self.verification),
+            (Some(_), Some(e)) => write!(f, "skipping message {}: {}", self.id, e),
+            (Some(sender), None) => {
+                write!(
+                    f,
+                    "WARNING: skipping message {} claiming to be from {}: {}",
+                    self.id, sender, self.verification
+                )?;
+                if self.verification == Verification::KeyChanged {
+                    write!(
+                        f,
+                        "\nWARNING: {}'s identity key has changed since you pinned it. Compare \
+                         safety numbers with `rune key fingerprint {}` before trusting the new \
+                         key with `rune contact add --force {}`.",
+                        sender, sender, sender
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// What `open_messages` read, and what it couldn't
+pub struct Opened {
+    pub received: Vec<ReceivedMessage>,
+    pub skipped: Vec<Skipped>,
+}
+
+// Senders' identity keys for checking signatures. Keys of senders who aren't
+// contacts are looked up once each, like `directory::known_identity` does.
+struct Senders<'a> {
+    paths: &'a Paths,
+    directory: Option<&'a str>,
+    contacts: ContactBook,
+    published: HashMap<String, Option<EdwardsPoint>>,
+}
+
+impl<'a> Senders<'a> {
+    fn load(paths: &'a Paths, owner: &str, directory: Option<&'a str>) -> crate::Result<Self> {
+        Ok(Self {
+            paths,
+            directory,
+            contacts: ContactBook::load(paths, owner)?,
+            published: HashMap::new(),
+        })
+    }
+
+    fn published(&mut self, sender: &str) -> Option<EdwardsPoint> {
+        *self.published.entry(sender.to_string()).or_insert_with(|| {
+            crate::directory::published_identity(self.paths, sender, self.directory)
+                .map_err(|e| log::debug!("no identity key for {}: {}", sender, e))
+                .ok()
+        })
+    }
+
+    // Verify `envelope` against the key pinned for `sender`, or their
+    // published key if they aren't a contact yet
+    fn verify(
+        &mut self,
+        sender: &str,
+        envelope: &Envelope,
+    ) -> (Verification, Option<EdwardsPoint>) {
+        match self
+            .contacts
+            .get(sender)
+            .and_then(|contact| contact.identity())
+        {
+            Some(pinned) if envelope.verify(&pinned).is_ok() => {
+                (Verification::Verified, Some(pinned))
+            }
+            Some(pinned) => match self.published(sender) {
+                Some(published) if published != pinned && envelope.verify(&published).is_ok() => {
+                    (Verification::KeyChanged, None)
+                }
+                _ => (Verification::Forged, None),
+            },
+            None => match self.published(sender) {
+                Some(published) if envelope.verify(&published).is_ok() => {
+                    (Verification::Verified, Some(published))
+                }
+                Some(_) => (Verification::Forged, None),
+                None => (Verification::UnknownSender, None),
+            },
+        }
+    }
+}
+
+fn inspect(
+    mailbox: &Mailbox,
+    senders: &mut Senders,
+    entry: MailboxEntry,
+) -> std::io::Result<InboxItem> {
+    let envelope = match Envelope::from_bytes(&mailbox.read(&entry)?) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            log::warn!("message {} is malformed: {}", entry.id, e);
+            return Ok(InboxItem {
+                entry,
+                sender: None,
+                verification: Verification::Malformed,
+                envelope: None,
+                sender_public: None,
+            });
+        }
+    };
+    log::debug!(
+        "message {}: envelope v{} suite {:?}",
+        entry.id,
+        envelope.version(),
+        envelope.suite
+    );
+
+    let sender = envelope.sender.as_ref().map(|s| s.username.clone());
+    let (verification, sender_public) = match &sender {
+        None => (Verification::Unsigned, None),
+        Some(sender) => senders.verify(sender, &envelope),
+    };
+
+    Ok(InboxItem {
+        entry,
+        sender,
+        verification,
+        envelope: Some(envelope),
+        sender_public,
+    })
+}
+
+// The messages in `owner`'s mailbox, oldest first. Only unread messages are
+// listed unless `include_read` is set. Senders who aren't contacts are looked
+// up in the directory on `directory` when their key isn't on this machine.
+pub fn list_inbox(
+    paths: &Paths,
+    owner: &str,
+    include_read: bool,
+    directory: Option<&str>,
+) -> crate::Result<Vec<InboxItem>> {
+    let mailbox = Mailbox::open(paths, owner)?;
+    let mut senders = Senders::load(paths, owner, directory)?;
+    let entries = if include_read {
+        mailbox.list()?
+    } else {
+        mailbox.unread()?
+    };
+
+    let mut items = Vec::new();
+    for entry in entries {
+        // parts of attachments are read along with the attachment itself
+        if let Ok((CipherSuite::FileChunk, _)) =
+            Envelope::peek(&mailbox.read_prefix(&entry, attachment::PEEK_LEN)?)
+        {
+            continue;
+        }
+        items.push(inspect(&mailbox, &mut senders, entry)?);
+    }
+    Ok(items)
+}
+
+// Look up specific messages in `owner`'s mailbox, in the order given
+pub fn find_messages(
+    paths: &Paths,
+    owner: &str,
+    ids: &[MessageId],
+    directory: Option<&str>,
+) -> crate::Result<Vec<InboxItem>> {
+    let mailbox = Mailbox::open(paths, owner)?;
+    let mut senders = Senders::load(paths, owner, directory)?;
+    let mut items = Vec::new();
+    for id in ids {
+        items.push(inspect(&mailbox, &mut senders, mailbox.get(id)?)?);
+    }
+    Ok(items)
+}
+
+// Decrypt `items` with `owner`'s keyring, then mark, delete or archive each
+// one according to `after`. Messages that aren't verified or fail to decrypt
+// are left where they are and returned as skipped. Senders we hadn't heard
+// from before are pinned as contacts.
+pub fn open_messages(
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+    items: Vec<InboxItem>,
+    after: AfterRead,
+) -> crate::Result<Opened> {
+    use crate::crypto::prekey::PrekeyStore;
+    use crate::session::SessionStore;
+
+    let mailbox = Mailbox::open(paths, owner)?;
+    let mut contacts = ContactBook::load(paths, owner)?;
+    let mut contacts_changed = false;
+    let mut prekeys = PrekeyStore::load(paths, owner, keyring)?;
+    let mut sessions: HashMap<String, SessionStore> = HashMap::new();
+    let mut groups = GroupCache::new(paths, owner, keyring);
+
+    let mut received = Vec::new();
+    let mut skipped = Vec::new();
+    // moved once the state that read them is saved
+    let mut done = Vec::new();
+    for item in items {
+        let (Some(sender), Some(envelope)) = (item.sender.clone(), item.envelope) else {
+            skipped.push(Skipped {
+                id: item.entry.id,
+                sender: item.sender,
+                verification: item.verification,
+                error: None,
+            });
+            continue;
+        };
+        let (Verification::Verified, Some(sender_public)) = (item.verification, item.sender_public)
+        else {
+            skipped.push(Skipped {
+                id: item.entry.id,
+                sender: Some(sender),
+                verification: item.verification,
+                error: None,
+            });
+            continue;
+        };
+        let skip = |e: &dyn std::fmt::Display| Skipped {
+            id: item.entry.id.clone(),
+            sender: Some(sender.clone()),
+            verification: item.verification,
+            error: Some(e.to_string()),
+        };
+        // the keys for these were used up when they were first read
+        if item.entry.state != MessageState::Unread
+            && matches!(
+                envelope.suite,
+                CipherSuite::DoubleRatchet | CipherSuite::Group
+            )
+        {
+            skipped.push(skip(&"already read, and can only be decrypted once"));
+            continue;
+        }
+
+        let mut to = Vec::new();
+        let mut group = None;
+        let decrypted = match envelope.suite {
+            CipherSuite::X25519ChaCha20Poly1305 | CipherSuite::FileChunk => envelope.open(keyring),
+            CipherSuite::MultiRecipient => {
+                envelope
+                    .open_for_many(keyring)
+                    .map(|(recipients, message)| {
+                        to = recipients;
+                        message
+                    })
+            }
+            CipherSuite::DoubleRatchet => {
+                let store = match sessions.entry(sender.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        entry.insert(SessionStore::load(paths, owner, keyring, &sender)?)
+                    }
+                };
+                store.decrypt(keyring, prekeys.as_mut(), &sender_public, &envelope)
+            }
+            CipherSuite::Group => match groups.decrypt(&sender, &sender_public, &envelope) {
+                Ok((name, message)) => {
+                    group = Some(name);
+                    Ok(message)
+                }
+                Err(e) => {
+                    skipped.push(skip(&format!("failed to decrypt: {}", e)));
+                    continue;
+                }
+            },
+        };
+
+        match decrypted.map(Zeroizing::new) {
+            Ok(decrypted) => {
+                let payload = match Payload::from_bytes(&decrypted) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        skipped.push(skip(&e));
+                        continue;
+                    }
+                };
+                let (notice, attachment) = match payload.content_type {
+                    ContentType::Text => (None, None),
+                    // news about a group, from a member over our session with them
+                    ContentType::GroupControl if envelope.suite == CipherSuite::DoubleRatchet => {
+                        match Control::from_body(payload.body).and_then(|control| {
+                            groups.apply(&contacts, &sender, &sender_public, control)
+                        }) {
+                            Ok(notice) => (Some(notice), None),
+                            Err(e) => {
+                                skipped.push(skip(&e));
+                                continue;
+                            }
+                        }
+                    }
+                    ContentType::GroupControl => {
+                        skipped.push(skip(&"group changes are only taken over a session"));
+                        continue;
+                    }
+                    ContentType::Attachment => match Attachment::from_body(payload.body) {
+                        Ok(attachment) => (None, Some(attachment)),
+                        Err(e) => {
+                            skipped.push(skip(&e));
+                            continue;
+                        }
+                    },
+                };
+                // can't fail, the signature was checked against the pinned key
+                contacts_changed |= contacts.check(&sender, &sender_public).unwrap_or(false);
+                done.push(item.entry.clone());
+                let body = match (notice, &attachment) {
+                    // a sender key, nothing to show for it
+                    (Some(None), _) => continue,
+                    (Some(Some((name, notice))), _) => {
+                        group = Some(name);
+                        notice
+                    }
+                    (None, Some(attachment)) => format!("sent {}", attachment.describe()),
+                    (None, None) => String::from_utf8_lossy(payload.body).into_owned(),
+                };
+                received.push(ReceivedMessage {
+                    id: item.entry.id,
+                    sender,
+                    body: Zeroizing::new(body),
+                    to,
+                    group,
+                    attachment,
+                });
+            }
+            Err(e) => skipped.push(skip(&format!("failed to decrypt: {}", e))),
+        }
+    }
+
+    if contacts_changed {
+        contacts.save(paths, owner)?;
+    }
+    for (contact, store) in sessions {
+        store.save(paths, owner, keyring, &contact)?;
+    }
+    if let Some(prekeys) = prekeys {
+        prekeys.save(paths, owner, keyring)?;
+    }
+    groups.save()?;
+
+    // a message is only marked read once the session keys it used up are
+    // gone for good, so it can be read again if saving failed
+    for entry in &done {
+        match after {
+            AfterRead::MarkRead => mailbox.mark_read(entry)?,
+            AfterRead::Delete => mailbox.remove(entry)?,
+            AfterRead::Archive => mailbox.archive(entry)?,
+        }
+    }
+    Ok(Opened { received, skipped })
+}
+
+pub fn print_inbox(items: &[InboxItem]) {
+    println!(
+        "{:<39}  {:<20}  {:<16}  {:>8}  {:<7}  STATUS",
+        "ID", "RECEIVED", "FROM", "SIZE", "STATE"
+    );
+    for item in items {
+        let received =
+            std::time::UNIX_EPOCH + std::time::Duration::from_millis(item.entry.timestamp());
+        println!(
+            "{:<39}  {:<20}  {:<16}  {:>8}  {:<7}  {}",
+            item.entry.id,
+            humantime::format_rfc3339_seconds(received),
+            item.sender.as_deref().unwrap_or("-"),
+            item.entry.size,
+            item.entry.state,
+            item.verification
+        );
+    }
+}
@@ -1,26 +1,215 @@
 // manage keyring
 //
 
+use crate::armor::{ArmorKind, Armored};
+use crate::config::{Config, Paths};
 use crate::crypto::keyring::{Keyring, KeyringEncryptor};
-use clap::{App, Arg, SubCommand};
+use crate::error::Error;
+use clap::{App, Arg, ArgGroup, SubCommand};
 
-pub struct KeyCmd(pub(crate) App<'static>);
+pub struct KeyCmd(pub App<'static>);
 
 impl Default for KeyCmd {
     fn default() -> Self {
         Self(
-            SubCommand::with_name("key").about("manage keyring").arg(
-                Arg::with_name("display")
-                    .help("export a keyring with armor")
-                    .required(false)
-                    .index(1),
-            ),
+            SubCommand::with_name("key")
+                .about("manage keyring")
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("export a key as an armored text block")
+                        .arg(
+                            Arg::with_name("public")
+                                .long("public")
+                                .help("export your public key, to share with others"),
+                        )
+                        .arg(
+                            Arg::with_name("secret")
+                                .long("secret")
+                                .help("export your keyring, encrypted under a new passphrase"),
+                        )
+                        .group(
+                            ArgGroup::new("kind")
+                                .args(&["public", "secret"])
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("output")
+                                .long("output")
+                                .short('o')
+                                .takes_value(true)
+                                .help("write to this file instead of stdout"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("import an armored public key or keyring")
+                        .arg(
+                            Arg::with_name("file")
+                                .help("file to read, or stdin if not given")
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("force")
+                                .long("force")
+                                .help("replace a different key already stored for the username"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("fingerprint")
+                        .about("show your key's fingerprint, or your safety number with a contact")
+                        .arg(
+                            Arg::with_name("contact")
+                                .help("username to compare keys with")
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("passwd").about("change the passphrase for your keyring"),
+                ),
         )
     }
 }
 
-impl KeyCmd {
-    pub fn unsafe_display(keyring: Keyring) {
-        println!("(unsafe!) keyring: {:?}", keyring);
+pub fn export_public(paths: &Paths, username: &str) -> crate::Result<String> {
+    let public = Keyring::load_public_key(paths, username)?;
+    Ok(
+        Armored::new(ArmorKind::PublicKey, public.compress().to_bytes().to_vec())
+            .with_header("Username", username)
+            .encode(),
+    )
+}
+
+// `username`'s keyring, decrypted with `passphrase` and re-encrypted under
+// `export_passphrase`
+pub fn export_secret(
+    config: &Config,
+    username: &str,
+    passphrase: &str,
+    export_passphrase: &str,
+) -> crate::Result<String> {
+    let path = KeyringEncryptor::path(&config.paths, username);
+    let keyring = KeyringEncryptor::decrypt(&path, passphrase)?;
+    let sealed = KeyringEncryptor::from(keyring).seal(export_passphrase, config.kdf)?;
+    Ok(Armored::new(ArmorKind::SecretKey, sealed)
+        .with_header("Username", username)
+        .encode())
+}
+
+// The username to import `armored` as, `username` if given, otherwise its
+// Username header
+pub fn import_username(armored: &Armored, username: Option<&str>) -> crate::Result<String> {
+    let username = username
+        .or_else(|| armored.header("Username"))
+        .ok_or_else(|| {
+            Error::Invalid("the block has no Username header, pass one with --as".to_string())
+        })?;
+    if !crate::relay::valid_username(username) {
+        return Err(Error::InvalidUsername(username.to_string()));
+    }
+    Ok(username.to_string())
+}
+
+// Store an armored public key as `username`'s public-key.pub
+pub fn import_public(
+    paths: &Paths,
+    armored: &Armored,
+    username: &str,
+    force: bool,
+) -> crate::Result<()> {
+    use curve25519_dalek::edwards::CompressedEdwardsY;
+
+    let public = CompressedEdwardsY::from_slice(&armored.data)
+        .ok()
+        .and_then(|compressed| compressed.decompress())
+        .ok_or_else(|| {
+            Error::Malformed("the block doesn't contain a valid public key".to_string())
+        })?;
+
+    if let Ok(existing) = Keyring::load_public_key(paths, username) {
+        if existing == public {
+            return Ok(());
+        }
+        if !force {
+            return Err(Error::Invalid(format!(
+                "a different key is already stored for {}, use --force to replace it",
+                username
+            )));
+        }
+    }
+
+    std::fs::create_dir_all(paths.user(username))?;
+    Ok(Keyring::store_public_key(paths, username, &public)?)
+}
+
+// Set up `username` on this machine from an armored keyring, which keeps the
+// passphrase it was exported with. Fresh prekeys are published for it.
+pub fn import_secret(
+    paths: &Paths,
+    armored: &Armored,
+    username: &str,
+    passphrase: &str,
+    force: bool,
+) -> crate::Result<()> {
+    use crate::crypto::prekey::{PrekeyStore, ONE_TIME_PREKEYS};
+
+    let keyring = KeyringEncryptor::open(&armored.data, passphrase)?;
+
+    let path = KeyringEncryptor::path(paths, username);
+    if path.exists() && !force {
+        return Err(Error::Invalid(format!(
+            "{} already has a keyring here, use --force to replace it",
+            username
+        )));
+    }
+    std::fs::create_dir_all(paths.user(username))?;
+
+    crate::util::write_atomic(&path, &armored.data)?;
+    keyring.save_public_key(paths, username)?;
+    let (prekeys, bundle) = PrekeyStore::generate(&keyring, ONE_TIME_PREKEYS)?;
+    prekeys.save(paths, username, &keyring)?;
+    bundle.save(paths, username)?;
+    Ok(())
+}
+
+// `username`'s fingerprint, and with `contact` theirs and the safety number
+// to compare with them. `directory` is where to look up the contact's key.
+pub fn fingerprint_report(
+    paths: &Paths,
+    username: &str,
+    contact: Option<&str>,
+    directory: Option<&str>,
+) -> crate::Result<String> {
+    use crate::crypto::fingerprint::{Fingerprint, SafetyNumber};
+
+    fn describe(name: &str, fingerprint: &Fingerprint) -> String {
+        format!(
+            "{}\n  {}\n  {}\n",
+            name,
+            fingerprint.hex(),
+            fingerprint.words()
+        )
+    }
+
+    let public = Keyring::load_public_key(paths, username)?;
+    let mut report = describe(username, &Fingerprint::of(&public));
+
+    if let Some(contact) = contact {
+        let contact_public = crate::directory::resolve_identity(paths, contact, directory)?;
+        report.push('\n');
+        report.push_str(&describe(contact, &Fingerprint::of(&contact_public)));
+
+        let safety_number = SafetyNumber::between(username, &public, contact, &contact_public);
+        report.push_str(&format!(
+            "\nSafety number with {}:\n{}\n\nIt should match the one {} sees for you.\n",
+            contact,
+            safety_number
+                .to_string()
+                .lines()
+                .map(|row| format!("  {}", row))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            contact
+        ));
     }
+    Ok(report)
 }
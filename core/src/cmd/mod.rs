@@ -1,3 +1,8 @@
+pub mod agent;
+pub mod contact;
+pub mod group;
+pub mod inbox;
 pub mod key;
+pub mod profile;
 pub mod register;
 pub mod send;
@@ -0,0 +1,55 @@
+// manage local identities
+//
+
+use clap::{App, Arg, SubCommand};
+
+pub struct ProfileCmd(pub App<'static>);
+
+impl Default for ProfileCmd {
+    fn default() -> Self {
+        Self(
+            SubCommand::with_name("profile")
+                .about("switch between the identities on this machine")
+                .subcommand_required(true)
+                .subcommand(SubCommand::with_name("list").about("list local identities"))
+                .subcommand(
+                    SubCommand::with_name("use")
+                        .about("make an identity the active one")
+                        .arg(
+                            Arg::with_name("username")
+                                .help("identity to switch to")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("delete an identity's keyring, contacts and messages")
+                        .arg(
+                            Arg::with_name("username")
+                                .help("identity to delete")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("yes")
+                                .long("yes")
+                                .short('y')
+                                .help("don't ask for confirmation"),
+                        ),
+                ),
+        )
+    }
+}
+
+// One line per profile, the active one marked with `*`
+pub fn print_profiles(profiles: &[String], active: Option<&str>) {
+    for profile in profiles {
+        let marker = if Some(profile.as_str()) == active {
+            "*"
+        } else {
+            " "
+        };
+        println!("{} {}", marker, profile);
+    }
+}
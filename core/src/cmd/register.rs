@@ -1,6 +1,9 @@
 use clap::{App, Arg, SubCommand};
 
-pub struct RegisterCmd(pub(crate) App<'static>);
+use crate::config::Paths;
+use crate::error::Error;
+
+pub struct RegisterCmd(pub App<'static>);
 
 impl Default for RegisterCmd {
     fn default() -> Self {
@@ -17,26 +20,19 @@ impl Default for RegisterCmd {
     }
 }
 
-// Create a directory at $HOME/.config/rune/ to store user's contact list of public keys
-pub fn register_create_home(username: &str) -> std::io::Result<()> {
-    let home = dirs::home_dir().unwrap();
-    if home.join(".config/rune").exists() {
-        if home.join(format!(".config/rune/{}", username)).exists() {
-            println!("Username already exists");
-            std::process::exit(1);
-        }
-        std::fs::create_dir(home.join(format!(".config/rune/{}", username)))?;
-    } else {
-        std::fs::create_dir(home.join(".config/rune"))?;
-        std::fs::create_dir(home.join(format!(".config/rune/{}", username)))?;
+// Create a directory for the user in the data directory, to hold their keyring and messages
+pub fn register_create_home(paths: &Paths, username: &str) -> crate::Result<()> {
+    let home = paths.user(username);
+    if home.exists() {
+        return Err(Error::UsernameTaken(username.to_string()));
     }
-    Ok(())
+    Ok(std::fs::create_dir_all(home)?)
 }
 
-pub(crate) fn prompt_passphrase() -> std::io::Result<String> {
+pub fn prompt(message: &str) -> std::io::Result<String> {
     use std::io::{stdin, stdout, Write};
 
-    print!("Enter passphrase to encrypt your keyring: ");
+    print!("{}", message);
     stdout().flush()?;
 
     let mut passphrase = String::new();
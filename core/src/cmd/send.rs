@@ -1,8 +1,19 @@
+use std::io::BufReader;
+use std::path::Path;
+
 use clap::{App, Arg, SubCommand};
-use curve25519_dalek::edwards::CompressedEdwardsY;
-use std::fs;
+use curve25519_dalek::edwards::EdwardsPoint;
+
+use super::inbox::InboxItem;
+use crate::attachment::Attachment;
+use crate::config::Paths;
+use crate::contacts::ContactBook;
+use crate::crypto::keyring::Keyring;
+use crate::envelope::{Envelope, Payload};
+use crate::error::Error;
+use crate::transport::Transport;
 
-pub struct SendCmd(pub(crate) App<'static>);
+pub struct SendCmd(pub App<'static>);
 
 impl Default for SendCmd {
     fn default() -> Self {
@@ -11,100 +22,232 @@ impl Default for SendCmd {
                 .about("Send an encrypted message to someone")
                 .arg(
                     Arg::with_name("recipient")
-                        .help("Recipient's username")
+                        .help("Recipient's username, or several separated by commas")
                         .required(true)
                         .index(1),
                 )
                 .arg(
                     Arg::with_name("message")
                         .help("Message to send")
-                        .required(true)
+                        .required_unless_present("file")
                         .index(2),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .long("file")
+                        .short('f')
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Send a file instead of a message")
+                        .conflicts_with("message"),
+                )
+                .arg(
+                    Arg::with_name("encrypt-headers")
+                        .long("encrypt-headers")
+                        .help("Encrypt session headers when starting a new session"),
+                )
+                .arg(
+                    Arg::with_name("hide-recipients")
+                        .long("hide-recipients")
+                        .help("Don't tell the recipients of a message to several people who else it went to"),
                 ),
         )
     }
 }
 
-pub(crate) fn send_message(recipient_username: &str, message: &str) -> std::io::Result<()> {
-    use crate::crypto::e2ee::encrypt_message_for;
-
-    let home = dirs::home_dir().unwrap();
-    let recipient_path = home.join(format!(".config/rune/{}", recipient_username));
-    if !recipient_path.exists() {
-        println!("Recipient does not exist");
-        return Ok(());
-    } else {
-        let recipient_public_key = fs::read(recipient_path.join("public-key.pub"))?;
-        let recipient_public_edwards = CompressedEdwardsY::from_slice(&recipient_public_key)
-            .unwrap()
-            .decompress()
-            .unwrap();
-
-        let (encrypted, ephemeral_public) =
-            encrypt_message_for(&recipient_public_edwards, message.as_bytes());
-
-        fs::write(recipient_path.join("encrypted_message.txt"), &encrypted)?;
-        fs::write(
-            recipient_path.join("ephemeral_public.txt"),
-            ephemeral_public.as_bytes(),
+// The identity key to encrypt to for `recipient`: the one `sender` pinned,
+// or else the one found for them, which is pinned on first use
+pub(crate) fn recipient_identity(
+    paths: &Paths,
+    sender_username: &str,
+    recipient_username: &str,
+    directory: Option<&str>,
+) -> crate::Result<EdwardsPoint> {
+    let mut contacts = ContactBook::load(paths, sender_username)?;
+    let recipient_public_edwards =
+        crate::directory::known_identity(paths, &contacts, recipient_username, directory)?;
+    if contacts.check(recipient_username, &recipient_public_edwards)? {
+        contacts.save(paths, sender_username)?;
+    }
+    Ok(recipient_public_edwards)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn send_payload(
+    paths: &Paths,
+    sender_username: &str,
+    sender_keyring: &Keyring,
+    recipient_username: &str,
+    recipient_public_edwards: &EdwardsPoint,
+    payload: &[u8],
+    header_encryption: bool,
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<()> {
+    use crate::crypto::prekey::PrekeyBundle;
+    use crate::relay::RelayClient;
+    use crate::session::SessionStore;
+
+    let mut sessions =
+        SessionStore::load(paths, sender_username, sender_keyring, recipient_username)?;
+    if !sessions.is_established() {
+        log::debug!("starting a new session with {}", recipient_username);
+        // the directory hands each one-time prekey out only once, a bundle
+        // published on this machine has to be saved without the one we claim
+        let mut bundle = match directory {
+            Some(server) => RelayClient::connect(server)?.claim_prekeys(recipient_username)?,
+            None => PrekeyBundle::load(paths, recipient_username)?,
+        };
+        sessions.initiate(
+            sender_keyring,
+            recipient_public_edwards,
+            bundle.as_mut(),
+            header_encryption,
         )?;
+        if let (None, Some(bundle)) = (directory, bundle) {
+            bundle.save(paths, recipient_username)?;
+        }
+    }
+    let mut envelope = sessions.encrypt(payload)?;
+    envelope.sign(sender_username, sender_keyring)?;
+    sessions.save(paths, sender_username, sender_keyring, recipient_username)?;
+    let id = transport.deliver(recipient_username, &envelope.to_bytes())?;
+    log::debug!("delivered {} to {}", id, recipient_username);
+
+    Ok(())
+}
 
-        Ok(())
+#[allow(clippy::too_many_arguments)]
+pub fn send_message(
+    paths: &Paths,
+    sender_username: &str,
+    sender_keyring: &Keyring,
+    recipient_username: &str,
+    message: &str,
+    header_encryption: bool,
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<()> {
+    let recipient_public_edwards =
+        recipient_identity(paths, sender_username, recipient_username, directory)?;
+    send_payload(
+        paths,
+        sender_username,
+        sender_keyring,
+        recipient_username,
+        &recipient_public_edwards,
+        &Payload::text(message).to_bytes(),
+        header_encryption,
+        transport,
+        directory,
+    )
+}
+
+// Send one envelope (see `Envelope::seal_for_many`) carrying `message` to
+// all of `recipients`. It's encrypted to their identity keys rather than
+// through a session, so it doesn't get the session's forward secrecy.
+#[allow(clippy::too_many_arguments)]
+pub fn send_to_many(
+    paths: &Paths,
+    sender_username: &str,
+    sender_keyring: &Keyring,
+    recipient_usernames: &[&str],
+    message: &str,
+    hide_recipients: bool,
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<()> {
+    // every recipient is checked before anything is sent to any of them
+    let mut recipients = Vec::new();
+    for &recipient_username in recipient_usernames {
+        let identity = recipient_identity(paths, sender_username, recipient_username, directory)?;
+        recipients.push((recipient_username, identity));
+    }
+
+    let payload = Payload::text(message).to_bytes();
+    let mut envelope = Envelope::seal_for_many(&recipients, &payload, hide_recipients)?;
+    envelope.sign(sender_username, sender_keyring)?;
+    let bytes = envelope.to_bytes();
+    for (recipient_username, _) in recipients {
+        let id = transport.deliver(recipient_username, &bytes)?;
+        log::debug!("delivered {} to {}", id, recipient_username);
     }
+    Ok(())
+}
+
+// Send the file at `path` as an attachment (see `attachment`), returning it
+#[allow(clippy::too_many_arguments)]
+pub fn send_file(
+    paths: &Paths,
+    sender_username: &str,
+    sender_keyring: &Keyring,
+    recipient_username: &str,
+    path: &Path,
+    header_encryption: bool,
+    transport: &mut dyn Transport,
+    directory: Option<&str>,
+) -> crate::Result<Attachment> {
+    let recipient_public_edwards =
+        recipient_identity(paths, sender_username, recipient_username, directory)?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::Invalid(format!("can't send {}", path.display())))?;
+    let mut file = BufReader::new(std::fs::File::open(path)?);
+    let attachment = Attachment::upload(name, &mut file, recipient_username, transport)?;
+    send_payload(
+        paths,
+        sender_username,
+        sender_keyring,
+        recipient_username,
+        &recipient_public_edwards,
+        &attachment.to_payload(),
+        header_encryption,
+        transport,
+        directory,
+    )?;
+    Ok(attachment)
 }
 
-pub struct ReceiveCmd(pub(crate) App<'static>);
+pub struct ReceiveCmd(pub App<'static>);
 
 impl Default for ReceiveCmd {
     fn default() -> Self {
         Self(
             SubCommand::with_name("receive")
-                .about("Decrypt a message sent to you")
+                .about("Decrypt unread messages sent to you")
                 .arg(
                     Arg::with_name("sender")
-                        .help("Sender's username")
+                        .help("Only read messages from this username")
                         .required(true)
                         .index(1),
                 )
-                .arg(Arg::with_name("receive_as").required(false).index(2)),
+                .arg(
+                    Arg::with_name("output-dir")
+                        .long("output-dir")
+                        .short('o')
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Where to save files sent with `rune send --file` [default: .]"),
+                ),
         )
     }
 }
 
-pub(crate) fn receive_message(
-    sender: &str,
+// The unread messages from `sender` in `receiver`'s mailbox, oldest first.
+// Ones that fail to verify are included, for `open_messages` to skip and
+// report.
+pub fn unread_from(
+    paths: &Paths,
     receiver: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    use crate::crypto::e2ee::decrypt_message_from;
-    use crate::crypto::keyring::KeyringEncryptor;
-    use curve25519_dalek::montgomery::MontgomeryPoint;
-
-    let home = dirs::home_dir().unwrap();
-    let sender_path = home.join(format!(".config/rune/{}", sender));
-    if !sender_path.exists() {
-        println!("Sender does not exist");
-        std::process::exit(1);
-    }
-
-    log::debug!("sender_path: {}", sender_path.display());
-
-    let receiver_path = home.join(format!(".config/rune/{}", receiver));
-    log::debug!("receiver_path: {}", receiver_path.display());
-    let encrypted = fs::read(receiver_path.join("encrypted_message.txt"))?;
-    let ephemeral_public_path = receiver_path.join("ephemeral_public.txt");
-    let ephemeral_public_bytes: [u8; 32] = fs::read(ephemeral_public_path)?.try_into().unwrap();
-    let ephemeral_public = MontgomeryPoint(ephemeral_public_bytes);
-
-    // Decrypt the message
-    let passphrase = crate::cmd::register::prompt_passphrase()?;
-    let keyring = KeyringEncryptor::decrypt(
-        &format!("{}/.config/rune/{}/keyring.enc", home.display(), receiver),
-        &passphrase,
-    )
-    .unwrap();
-    let decrypted = decrypt_message_from(&keyring.private, &encrypted, ephemeral_public);
+    sender: &str,
+    directory: Option<&str>,
+) -> crate::Result<Vec<InboxItem>> {
+    // fails if there's no key for them anywhere
+    let contacts = ContactBook::load(paths, receiver)?;
+    crate::directory::known_identity(paths, &contacts, sender, directory)?;
 
-    let msg = String::from_utf8_lossy(&decrypted);
-    println!("Decrypted Message: {}", String::from_utf8_lossy(&decrypted));
-    Ok(msg.to_string())
+    let mut pending = super::inbox::list_inbox(paths, receiver, false, directory)?;
+    pending.retain(|item| item.sender.as_deref() == Some(sender));
+    Ok(pending)
 }
@@ -0,0 +1,232 @@
+// Where rune keeps its files, and the settings in rune.toml
+//
+// Configuration (rune.toml) lives in $XDG_CONFIG_HOME/rune, by default
+// $HOME/.config/rune. Everything else, the active profile and each user's
+// keyring, sessions, contacts and mailbox, lives in $XDG_DATA_HOME/rune, by
+// default $HOME/.local/share/rune. Setting $RUNE_HOME puts both in that one
+// directory instead, which is what tests and portable installs want.
+//
+// Before the split everything was kept in $HOME/.config/rune, so that's still
+// used for data if it holds any user's keyring, or an active profile, and
+// nothing has been written to the new data directory yet.
+//
+// rune.toml is optional, and so is every setting in it:
+//
+//   transport = "relay:example.org:7878"
+//   theme = "light"
+//   pinentry = "pinentry-curses"
+//
+//   [agent]
+//   timeout = "30m"     # how long rune-agent holds unlocked keyrings
+//
+//   [kdf]
+//   m_cost = 65536
+//   t_cost = 3
+//   p_cost = 1
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::crypto::keyring::{KdfId, KdfParams};
+use crate::error::Error;
+use crate::transport::TransportSpec;
+
+pub const HOME_ENV: &str = "RUNE_HOME";
+pub const CONFIG_FILE: &str = "rune.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    config: PathBuf,
+    data: PathBuf,
+}
+
+// Whether `dir` holds data from before the config and data directories were
+// split: a <user>/keyring.enc, as every install has, or the active profile
+fn holds_old_data(dir: &Path) -> bool {
+    if dir.join("me").exists() {
+        return true;
+    }
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .any(|entry| entry.path().join("keyring.enc").is_file())
+        })
+        .unwrap_or(false)
+}
+
+// $`var` if it's set to an absolute path, as the XDG spec asks
+fn xdg_dir(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+}
+
+impl Paths {
+    // From $RUNE_HOME, or the XDG directories
+    pub fn from_env() -> crate::Result<Self> {
+        if let Some(root) = std::env::var_os(HOME_ENV).filter(|root| !root.is_empty()) {
+            return Ok(Self::at(root));
+        }
+
+        let home = dirs::home_dir().ok_or_else(|| {
+            Error::Config(format!("can't find a home directory, set ${}", HOME_ENV))
+        })?;
+        let config = xdg_dir("XDG_CONFIG_HOME")
+            .unwrap_or_else(|| home.join(".config"))
+            .join("rune");
+        let data = xdg_dir("XDG_DATA_HOME")
+            .unwrap_or_else(|| home.join(".local/share"))
+            .join("rune");
+        let data = match !data.exists() && holds_old_data(&config) {
+            true => {
+                log::debug!("using data from before the split in {}", config.display());
+                config.clone()
+            }
+            false => data,
+        };
+        Ok(Self { config, data })
+    }
+
+    // Keep configuration and data together under `root`
+    pub fn at(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        Self {
+            config: root.clone(),
+            data: root,
+        }
+    }
+
+    pub fn config_dir(&self) -> &Path {
+        &self.config
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.config.join(CONFIG_FILE)
+    }
+
+    // Holds the active profile's username
+    pub fn active_profile(&self) -> PathBuf {
+        self.data.join("me")
+    }
+
+    // `username`'s keyring, sessions, contacts and mailbox
+    pub fn user(&self, username: &str) -> PathBuf {
+        self.data.join(username)
+    }
+
+    // Where rune-agent listens
+    pub fn agent_socket(&self) -> PathBuf {
+        self.data.join("agent.sock")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub paths: Paths,
+    // used when neither --transport nor $RUNE_TRANSPORT are given
+    pub transport: Option<TransportSpec>,
+    // cost of the KDF protecting newly encrypted keyrings
+    pub kdf: KdfParams,
+    // name of the TUI's color theme
+    pub theme: Option<String>,
+    // program to ask for passphrases, see `passphrase`
+    pub pinentry: Option<String>,
+    // how long rune-agent holds unlocked keyrings by default
+    pub agent_timeout: Duration,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    transport: Option<String>,
+    theme: Option<String>,
+    pinentry: Option<String>,
+    agent: Option<AgentSettings>,
+    kdf: Option<KdfCost>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AgentSettings {
+    timeout: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KdfCost {
+    m_cost: Option<u32>,
+    t_cost: Option<u32>,
+    p_cost: Option<u32>,
+}
+
+fn invalid_config(path: &Path, message: impl std::fmt::Display) -> Error {
+    Error::Config(format!("{}: {}", path.display(), message))
+}
+
+impl Config {
+    // `paths` with the settings from its rune.toml, if there is one
+    pub fn load(paths: Paths) -> crate::Result<Self> {
+        let path = paths.config_file();
+        let file = match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| invalid_config(&path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ConfigFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        log::debug!(
+            "data in {}, config in {}",
+            paths.data.display(),
+            path.display()
+        );
+
+        let transport = file
+            .transport
+            .map(|spec| spec.parse())
+            .transpose()
+            .map_err(|e| invalid_config(&path, e))?;
+
+        let agent_timeout = file
+            .agent
+            .and_then(|agent| agent.timeout)
+            .map(|timeout| humantime::parse_duration(&timeout))
+            .transpose()
+            .map_err(|e| invalid_config(&path, format!("bad agent timeout: {}", e)))?
+            .map(crate::agent::check_timeout)
+            .transpose()
+            .map_err(|e| invalid_config(&path, format!("bad agent timeout: {}", e)))?
+            .unwrap_or(crate::agent::DEFAULT_TIMEOUT);
+
+        let mut kdf = KdfParams::default();
+        if let Some(cost) = file.kdf {
+            kdf = KdfParams {
+                id: KdfId::Argon2id,
+                m_cost: cost.m_cost.unwrap_or(kdf.m_cost),
+                t_cost: cost.t_cost.unwrap_or(kdf.t_cost),
+                p_cost: cost.p_cost.unwrap_or(kdf.p_cost),
+            };
+            // catch bad costs now rather than when a keyring is encrypted
+            kdf.check()
+                .map_err(|e| invalid_config(&path, format!("bad kdf cost: {}", e)))?;
+        }
+
+        Ok(Self {
+            paths,
+            transport,
+            kdf,
+            theme: file.theme,
+            pinentry: file.pinentry,
+            agent_timeout,
+        })
+    }
+
+    // Settings for the environment's `Paths`
+    pub fn from_env() -> crate::Result<Self> {
+        Self::load(Paths::from_env()?)
+    }
+}
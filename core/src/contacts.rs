@@ -0,0 +1,304 @@
+// Contacts and their pinned identity keys
+//
+// Each identity keeps its own contact book at
+// <data dir>/<user>/contacts. A contact's key is pinned the first
+// time it's used (trust on first use) or when it's added by hand, and from
+// then on a different key for the same username is treated as a possible
+// impersonation: sending is refused and messages signed with the new key
+// aren't decrypted, until the user re-adds or re-verifies the contact.
+//
+// The file is `CONTACTS_MAGIC || version` followed by the bincode encoded
+// book, the same layout as a `PrekeyBundle`.
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Paths;
+use crate::crypto::fingerprint::Fingerprint;
+use crate::error::Error;
+
+pub const CONTACTS_MAGIC: [u8; 4] = *b"RCTS";
+pub const CONTACTS_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Trust {
+    // added by hand, key not checked with the contact
+    Unverified,
+    // pinned automatically the first time we exchanged messages
+    Tofu,
+    // safety number compared with the contact
+    Verified,
+}
+
+impl std::fmt::Display for Trust {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // pad, so it lines up in `rune contact list`
+        f.pad(match self {
+            Trust::Unverified => "unverified",
+            Trust::Tofu => "tofu",
+            Trust::Verified => "verified",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub username: String,
+    pub display_name: Option<String>,
+    // compressed Edwards identity key, as in public-key.pub
+    identity: [u8; 32],
+    // seconds since the unix epoch
+    pub first_seen: u64,
+    pub trust: Trust,
+}
+
+impl Contact {
+    pub fn identity(&self) -> Option<EdwardsPoint> {
+        CompressedEdwardsY(self.identity).decompress()
+    }
+
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.identity().map(|identity| Fingerprint::of(&identity))
+    }
+
+    fn matches(&self, identity: &EdwardsPoint) -> bool {
+        self.identity == identity.compress().to_bytes()
+    }
+}
+
+// A contact presented a different key than the one we pinned
+#[derive(Debug)]
+pub struct KeyChanged {
+    pub username: String,
+    pub pinned: Fingerprint,
+    pub presented: Fingerprint,
+}
+
+impl std::fmt::Display for KeyChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the identity key for {} has changed (pinned {}, now {}). \
+             If this is expected, compare safety numbers and run `rune contact add --force {}`",
+            self.username, self.pinned, self.presented, self.username
+        )
+    }
+}
+
+impl std::error::Error for KeyChanged {}
+
+#[derive(Serialize, Deserialize)]
+pub struct ContactBook {
+    magic: [u8; 4],
+    version: u8,
+    contacts: Vec<Contact>,
+}
+
+impl Default for ContactBook {
+    fn default() -> Self {
+        Self {
+            magic: CONTACTS_MAGIC,
+            version: CONTACTS_VERSION,
+            contacts: Vec::new(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ContactBook {
+    pub fn path(paths: &Paths, owner: &str) -> PathBuf {
+        paths.user(owner).join("contacts")
+    }
+
+    // `owner`'s contacts, or an empty book if they have none yet
+    pub fn load(paths: &Paths, owner: &str) -> crate::Result<Self> {
+        let path = Self::path(paths, owner);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let bytes = std::fs::read(&path)?;
+        if !bytes.starts_with(&CONTACTS_MAGIC) {
+            return Err(Error::Malformed(format!(
+                "{} is not a contact book",
+                path.display()
+            )));
+        }
+        if let Some(&version) = bytes.get(CONTACTS_MAGIC.len()) {
+            if version != CONTACTS_VERSION {
+                return Err(Error::Malformed(format!(
+                    "unsupported contact book version {}",
+                    version
+                )));
+            }
+        }
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    pub fn save(&self, paths: &Paths, owner: &str) -> std::io::Result<()> {
+        let path = Self::path(paths, owner);
+        std::fs::create_dir_all(paths.user(owner))?;
+        crate::util::write_atomic(
+            &path,
+            &bincode::serialize(self).expect("contact book serialization is infallible"),
+        )
+    }
+
+    // Contacts sorted by username
+    pub fn list(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|c| c.username == username)
+    }
+
+    // Add `username` with `identity`, or replace their pinned key. A new key
+    // starts out with `trust`; re-adding the same key keeps the trust it had.
+    pub fn add(
+        &mut self,
+        username: &str,
+        display_name: Option<&str>,
+        identity: &EdwardsPoint,
+        trust: Trust,
+    ) -> &Contact {
+        let index = match self.contacts.iter().position(|c| c.username == username) {
+            Some(index) => {
+                let contact = &mut self.contacts[index];
+                if !contact.matches(identity) {
+                    contact.identity = identity.compress().to_bytes();
+                    contact.first_seen = now();
+                    contact.trust = trust;
+                }
+                if display_name.is_some() {
+                    contact.display_name = display_name.map(str::to_string);
+                }
+                index
+            }
+            None => {
+                let index = self
+                    .contacts
+                    .partition_point(|c| c.username.as_str() < username);
+                self.contacts.insert(
+                    index,
+                    Contact {
+                        username: username.to_string(),
+                        display_name: display_name.map(str::to_string),
+                        identity: identity.compress().to_bytes(),
+                        first_seen: now(),
+                        trust,
+                    },
+                );
+                index
+            }
+        };
+        &self.contacts[index]
+    }
+
+    pub fn remove(&mut self, username: &str) -> Option<Contact> {
+        let index = self.contacts.iter().position(|c| c.username == username)?;
+        Some(self.contacts.remove(index))
+    }
+
+    pub fn set_trust(&mut self, username: &str, trust: Trust) -> bool {
+        match self.contacts.iter_mut().find(|c| c.username == username) {
+            Some(contact) => {
+                contact.trust = trust;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Check `identity` against the key pinned for `username`, if there is
+    // one, without pinning anything. Returns whether they're pinned.
+    pub fn compare(&self, username: &str, identity: &EdwardsPoint) -> Result<bool, KeyChanged> {
+        match self.get(username) {
+            Some(contact) if contact.matches(identity) => Ok(true),
+            Some(contact) => Err(KeyChanged {
+                username: username.to_string(),
+                pinned: contact
+                    .fingerprint()
+                    .unwrap_or_else(|| Fingerprint::of(identity)),
+                presented: Fingerprint::of(identity),
+            }),
+            None => Ok(false),
+        }
+    }
+
+    // Check `identity` against the key pinned for `username`, pinning it if
+    // they're new. Returns true if the book changed and should be saved.
+    pub fn check(&mut self, username: &str, identity: &EdwardsPoint) -> Result<bool, KeyChanged> {
+        if self.compare(username, identity)? {
+            return Ok(false);
+        }
+        log::debug!("pinning the key for {} on first use", username);
+        self.add(username, None, identity, Trust::Tofu);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+    use curve25519_dalek::Scalar;
+
+    fn key(n: u64) -> EdwardsPoint {
+        Scalar::from(n) * ED25519_BASEPOINT_POINT
+    }
+
+    #[test]
+    fn a_changed_key_raises_key_changed() {
+        let mut book = ContactBook::default();
+        assert!(book.check("bob", &key(1)).unwrap());
+        assert_eq!(book.get("bob").unwrap().trust, Trust::Tofu);
+        assert!(!book.check("bob", &key(1)).unwrap());
+
+        let changed = book.check("bob", &key(2)).unwrap_err();
+        assert_eq!(changed.username, "bob");
+        assert_eq!(changed.pinned, Fingerprint::of(&key(1)));
+        assert_eq!(changed.presented, Fingerprint::of(&key(2)));
+        // the pin stays as it was
+        assert_eq!(book.get("bob").unwrap().identity(), Some(key(1)));
+
+        // until the contact is re-added by hand
+        book.add("bob", None, &key(2), Trust::Unverified);
+        assert!(!book.check("bob", &key(2)).unwrap());
+        assert!(book.check("bob", &key(1)).is_err());
+    }
+
+    #[test]
+    fn compare_never_pins() {
+        let mut book = ContactBook::default();
+        assert!(!book.compare("bob", &key(1)).unwrap());
+        assert!(book.get("bob").is_none());
+
+        book.add("bob", Some("Bob"), &key(1), Trust::Verified);
+        assert!(book.compare("bob", &key(1)).unwrap());
+        assert!(book.compare("bob", &key(2)).is_err());
+    }
+
+    #[test]
+    fn pins_survive_a_reload() {
+        let mut book = ContactBook::default();
+        book.check("carol", &key(3)).unwrap();
+        book.check("bob", &key(1)).unwrap();
+
+        let bytes = bincode::serialize(&book).unwrap();
+        assert!(bytes.starts_with(&CONTACTS_MAGIC));
+        let mut book: ContactBook = bincode::deserialize(&bytes).unwrap();
+        let names: Vec<_> = book.list().iter().map(|c| c.username.as_str()).collect();
+        assert_eq!(names, ["bob", "carol"]);
+        assert!(book.check("bob", &key(2)).is_err());
+    }
+}
@@ -1,41 +1,355 @@
-use aes::Aes256;
-use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use curve25519_dalek::edwards::EdwardsPoint;
 use curve25519_dalek::montgomery::MontgomeryPoint;
 use curve25519_dalek::scalar::Scalar;
+use hkdf::Hkdf;
 use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
 use rand::RngCore;
-use sha2::Digest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
 
-type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+use super::keyring::Keyring;
+
+pub const NONCE_LEN: usize = 12;
+
+const HKDF_INFO: &[u8] = b"rune-e2ee-x25519-chacha20poly1305";
+const STANZA_INFO: &[u8] = b"rune-e2ee-multi-recipient-stanza";
+pub const STANZA_TAG_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    // the DH produced the all-zero point, i.e. a low order or malformed key
+    InvalidKey,
+    // AEAD tag check failed: wrong key, or ciphertext/associated data were modified
+    Tampered,
+    // missing or invalid signature from the claimed sender
+    BadSignature,
+    // a session message is further ahead of its chain than we're willing to skip
+    TooManySkipped,
+    // no session with the sender can decrypt the message
+    NoSession,
+    // a session handshake refers to a prekey we no longer (or never) had
+    MissingPrekey,
+    // rune-agent, which holds the keyring, couldn't do what was asked
+    Agent(String),
+    // none of a multi-recipient message's stanzas are for us
+    NotRecipient,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::InvalidKey => write!(f, "invalid ephemeral or recipient key"),
+            CryptoError::Tampered => write!(f, "message failed authentication"),
+            CryptoError::BadSignature => write!(f, "signature verification failed"),
+            CryptoError::TooManySkipped => write!(f, "too many skipped messages in session"),
+            CryptoError::NoSession => {
+                write!(f, "no session with the sender can decrypt this message")
+            }
+            CryptoError::MissingPrekey => write!(f, "session handshake uses an unknown prekey"),
+            CryptoError::Agent(e) => write!(f, "{}", e),
+            CryptoError::NotRecipient => write!(f, "message isn't addressed to this key"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+// Output of a one-shot ECIES encryption to a recipient's long-term key
+pub struct SealedMessage {
+    pub ephemeral_public: MontgomeryPoint,
+    pub nonce: [u8; NONCE_LEN],
+    pub ciphertext: Vec<u8>,
+}
+
+// Both the ephemeral key and the recipient key are bound into the AEAD as
+// associated data, so a message can't be replayed under a different key pair
+fn associated_data(ephemeral_public: &MontgomeryPoint, recipient: &MontgomeryPoint) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(64);
+    ad.extend_from_slice(ephemeral_public.as_bytes());
+    ad.extend_from_slice(recipient.as_bytes());
+    ad
+}
+
+fn derive_key(
+    shared_secret: &MontgomeryPoint,
+    associated_data: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, CryptoError> {
+    if shared_secret.as_bytes() == &[0u8; 32] {
+        return Err(CryptoError::InvalidKey);
+    }
+
+    let hk = Hkdf::<Sha256>::new(Some(associated_data), shared_secret.as_bytes());
+    let mut key = Zeroizing::new([0u8; 32]);
+    hk.expand(HKDF_INFO, &mut *key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(key)
+}
 
 pub fn encrypt_message_for(
     recipient_public: &EdwardsPoint,
     message: &[u8],
-) -> (Vec<u8>, MontgomeryPoint) {
-    let mut bytes = [0u8; 32];
-    OsRng.fill_bytes(&mut bytes);
-    let ephemeral_private = Scalar::from_bytes_mod_order(bytes);
+) -> Result<SealedMessage, CryptoError> {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut *bytes);
+    let ephemeral_private = Zeroizing::new(Scalar::from_bytes_mod_order(*bytes));
     let ephemeral_public =
-        (ephemeral_private * curve25519_dalek::constants::ED25519_BASEPOINT_POINT).to_montgomery();
+        (*ephemeral_private * curve25519_dalek::constants::ED25519_BASEPOINT_POINT).to_montgomery();
 
-    let shared_secret_point = recipient_public.to_montgomery() * ephemeral_private;
-    let shared_secret = sha2::Sha256::digest(shared_secret_point.as_bytes());
+    let recipient = recipient_public.to_montgomery();
+    let shared_secret_point = Zeroizing::new(recipient * *ephemeral_private);
+    let ad = associated_data(&ephemeral_public, &recipient);
+    let key = derive_key(&shared_secret_point, &ad)?;
 
-    let cipher = Aes256Cbc::new_from_slices(&shared_secret, &[0u8; 16]).unwrap();
-    let encrypted = cipher.encrypt_vec(message);
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
 
-    (encrypted, ephemeral_public)
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&*key));
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: message,
+                aad: &ad,
+            },
+        )
+        .expect("chacha20poly1305 encryption is infallible for in-memory buffers");
+
+    Ok(SealedMessage {
+        ephemeral_public,
+        nonce,
+        ciphertext,
+    })
 }
 
 pub fn decrypt_message_from(
-    private_key: &Scalar,
-    ciphertext: &[u8],
-    ephemeral_public: MontgomeryPoint,
-) -> Vec<u8> {
-    let shared_secret_point = ephemeral_public * private_key;
-    let shared_secret = sha2::Sha256::digest(shared_secret_point.as_bytes());
-
-    let cipher = Aes256Cbc::new_from_slices(&shared_secret, &[0u8; 16]).unwrap();
-    cipher.decrypt_vec(ciphertext).unwrap()
+    keyring: &Keyring,
+    sealed: &SealedMessage,
+) -> Result<Vec<u8>, CryptoError> {
+    let recipient = keyring.public.to_montgomery();
+    let shared_secret_point = keyring.diffie_hellman(&sealed.ephemeral_public)?;
+    let ad = associated_data(&sealed.ephemeral_public, &recipient);
+    let key = derive_key(&shared_secret_point, &ad)?;
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&*key));
+    cipher
+        .decrypt(
+            Nonce::from_slice(&sealed.nonce),
+            Payload {
+                msg: &sealed.ciphertext,
+                aad: &ad,
+            },
+        )
+        .map_err(|_| CryptoError::Tampered)
+}
+
+// Output of `encrypt_message_for_many`: the message encrypted once under a
+// random content key, and the content key wrapped once per recipient
+pub struct SealedForMany {
+    pub ephemeral_public: MontgomeryPoint,
+    pub nonce: [u8; NONCE_LEN],
+    pub stanzas: Vec<Stanza>,
+    pub ciphertext: Vec<u8>,
+}
+
+// The content key wrapped for one recipient. The tag comes out of the same
+// DH as the wrapping key, so a recipient can pick out their own stanza but
+// can't tell whose the others are.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stanza {
+    pub tag: [u8; STANZA_TAG_LEN],
+    pub wrapped_key: Vec<u8>,
+}
+
+// The tag and wrapping key of the stanza for `recipient`
+fn stanza_keys(
+    shared_secret: &MontgomeryPoint,
+    ephemeral_public: &MontgomeryPoint,
+    recipient: &MontgomeryPoint,
+) -> Result<([u8; STANZA_TAG_LEN], Zeroizing<[u8; 32]>), CryptoError> {
+    if shared_secret.as_bytes() == &[0u8; 32] {
+        return Err(CryptoError::InvalidKey);
+    }
+
+    let ad = associated_data(ephemeral_public, recipient);
+    let hk = Hkdf::<Sha256>::new(Some(&ad), shared_secret.as_bytes());
+    let mut okm = Zeroizing::new([0u8; STANZA_TAG_LEN + 32]);
+    hk.expand(STANZA_INFO, &mut *okm)
+        .expect("48 bytes is a valid HKDF-SHA256 output length");
+    let mut tag = [0u8; STANZA_TAG_LEN];
+    tag.copy_from_slice(&okm[..STANZA_TAG_LEN]);
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&okm[STANZA_TAG_LEN..]);
+    Ok((tag, key))
+}
+
+// Encrypt `message` once for all of `recipients`. One ephemeral key serves
+// every stanza, each wrapping key being bound to its recipient's key, and the
+// stanzas are shuffled so their order says nothing either.
+pub fn encrypt_message_for_many(
+    recipients: &[EdwardsPoint],
+    message: &[u8],
+) -> Result<SealedForMany, CryptoError> {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut *bytes);
+    let ephemeral_private = Zeroizing::new(Scalar::from_bytes_mod_order(*bytes));
+    let ephemeral_public =
+        (*ephemeral_private * curve25519_dalek::constants::ED25519_BASEPOINT_POINT).to_montgomery();
+
+    let mut content_key = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(&mut *content_key);
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&*content_key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: message,
+                aad: ephemeral_public.as_bytes(),
+            },
+        )
+        .expect("chacha20poly1305 encryption is infallible for in-memory buffers");
+
+    let mut stanzas = Vec::with_capacity(recipients.len());
+    for recipient_public in recipients {
+        let recipient = recipient_public.to_montgomery();
+        let shared_secret_point = Zeroizing::new(recipient * *ephemeral_private);
+        let (tag, key) = stanza_keys(&shared_secret_point, &ephemeral_public, &recipient)?;
+        // every wrapping key is used exactly once, so a fixed nonce will do
+        let wrapped_key = ChaCha20Poly1305::new(Key::from_slice(&*key))
+            .encrypt(Nonce::from_slice(&[0u8; NONCE_LEN]), &content_key[..])
+            .expect("chacha20poly1305 encryption is infallible for in-memory buffers");
+        stanzas.push(Stanza { tag, wrapped_key });
+    }
+    stanzas.shuffle(&mut OsRng);
+
+    Ok(SealedForMany {
+        ephemeral_public,
+        nonce,
+        stanzas,
+        ciphertext,
+    })
+}
+
+pub fn decrypt_message_for_many(
+    keyring: &Keyring,
+    sealed: &SealedForMany,
+) -> Result<Vec<u8>, CryptoError> {
+    let recipient = keyring.public.to_montgomery();
+    let shared_secret_point = keyring.diffie_hellman(&sealed.ephemeral_public)?;
+    let (tag, key) = stanza_keys(&shared_secret_point, &sealed.ephemeral_public, &recipient)?;
+    let stanza = sealed
+        .stanzas
+        .iter()
+        .find(|stanza| stanza.tag == tag)
+        .ok_or(CryptoError::NotRecipient)?;
+
+    let content_key = Zeroizing::new(
+        ChaCha20Poly1305::new(Key::from_slice(&*key))
+            .decrypt(
+                Nonce::from_slice(&[0u8; NONCE_LEN]),
+                stanza.wrapped_key.as_slice(),
+            )
+            .map_err(|_| CryptoError::Tampered)?,
+    );
+    if content_key.len() != 32 {
+        return Err(CryptoError::Tampered);
+    }
+    ChaCha20Poly1305::new(Key::from_slice(&content_key))
+        .decrypt(
+            Nonce::from_slice(&sealed.nonce),
+            Payload {
+                msg: &sealed.ciphertext,
+                aad: sealed.ephemeral_public.as_bytes(),
+            },
+        )
+        .map_err(|_| CryptoError::Tampered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair() -> (Keyring, EdwardsPoint) {
+        let keyring = Keyring::generate();
+        let public = keyring.public;
+        (keyring, public)
+    }
+
+    fn sealed(recipient: &EdwardsPoint) -> SealedMessage {
+        encrypt_message_for(recipient, b"attack at dawn").unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (private, public) = keypair();
+        let message = sealed(&public);
+        assert_eq!(
+            decrypt_message_from(&private, &message).unwrap(),
+            b"attack at dawn"
+        );
+        // a fresh ephemeral key and nonce every time
+        let again = sealed(&public);
+        assert_ne!(again.ephemeral_public, message.ephemeral_public);
+        assert_ne!(again.ciphertext, message.ciphertext);
+    }
+
+    #[test]
+    fn tampered_tag_or_ciphertext_is_rejected() {
+        let (private, public) = keypair();
+        // the first byte of the ciphertext, and the first of the tag
+        let len = sealed(&public).ciphertext.len();
+        for index in [0, len - 16] {
+            let mut message = sealed(&public);
+            message.ciphertext[index] ^= 1;
+            assert!(matches!(
+                decrypt_message_from(&private, &message),
+                Err(CryptoError::Tampered)
+            ));
+        }
+        let mut message = sealed(&public);
+        message.ciphertext.truncate(message.ciphertext.len() - 1);
+        assert!(decrypt_message_from(&private, &message).is_err());
+    }
+
+    #[test]
+    fn tampered_nonce_or_associated_data_is_rejected() {
+        let (private, public) = keypair();
+        let mut message = sealed(&public);
+        message.nonce[0] ^= 1;
+        assert!(matches!(
+            decrypt_message_from(&private, &message),
+            Err(CryptoError::Tampered)
+        ));
+
+        // the ephemeral key is part of the associated data, so swapping it
+        // for another message's fails even though both are for bob
+        let mut message = sealed(&public);
+        message.ephemeral_public = sealed(&public).ephemeral_public;
+        assert!(matches!(
+            decrypt_message_from(&private, &message),
+            Err(CryptoError::Tampered)
+        ));
+
+        // as is the recipient's key
+        let (mallory, _) = keypair();
+        assert!(matches!(
+            decrypt_message_from(&mallory, &sealed(&public)),
+            Err(CryptoError::Tampered)
+        ));
+    }
+
+    #[test]
+    fn low_order_ephemeral_key_is_rejected() {
+        let (private, public) = keypair();
+        let mut message = sealed(&public);
+        message.ephemeral_public = MontgomeryPoint([0u8; 32]);
+        assert!(matches!(
+            decrypt_message_from(&private, &message),
+            Err(CryptoError::InvalidKey)
+        ));
+    }
 }
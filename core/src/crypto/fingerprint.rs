@@ -0,0 +1,203 @@
+// Fingerprints and safety numbers, for checking keys out of band
+//
+// A fingerprint is SHA-256 over a context string and the compressed Edwards
+// identity key, truncated to 20 bytes. It's shown as hex in groups of four,
+// and its first 8 bytes as words from `WORDS`, which are easier to read out
+// loud.
+//
+// A safety number covers both sides of a conversation, as in Signal
+// (https://signal.org/blog/safety-number-updates/). Each party's half is
+// 5200 iterations of SHA-512 over their key and username, shown as 30
+// digits, and the halves are sorted so both sides see the same 60 digits.
+
+use curve25519_dalek::edwards::EdwardsPoint;
+use sha2::{Digest, Sha256, Sha512};
+
+const FINGERPRINT_CONTEXT: &[u8] = b"rune-fingerprint";
+const FINGERPRINT_LEN: usize = 20;
+const FINGERPRINT_WORDS: usize = 8;
+
+const SAFETY_NUMBER_VERSION: u16 = 0;
+const SAFETY_NUMBER_ITERATIONS: usize = 5200;
+
+// One word per byte value
+pub const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adobe", "agent", "aisle", "alarm", "album", "alien", "alley",
+    "amber", "ample", "angel", "ankle", "anvil", "apple", "apron", "arena", "armor", "arrow",
+    "aspen", "atlas", "attic", "audio", "autumn", "avocado", "badge", "bagel", "baker", "bamboo",
+    "banjo", "barley", "basil", "basin", "beach", "beacon", "beetle", "bell", "bench", "berry",
+    "bison", "blade", "blanket", "blimp", "bloom", "blossom", "bluff", "board", "bonus", "boot",
+    "border", "bottle", "boulder", "bowl", "bramble", "brick", "bridge", "brook", "broom",
+    "bucket", "buffalo", "bugle", "bundle", "burrow", "butter", "cabin", "cable", "cactus",
+    "camel", "candle", "canoe", "canyon", "carbon", "cargo", "carpet", "castle", "cedar", "cellar",
+    "chalk", "cherry", "chess", "chimney", "cider", "cinder", "circus", "clam", "cliff", "clock",
+    "clover", "cobalt", "cocoa", "comet", "copper", "coral", "cotton", "cougar", "crane", "crater",
+    "crayon", "cricket", "crown", "crystal", "cube", "dahlia", "daisy", "delta", "desert", "dingo",
+    "dolphin", "domino", "donkey", "dragon", "drum", "dune", "eagle", "easel", "echo", "eclipse",
+    "elbow", "elder", "ember", "emerald", "engine", "falcon", "fable", "feather", "fennel",
+    "ferry", "fiddle", "fig", "flint", "flute", "fossil", "fox", "fresco", "frost", "galaxy",
+    "garlic", "garnet", "gecko", "geyser", "ginger", "glacier", "globe", "goblet", "gopher",
+    "granite", "grape", "gravel", "gull", "hammer", "harbor", "harp", "hazel", "helmet", "heron",
+    "hickory", "honey", "hornet", "igloo", "indigo", "iris", "island", "ivory", "jacket", "jade",
+    "jaguar", "jasmine", "jelly", "jigsaw", "juniper", "kayak", "kernel", "kettle", "kiwi",
+    "koala", "ladder", "lagoon", "lantern", "lemon", "lentil", "lilac", "linen", "lizard", "llama",
+    "lobster", "locket", "lotus", "lumber", "lynx", "magnet", "mango", "maple", "marble", "meadow",
+    "melon", "meteor", "mint", "mirror", "mitten", "moose", "mosaic", "moth", "muffin", "nectar",
+    "nickel", "nomad", "nutmeg", "oasis", "oatmeal", "ocean", "olive", "onyx", "orbit", "orchid",
+    "otter", "owl", "oyster", "paddle", "panda", "papaya", "parrot", "pebble", "pepper", "piano",
+    "pickle", "pigeon", "pine", "pirate", "planet", "plum", "pony", "poppy", "potato", "prism",
+    "pumpkin", "puzzle", "quail", "quartz", "quill", "rabbit", "radar", "radish", "raven", "reef",
+    "ribbon", "river", "robin", "rocket", "saddle", "salmon", "sandal", "satin", "scarf", "shovel",
+    "silver",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint([u8; FINGERPRINT_LEN]);
+
+impl Fingerprint {
+    pub fn of(public: &EdwardsPoint) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(FINGERPRINT_CONTEXT);
+        hasher.update(public.compress().as_bytes());
+        Self(hasher.finalize()[..FINGERPRINT_LEN].try_into().unwrap())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; FINGERPRINT_LEN] {
+        &self.0
+    }
+
+    // e.g. "3F2A 9C01 ..."
+    pub fn hex(&self) -> String {
+        hex::encode_upper(self.0)
+            .as_bytes()
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn words(&self) -> String {
+        self.0[..FINGERPRINT_WORDS]
+            .iter()
+            .map(|&b| WORDS[b as usize])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.hex())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber(String);
+
+impl SafetyNumber {
+    // The same for both parties, whichever way round they're given
+    pub fn between(
+        username: &str,
+        public: &EdwardsPoint,
+        contact: &str,
+        contact_public: &EdwardsPoint,
+    ) -> Self {
+        let mut halves = [half(username, public), half(contact, contact_public)];
+        halves.sort();
+        Self(halves.concat())
+    }
+
+    pub fn digits(&self) -> &str {
+        &self.0
+    }
+}
+
+// Twelve groups of five digits, in three rows
+impl std::fmt::Display for SafetyNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let groups: Vec<&str> = self
+            .0
+            .as_bytes()
+            .chunks(5)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect();
+        let rows: Vec<String> = groups.chunks(4).map(|row| row.join(" ")).collect();
+        write!(f, "{}", rows.join("\n"))
+    }
+}
+
+fn half(username: &str, public: &EdwardsPoint) -> String {
+    let key = public.compress().to_bytes();
+
+    let mut hash = SAFETY_NUMBER_VERSION.to_be_bytes().to_vec();
+    hash.extend_from_slice(&key);
+    hash.extend_from_slice(username.as_bytes());
+    for _ in 0..SAFETY_NUMBER_ITERATIONS {
+        let mut hasher = Sha512::new();
+        hasher.update(&hash);
+        hasher.update(key);
+        hash = hasher.finalize().to_vec();
+    }
+
+    // six chunks of five bytes, five digits each
+    hash[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+    use curve25519_dalek::Scalar;
+
+    fn key(n: u64) -> EdwardsPoint {
+        Scalar::from(n) * ED25519_BASEPOINT_POINT
+    }
+
+    #[test]
+    fn fingerprints_are_stable() {
+        // changing these breaks every fingerprint users have compared
+        let fingerprint = Fingerprint::of(&key(1));
+        assert_eq!(
+            fingerprint.hex(),
+            "4623 0988 60CC 64AC 793C 3732 1A92 7897 B22E 89AF"
+        );
+        assert_eq!(
+            fingerprint.words(),
+            "canoe beacon alley galaxy crane nectar crown kernel"
+        );
+        assert_eq!(fingerprint, Fingerprint::of(&key(1)));
+        assert_ne!(fingerprint, Fingerprint::of(&key(2)));
+    }
+
+    #[test]
+    fn safety_numbers_are_symmetric() {
+        let ours = SafetyNumber::between("alice", &key(1), "bob", &key(2));
+        assert_eq!(
+            ours,
+            SafetyNumber::between("bob", &key(2), "alice", &key(1))
+        );
+        assert_eq!(ours.digits().len(), 60);
+        assert!(ours.digits().bytes().all(|b| b.is_ascii_digit()));
+        assert_eq!(
+            ours.digits(),
+            "213893586256023116548956226523606037648566493725181825585449"
+        );
+        assert_eq!(ours.to_string().lines().count(), 3);
+
+        // either key or name changing changes the number
+        assert_ne!(
+            ours,
+            SafetyNumber::between("alice", &key(1), "bob", &key(3))
+        );
+        assert_ne!(
+            ours,
+            SafetyNumber::between("alice", &key(1), "carol", &key(2))
+        );
+    }
+}
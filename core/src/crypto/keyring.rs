@@ -1,74 +1,290 @@
 use std::io::Write;
+use std::path::Path;
 
 use aes::Aes256;
 use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use curve25519_dalek::{
     edwards::{CompressedEdwardsY, EdwardsPoint},
+    montgomery::MontgomeryPoint,
     Scalar,
 };
 use hmac::Hmac;
 use pbkdf2::pbkdf2;
 use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use super::e2ee::{CryptoError, NONCE_LEN};
+use super::secret::Secret;
+use crate::agent::AgentKey;
+use crate::config::Paths;
+use crate::error::Error;
 
-#[derive(Debug)]
 pub struct Keyring {
     pub public: EdwardsPoint,
-    pub private: Scalar,
+    private: Private,
+}
+
+// Where the secret key is
+enum Private {
+    Local(Secret<Scalar>),
+    // in rune-agent, which does everything that needs it
+    Agent(AgentKey),
 }
 
 impl Keyring {
     pub fn generate() -> Keyring {
-        let mut bytes = [0u8; 32];
-        OsRng.fill_bytes(&mut bytes);
-        let private = Scalar::from_bytes_mod_order(bytes);
-        let public = private * &curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
-        Keyring { public, private }
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut *bytes);
+        Self::construct(Secret::new(Scalar::from_bytes_mod_order(*bytes)))
     }
 
-    pub fn construct(pk: EdwardsPoint, sk: Scalar) -> Keyring {
+    // The keyring for the secret key `sk`
+    pub fn construct(sk: Secret<Scalar>) -> Keyring {
+        let public = sk.expose() * curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
         Self {
-            public: pk,
-            private: sk,
+            public,
+            private: Private::Local(sk),
         }
     }
 
-    pub fn save_public_key(&self, username: &str) -> std::io::Result<()> {
-        let public_key_bytes = self.public.compress().to_bytes();
-        let path = dirs::home_dir()
-            .unwrap()
-            .join(format!(".config/rune/{}/public-key.pub", username));
+    // The keyring for `public` that rune-agent holds the secret key of
+    pub fn held_by_agent(public: EdwardsPoint, key: AgentKey) -> Keyring {
+        Self {
+            public,
+            private: Private::Agent(key),
+        }
+    }
+
+    // The secret key, unless it's held by rune-agent
+    pub fn secret(&self) -> Option<&Scalar> {
+        match &self.private {
+            Private::Local(secret) => Some(secret.expose()),
+            Private::Agent(_) => None,
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<[u8; super::sign::SIGNATURE_LEN], CryptoError> {
+        match &self.private {
+            Private::Local(secret) => Ok(super::sign::sign(secret.expose(), &self.public, message)),
+            Private::Agent(key) => key.sign(message),
+        }
+    }
+
+    // X25519 between the identity key and `point`
+    pub fn diffie_hellman(
+        &self,
+        point: &MontgomeryPoint,
+    ) -> Result<Zeroizing<MontgomeryPoint>, CryptoError> {
+        match &self.private {
+            Private::Local(secret) => Ok(Zeroizing::new(point * secret.expose())),
+            Private::Agent(key) => key.diffie_hellman(point),
+        }
+    }
+
+    // The key for local state labelled `purpose`, see `storage`
+    pub fn storage_key(&self, purpose: &[u8]) -> Result<Zeroizing<[u8; 32]>, CryptoError> {
+        match &self.private {
+            Private::Local(secret) => Ok(super::storage::derive_key(secret.expose(), purpose)),
+            Private::Agent(key) => key.storage_key(purpose),
+        }
+    }
+
+    pub fn save_public_key(&self, paths: &Paths, username: &str) -> std::io::Result<()> {
+        Self::store_public_key(paths, username, &self.public)
+    }
+
+    pub fn public_key_path(paths: &Paths, username: &str) -> std::path::PathBuf {
+        paths.user(username).join("public-key.pub")
+    }
+
+    // Record `public` as `username`'s key, as in public-key.pub
+    pub fn store_public_key(
+        paths: &Paths,
+        username: &str,
+        public: &EdwardsPoint,
+    ) -> std::io::Result<()> {
+        let public_key_bytes = public.compress().to_bytes();
+        let path = Self::public_key_path(paths, username);
 
         let mut file = std::fs::File::create(path.clone())?;
         log::debug!("writing public key to {}", path.display());
         file.write_all(&public_key_bytes)
     }
 
-    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        use crate::cmd::register::prompt_passphrase;
+    pub fn load_public_key(paths: &Paths, username: &str) -> crate::Result<EdwardsPoint> {
+        let path = Self::public_key_path(paths, username);
+        let bytes = std::fs::read(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::UnknownContact(username.to_string()),
+            _ => e.into(),
+        })?;
 
-        let me_path = dirs::home_dir().unwrap().join(".config/rune/me");
-        // read me_path
-        let username = std::fs::read_to_string(me_path)?.trim().to_string();
+        CompressedEdwardsY::from_slice(&bytes)
+            .ok()
+            .and_then(|compressed| compressed.decompress())
+            .ok_or_else(|| Error::Malformed(format!("invalid public key in {}", path.display())))
+    }
+}
 
-        let path = dirs::home_dir()
-            .unwrap()
-            .join(format!(".config/rune/{}/keyring.enc", username));
-        let decryptor =
-            KeyringEncryptor::decrypt(path.to_str().unwrap(), &prompt_passphrase().unwrap());
-        decryptor
+// Keyring files are `MAGIC || version` followed by a bincode encoded
+// `KeyringFile`, the same layout as an `Envelope`. The key for
+// ChaCha20-Poly1305 is derived from the passphrase with the KDF named in the
+// header, and the whole header is authenticated along with the keys, so a
+// wrong passphrase and a damaged file are both caught before anything is
+// parsed out of the plaintext.
+//
+// Files written before the header was introduced are `salt || iv ||
+// AES-256-CBC(pk || sk)` with a PBKDF2 key and no MAC. They can still be
+// read, see `KeyringEncryptor::is_legacy`.
+pub const KEYRING_MAGIC: [u8; 4] = *b"RKEY";
+pub const KEYRING_VERSION: u8 = 1;
+
+const LEGACY_PBKDF2_ROUNDS: u32 = 10_000;
+// salt || iv || two AES blocks of keys plus one of padding
+const LEGACY_LEN: usize = 16 + 16 + 80;
+
+#[derive(Debug)]
+pub enum KeyringError {
+    BadPassphrase,
+    UnsupportedVersion(u8),
+    UnknownKdf(u8),
+    Malformed(String),
+}
+
+// Only the public half is ever shown
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field(
+                "public",
+                &super::fingerprint::Fingerprint::of(&self.public).to_string(),
+            )
+            .field(
+                "private",
+                &match &self.private {
+                    Private::Local(secret) => format!("{:?}", secret),
+                    Private::Agent(_) => "held by rune-agent".to_string(),
+                },
+            )
+            .finish()
     }
+}
 
-    pub fn load_custom(username: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        use crate::cmd::register::prompt_passphrase;
+impl std::fmt::Display for KeyringError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyringError::BadPassphrase => write!(f, "wrong passphrase for keyring"),
+            KeyringError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported keyring version {} (expected {})",
+                v, KEYRING_VERSION
+            ),
+            KeyringError::UnknownKdf(id) => write!(f, "unknown keyring KDF {}", id),
+            KeyringError::Malformed(e) => write!(f, "malformed keyring: {}", e),
+        }
+    }
+}
 
-        let path = dirs::home_dir()
-            .unwrap()
-            .join(format!(".config/rune/{}/keyring.enc", username));
-        KeyringEncryptor::decrypt(path.to_str().unwrap(), &prompt_passphrase().unwrap())
+impl std::error::Error for KeyringError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum KdfId {
+    Argon2id = 1,
+}
+
+impl TryFrom<u8> for KdfId {
+    type Error = KeyringError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            1 => Ok(KdfId::Argon2id),
+            _ => Err(KeyringError::UnknownKdf(id)),
+        }
+    }
+}
+
+impl From<KdfId> for u8 {
+    fn from(id: KdfId) -> u8 {
+        id as u8
+    }
+}
+
+// Upper bounds on the costs, checked before Argon2 runs so a keyring file
+// can't make it allocate or spin without limit. 4 GiB, well beyond anything
+// sensible to unlock a keyring with.
+pub const MAX_M_COST: u32 = 4 * 1024 * 1024;
+pub const MAX_T_COST: u32 = 64;
+pub const MAX_P_COST: u32 = 64;
+
+// Cost parameters, stored in the header so they can be raised later without
+// breaking existing keyrings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub id: KdfId,
+    // memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            id: KdfId::Argon2id,
+            m_cost: argon2::Params::DEFAULT_M_COST,
+            t_cost: argon2::Params::DEFAULT_T_COST,
+            p_cost: argon2::Params::DEFAULT_P_COST,
+        }
     }
 }
 
+impl KdfParams {
+    // Whether the costs are ones Argon2 accepts and within the maximums
+    pub fn check(&self) -> Result<argon2::Params, KeyringError> {
+        let bad = |e: String| KeyringError::Malformed(format!("bad Argon2 parameters: {}", e));
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            return Err(bad(format!(
+                "costs m={} t={} p={} exceed the maximum m={} t={} p={}",
+                self.m_cost, self.t_cost, self.p_cost, MAX_M_COST, MAX_T_COST, MAX_P_COST
+            )));
+        }
+        argon2::Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+            .map_err(|e| bad(e.to_string()))
+    }
+
+    fn derive_key(&self, pass: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, KeyringError> {
+        match self.id {
+            KdfId::Argon2id => {
+                let params = self.check()?;
+                let mut key = Zeroizing::new([0u8; 32]);
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(pass.as_bytes(), salt, &mut *key)
+                    .map_err(|e| KeyringError::Malformed(e.to_string()))?;
+                Ok(key)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyringHeader {
+    magic: [u8; 4],
+    version: u8,
+    kdf: KdfParams,
+    salt: [u8; 16],
+    nonce: [u8; NONCE_LEN],
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyringFile {
+    header: KeyringHeader,
+    ciphertext: Vec<u8>,
+}
+
 type Aes256Cbc = Cbc<Aes256, Pkcs7>;
 
 // Can encrypt a keyring using a passphrase so users can store their keys
@@ -84,64 +300,392 @@ impl From<Keyring> for KeyringEncryptor {
 }
 
 impl KeyringEncryptor {
-    pub fn encrypt(&self, out_path: &str, pass: &str) -> Result<(), std::io::Error> {
+    pub fn path(paths: &Paths, username: &str) -> std::path::PathBuf {
+        paths.user(username).join("keyring.enc")
+    }
+
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    pub fn into_keyring(self) -> Keyring {
+        self.keyring
+    }
+
+    pub fn encrypt(&self, out_path: impl AsRef<Path>, pass: &str) -> std::io::Result<()> {
+        self.encrypt_with(out_path, pass, KdfParams::default())
+    }
+
+    pub fn encrypt_with(
+        &self,
+        out_path: impl AsRef<Path>,
+        pass: &str,
+        kdf: KdfParams,
+    ) -> std::io::Result<()> {
+        let out_path = out_path.as_ref();
+        let data = self.seal(pass, kdf)?;
+        log::debug!("writing encrypted keyring to {}", out_path.display());
+        crate::util::write_atomic(out_path, &data)
+    }
+
+    // The contents of a keyring file encrypted under `pass`
+    pub fn seal(&self, pass: &str, kdf: KdfParams) -> std::io::Result<Vec<u8>> {
+        let mut header = KeyringHeader {
+            magic: KEYRING_MAGIC,
+            version: KEYRING_VERSION,
+            kdf,
+            salt: [0u8; 16],
+            nonce: [0u8; NONCE_LEN],
+        };
+        OsRng.fill_bytes(&mut header.salt);
+        OsRng.fill_bytes(&mut header.nonce);
+
+        let key = kdf
+            .derive_key(pass, &header.salt)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let ad = bincode::serialize(&header).expect("header serialization is infallible");
+
+        let secret = self.keyring.secret().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the keyring is held by rune-agent and can't be written out",
+            )
+        })?;
+        let mut combined = Zeroizing::new(self.keyring.public.compress().to_bytes().to_vec());
+        combined.extend_from_slice(secret.as_bytes());
+
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&*key))
+            .encrypt(
+                Nonce::from_slice(&header.nonce),
+                Payload {
+                    msg: &combined,
+                    aad: &ad,
+                },
+            )
+            .expect("chacha20poly1305 encryption is infallible for in-memory buffers");
+
+        let file = KeyringFile { header, ciphertext };
+        Ok(bincode::serialize(&file).expect("keyring serialization is infallible"))
+    }
+
+    pub fn decrypt(path: impl AsRef<Path>, pass: &str) -> crate::Result<Keyring> {
+        let data = std::fs::read(path)?;
+        Ok(Self::open(&data, pass)?)
+    }
+
+    // Decrypt the contents of a keyring file, in either format
+    pub fn open(data: &[u8], pass: &str) -> Result<Keyring, KeyringError> {
+        if data.starts_with(&KEYRING_MAGIC) {
+            Self::decrypt_current(data, pass)
+        } else {
+            log::debug!("reading a legacy keyring");
+            Self::decrypt_legacy(data, pass)
+        }
+    }
+
+    // Re-encrypt the keyring at `path` under `new`, with a fresh salt and
+    // nonce. The file is replaced atomically, so it's either still readable
+    // with `old` or already with `new`. Legacy keyrings are upgraded, and the
+    // KDF cost becomes `kdf`.
+    pub fn rekey(
+        path: impl AsRef<Path>,
+        old: &str,
+        new: &str,
+        kdf: KdfParams,
+    ) -> crate::Result<()> {
+        let path = path.as_ref();
+        let keyring = Self::decrypt(path, old)?;
+        Self::from(keyring).encrypt_with(path, new, kdf)?;
+        log::debug!("changed passphrase for {}", path.display());
+        Ok(())
+    }
+
+    // Whether the keyring at `path` predates the versioned format and should
+    // be re-encrypted with `encrypt`
+    pub fn is_legacy(path: impl AsRef<Path>) -> std::io::Result<bool> {
+        Ok(!std::fs::read(path)?.starts_with(&KEYRING_MAGIC))
+    }
+
+    fn decrypt_current(data: &[u8], pass: &str) -> Result<Keyring, KeyringError> {
+        if let Some(&version) = data.get(KEYRING_MAGIC.len()) {
+            if version != KEYRING_VERSION {
+                return Err(KeyringError::UnsupportedVersion(version));
+            }
+        }
+        let file: KeyringFile =
+            bincode::deserialize(data).map_err(|e| KeyringError::Malformed(e.to_string()))?;
+
+        let key = file.header.kdf.derive_key(pass, &file.header.salt)?;
+        let ad = bincode::serialize(&file.header).expect("header serialization is infallible");
+        // the header is authenticated too, so this can't tell a wrong
+        // passphrase from a tampered file; the former is far more likely
+        let plaintext = Zeroizing::new(
+            ChaCha20Poly1305::new(Key::from_slice(&*key))
+                .decrypt(
+                    Nonce::from_slice(&file.header.nonce),
+                    Payload {
+                        msg: &file.ciphertext,
+                        aad: &ad,
+                    },
+                )
+                .map_err(|_| KeyringError::BadPassphrase)?,
+        );
+
+        keyring_from_bytes(&plaintext)
+    }
+
+    fn decrypt_legacy(data: &[u8], pass: &str) -> Result<Keyring, KeyringError> {
+        if data.len() != LEGACY_LEN {
+            return Err(KeyringError::Malformed(format!(
+                "expected {} bytes, found {}",
+                LEGACY_LEN,
+                data.len()
+            )));
+        }
+        let (salt, rest) = data.split_at(16);
+        let (iv, encrypted_data) = rest.split_at(16);
+
+        let mut key = Zeroizing::new([0u8; 32]);
+        pbkdf2::<Hmac<Sha256>>(pass.as_bytes(), salt, LEGACY_PBKDF2_ROUNDS, &mut *key)
+            .expect("HMAC accepts keys of any length");
+
+        let cipher = Aes256Cbc::new_from_slices(&*key, iv).expect("key and iv sizes are fixed");
+        // there's no MAC, so bad padding or keys that don't match each other
+        // are the only signs of a wrong passphrase
+        let decrypted = Zeroizing::new(
+            cipher
+                .decrypt_vec(encrypted_data)
+                .map_err(|_| KeyringError::BadPassphrase)?,
+        );
+        keyring_from_bytes(&decrypted).map_err(|_| KeyringError::BadPassphrase)
+    }
+}
+
+// pk || sk, checking the public key belongs to the private key
+fn keyring_from_bytes(bytes: &[u8]) -> Result<Keyring, KeyringError> {
+    let malformed = || KeyringError::Malformed("invalid key material".to_string());
+    if bytes.len() != 64 {
+        return Err(malformed());
+    }
+
+    let private_bytes: Zeroizing<[u8; 32]> =
+        Zeroizing::new(bytes[32..].try_into().map_err(|_| malformed())?);
+    let keyring = Keyring::construct(Secret::new(Scalar::from_bytes_mod_order(*private_bytes)));
+    let public = CompressedEdwardsY::from_slice(&bytes[..32])
+        .ok()
+        .and_then(|compressed| compressed.decompress())
+        .ok_or_else(malformed)?;
+    if public != keyring.public {
+        return Err(malformed());
+    }
+
+    Ok(keyring)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHEAP: KdfParams = KdfParams {
+        id: KdfId::Argon2id,
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn copy(keyring: &Keyring) -> Keyring {
+        Keyring::construct(Secret::new(*keyring.secret().unwrap()))
+    }
+
+    // `keyring` sealed under `pass` in the current format
+    fn sealed(keyring: &Keyring, pass: &str) -> Vec<u8> {
+        sealed_with(keyring, pass, CHEAP)
+    }
+
+    // A keyring file sealed under `pass` with `kdf` in its header
+    fn sealed_with(keyring: &Keyring, pass: &str, kdf: KdfParams) -> Vec<u8> {
+        let mut file: KeyringFile = bincode::deserialize(
+            &KeyringEncryptor::from(copy(keyring))
+                .seal(pass, CHEAP)
+                .unwrap(),
+        )
+        .unwrap();
+        file.header.kdf = kdf;
+        bincode::serialize(&file).unwrap()
+    }
+
+    // The same keyring in the format from before the header
+    fn legacy(keyring: &Keyring, pass: &str) -> Vec<u8> {
         let mut salt = [0u8; 16];
         let mut iv = [0u8; 16];
         OsRng.fill_bytes(&mut salt);
         OsRng.fill_bytes(&mut iv);
+        let mut key = [0u8; 32];
+        pbkdf2::<Hmac<Sha256>>(pass.as_bytes(), &salt, LEGACY_PBKDF2_ROUNDS, &mut key).unwrap();
+
+        let mut keys = keyring.public.compress().to_bytes().to_vec();
+        keys.extend_from_slice(keyring.secret().unwrap().as_bytes());
+        let encrypted = Aes256Cbc::new_from_slices(&key, &iv)
+            .unwrap()
+            .encrypt_vec(&keys);
+        [&salt[..], &iv, &encrypted].concat()
+    }
+
+    #[test]
+    fn keyrings_open_only_with_their_passphrase() {
+        let keyring = Keyring::generate();
+        let data = sealed(&keyring, "right");
 
-        // derive a key from passphrase
-        let mut derived_key = [0u8; 32];
-        let _ = pbkdf2::<Hmac<Sha256>>(pass.as_bytes(), &salt, 10_000, &mut derived_key).unwrap();
+        let opened = KeyringEncryptor::decrypt_current(&data, "right").unwrap();
+        assert_eq!(opened.public, keyring.public);
+        assert_eq!(opened.secret().unwrap(), keyring.secret().unwrap());
+        assert!(matches!(
+            KeyringEncryptor::decrypt_current(&data, "wrong"),
+            Err(KeyringError::BadPassphrase)
+        ));
 
-        let public_bytes = self.keyring.public.compress().to_bytes();
+        // the header is authenticated, so a changed salt looks the same
+        let mut file: KeyringFile = bincode::deserialize(&data).unwrap();
+        file.header.salt[0] ^= 1;
+        assert!(matches!(
+            KeyringEncryptor::decrypt_current(&bincode::serialize(&file).unwrap(), "right"),
+            Err(KeyringError::BadPassphrase)
+        ));
+    }
 
-        let private_bytes = self.keyring.private.to_bytes();
+    #[test]
+    fn unknown_versions_and_kdfs_are_rejected() {
+        let data = sealed(&Keyring::generate(), "pass");
 
-        let mut combined = Vec::new();
-        combined.extend_from_slice(&public_bytes);
-        combined.extend_from_slice(&private_bytes);
+        let mut newer = data.clone();
+        newer[KEYRING_MAGIC.len()] = KEYRING_VERSION + 1;
+        assert!(matches!(
+            KeyringEncryptor::decrypt_current(&newer, "pass"),
+            Err(KeyringError::UnsupportedVersion(v)) if v == KEYRING_VERSION + 1
+        ));
+
+        // the KDF id follows the magic and version
+        let mut unknown = data;
+        unknown[KEYRING_MAGIC.len() + 1] = 9;
+        assert!(matches!(
+            KeyringEncryptor::decrypt_current(&unknown, "pass"),
+            Err(KeyringError::Malformed(_))
+        ));
+    }
 
-        let cipher = Aes256Cbc::new_from_slices(&derived_key, &iv).unwrap();
-        let encrypted = cipher.encrypt_vec(&combined);
+    #[test]
+    fn legacy_keyrings_are_read_and_upgraded() {
+        let keyring = Keyring::generate();
+        let data = legacy(&keyring, "old");
+        assert_eq!(data.len(), LEGACY_LEN);
 
-        // move these into their own struct and serde::Serialize
-        let mut output = Vec::new();
-        output.extend_from_slice(&salt);
-        output.extend_from_slice(&iv);
-        output.extend_from_slice(&encrypted);
+        let opened = KeyringEncryptor::decrypt_legacy(&data, "old").unwrap();
+        assert_eq!(opened.public, keyring.public);
+        assert_eq!(opened.secret().unwrap(), keyring.secret().unwrap());
+        assert!(matches!(
+            KeyringEncryptor::decrypt_legacy(&data, "wrong"),
+            Err(KeyringError::BadPassphrase)
+        ));
 
-        log::debug!("writing encrypted keyring to {}", out_path);
-        let mut file = std::fs::File::create(out_path)?;
-        file.write_all(output.as_ref())
+        let path = std::env::temp_dir().join(format!("rune-legacy-{}.enc", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, &data).unwrap();
+        assert!(KeyringEncryptor::is_legacy(path).unwrap());
+
+        KeyringEncryptor::from(opened)
+            .encrypt_with(path, "new", CHEAP)
+            .unwrap();
+        assert!(!KeyringEncryptor::is_legacy(path).unwrap());
+        let upgraded = KeyringEncryptor::decrypt(path, "new").unwrap();
+        assert_eq!(upgraded.public, keyring.public);
+        assert_eq!(upgraded.secret().unwrap(), keyring.secret().unwrap());
+        assert!(KeyringEncryptor::decrypt(path, "old").is_err());
+
+        std::fs::remove_file(path).unwrap();
     }
 
-    pub fn decrypt(path: &str, pass: &str) -> Result<Keyring, Box<dyn std::error::Error>> {
-        let data = std::fs::read(path)?;
+    // A keyring sealed under "old" in a directory of its own
+    fn keyring_file(name: &str) -> (Keyring, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("rune-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let keyring = Keyring::generate();
+        let path = dir.join("keyring.enc");
+        KeyringEncryptor::from(copy(&keyring))
+            .encrypt_with(path.to_str().unwrap(), "old", CHEAP)
+            .unwrap();
+        (keyring, path)
+    }
 
-        let salt = &data[0..16];
-        let iv = &data[16..32];
-        let encrypted_data = &data[32..];
+    fn entries(path: &std::path::Path) -> usize {
+        std::fs::read_dir(path.parent().unwrap()).unwrap().count()
+    }
 
-        let mut key = [0u8; 32];
-        pbkdf2::<Hmac<Sha256>>(pass.as_bytes(), salt, 10_000, &mut key)?;
+    #[test]
+    fn rekey_keeps_the_keys() {
+        let (keyring, path) = keyring_file("rekey");
+        let sealed =
+            crate::crypto::e2ee::encrypt_message_for(&keyring.public, b"still mine").unwrap();
 
-        let cipher = Aes256Cbc::new_from_slices(&key, &iv).unwrap();
-        let decrypted = cipher.decrypt_vec(encrypted_data).map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Decryption failed")
-        })?;
+        KeyringEncryptor::rekey(path.to_str().unwrap(), "old", "new", CHEAP).unwrap();
+        let rekeyed = KeyringEncryptor::decrypt(path.to_str().unwrap(), "new").unwrap();
+        assert_eq!(rekeyed.public, keyring.public);
+        assert_eq!(
+            crate::crypto::e2ee::decrypt_message_from(&rekeyed, &sealed).unwrap(),
+            b"still mine"
+        );
+        assert!(KeyringEncryptor::decrypt(path.to_str().unwrap(), "old").is_err());
+        // the temporary file was renamed into place
+        assert_eq!(entries(&path), 1);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 
-        let public_bytes = &decrypted[0..32];
-        let private_bytes = &decrypted[32..64];
+    #[test]
+    fn failed_rekey_leaves_the_old_keyring() {
+        let (keyring, path) = keyring_file("rekey-failed");
+        let before = std::fs::read(&path).unwrap();
 
-        let private_bytes_arr: [u8; 32] = private_bytes.try_into().map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid private key")
-        })?;
+        assert!(KeyringEncryptor::rekey(path.to_str().unwrap(), "wrong", "new", CHEAP).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        // the old passphrase is right, but the new parameters are unusable
+        let broken = KdfParams { m_cost: 1, ..CHEAP };
+        assert!(KeyringEncryptor::rekey(path.to_str().unwrap(), "old", "new", broken).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        let opened = KeyringEncryptor::decrypt(path.to_str().unwrap(), "old").unwrap();
+        assert_eq!(opened.public, keyring.public);
+        assert_eq!(entries(&path), 1);
 
-        let private = Scalar::from_bytes_mod_order(private_bytes_arr);
-        let public_edwards_compressed = CompressedEdwardsY::from_slice(public_bytes).unwrap();
-        let public_edwards = public_edwards_compressed.decompress().unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 
-        Ok(Keyring::construct(public_edwards, private))
+    #[test]
+    fn costs_above_the_maximum_are_rejected_before_argon2() {
+        let keyring = Keyring::generate();
+        for kdf in [
+            KdfParams {
+                m_cost: MAX_M_COST + 1,
+                ..CHEAP
+            },
+            KdfParams {
+                t_cost: u32::MAX,
+                ..CHEAP
+            },
+            KdfParams {
+                p_cost: MAX_P_COST + 1,
+                ..CHEAP
+            },
+        ] {
+            let data = sealed_with(&keyring, "pass", kdf);
+            assert!(matches!(
+                KeyringEncryptor::open(&data, "pass"),
+                Err(KeyringError::Malformed(_))
+            ));
+            assert!(KeyringEncryptor::from(copy(&keyring))
+                .seal("pass", kdf)
+                .is_err());
+        }
+        assert!(CHEAP.check().is_ok());
+        assert!(KdfParams::default().check().is_ok());
     }
 }
@@ -1,2 +1,10 @@
 pub mod e2ee;
+pub mod fingerprint;
 pub mod keyring;
+pub mod prekey;
+pub mod ratchet;
+pub mod secret;
+pub mod sender_key;
+pub mod sign;
+pub mod storage;
+pub mod stream;
@@ -0,0 +1,443 @@
+// X3DH prekeys, following https://signal.org/docs/specifications/x3dh/
+//
+// `register` publishes a `PrekeyBundle` next to public-key.pub, and to the
+// directory if there is one: the identity key, a prekey signed by it and a
+// batch of one-time prekeys. Their secrets are kept in a `PrekeyStore`,
+// encrypted under the identity key. A sender claims a one-time prekey by
+// removing it from the published bundle (the relay does this for bundles it
+// keeps), and the recipient deletes the secret once the session it started
+// has been accepted.
+//
+// A bundle file is `BUNDLE_MAGIC || version` followed by the bincode encoded
+// bundle, the same layout as an `Envelope`.
+
+use std::path::PathBuf;
+
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::Scalar;
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use super::e2ee::CryptoError;
+use super::keyring::Keyring;
+use crate::config::Paths;
+
+pub const BUNDLE_MAGIC: [u8; 4] = *b"RPKB";
+pub const BUNDLE_VERSION: u8 = 1;
+pub const ONE_TIME_PREKEYS: u32 = 100;
+
+const SIGNATURE_CONTEXT: &[u8] = b"rune-signed-prekey";
+const STORAGE_PURPOSE: &[u8] = b"rune-prekey-storage";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedPrekey {
+    pub id: u32,
+    pub public: [u8; 32],
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OneTimePrekey {
+    pub id: u32,
+    pub public: [u8; 32],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyBundle {
+    magic: [u8; 4],
+    version: u8,
+    // compressed Edwards identity key, as in public-key.pub
+    pub identity: [u8; 32],
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+// Which of the recipient's prekeys an X3DH handshake used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyIds {
+    pub signed: u32,
+    pub one_time: Option<u32>,
+}
+
+// Initiator's result of an X3DH handshake
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct Handshake {
+    pub shared_secret: [u8; 32],
+    pub ephemeral_public: [u8; 32],
+    #[zeroize(skip)]
+    pub prekeys: PrekeyIds,
+    // the signed prekey doubles as the responder's initial ratchet key
+    pub ratchet_key: [u8; 32],
+}
+
+fn signed_prekey_message(id: u32, public: &[u8; 32]) -> Vec<u8> {
+    let mut msg = SIGNATURE_CONTEXT.to_vec();
+    msg.extend_from_slice(&id.to_le_bytes());
+    msg.extend_from_slice(public);
+    msg
+}
+
+fn generate_keypair() -> (Scalar, [u8; 32]) {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let secret = Scalar::from_bytes_mod_order(bytes);
+    let public = (secret * curve25519_dalek::constants::ED25519_BASEPOINT_POINT).to_montgomery();
+    (secret, public.to_bytes())
+}
+
+impl PrekeyBundle {
+    pub fn path(paths: &Paths, username: &str) -> PathBuf {
+        paths.user(username).join("prekey-bundle.pub")
+    }
+
+    // The bundle `username` has published, if any
+    pub fn load(paths: &Paths, username: &str) -> crate::Result<Option<Self>> {
+        let path = Self::path(paths, username);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Self::from_bytes(&std::fs::read(path)?).map(Some)
+    }
+
+    pub fn save(&self, paths: &Paths, username: &str) -> std::io::Result<()> {
+        let path = Self::path(paths, username);
+        log::debug!("publishing prekey bundle to {}", path.display());
+        crate::util::write_atomic(&path, &self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("bundle serialization is infallible")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> crate::Result<Self> {
+        if !bytes.starts_with(&BUNDLE_MAGIC) {
+            return Err(crate::Error::Malformed("not a prekey bundle".to_string()));
+        }
+        if let Some(&version) = bytes.get(BUNDLE_MAGIC.len()) {
+            if version != BUNDLE_VERSION {
+                return Err(crate::Error::Malformed(format!(
+                    "unsupported prekey bundle version {}",
+                    version
+                )));
+            }
+        }
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    // Check the bundle belongs to `identity` and its prekey was signed by it
+    pub fn verify(&self, identity: &EdwardsPoint) -> Result<(), CryptoError> {
+        if identity.compress().to_bytes() != self.identity {
+            return Err(CryptoError::BadSignature);
+        }
+        super::sign::verify(
+            identity,
+            &signed_prekey_message(self.signed_prekey.id, &self.signed_prekey.public),
+            &self.signed_prekey.signature,
+        )
+    }
+
+    // Take a one-time prekey out of the bundle; save the bundle afterwards so
+    // no one else uses it
+    pub fn claim_one_time(&mut self) -> Option<OneTimePrekey> {
+        if self.one_time_prekeys.is_empty() {
+            None
+        } else {
+            Some(self.one_time_prekeys.remove(0))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct PrekeySecret {
+    id: u32,
+    secret: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PrekeyStore {
+    signed: PrekeySecret,
+    one_time: Vec<PrekeySecret>,
+}
+
+impl PrekeyStore {
+    // A fresh signed prekey and `one_time` one-time prekeys, with the bundle to publish
+    pub fn generate(keyring: &Keyring, one_time: u32) -> Result<(Self, PrekeyBundle), CryptoError> {
+        let (signed_secret, signed_public) = generate_keypair();
+        let signed_id = OsRng.next_u32();
+        let signature = keyring.sign(&signed_prekey_message(signed_id, &signed_public))?;
+
+        let mut secrets = Vec::new();
+        let mut publics = Vec::new();
+        for id in 0..one_time {
+            let (secret, public) = generate_keypair();
+            secrets.push(PrekeySecret {
+                id,
+                secret: secret.to_bytes(),
+            });
+            publics.push(OneTimePrekey { id, public });
+        }
+
+        let store = Self {
+            signed: PrekeySecret {
+                id: signed_id,
+                secret: signed_secret.to_bytes(),
+            },
+            one_time: secrets,
+        };
+        let bundle = PrekeyBundle {
+            magic: BUNDLE_MAGIC,
+            version: BUNDLE_VERSION,
+            identity: keyring.public.compress().to_bytes(),
+            signed_prekey: SignedPrekey {
+                id: signed_id,
+                public: signed_public,
+                signature: signature.to_vec(),
+            },
+            one_time_prekeys: publics,
+        };
+        Ok((store, bundle))
+    }
+
+    pub fn path(paths: &Paths, username: &str) -> PathBuf {
+        paths.user(username).join("prekeys.enc")
+    }
+
+    // The prekey secrets for `username`, if they've published a bundle
+    pub fn load(paths: &Paths, username: &str, keyring: &Keyring) -> crate::Result<Option<Self>> {
+        let path = Self::path(paths, username);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(path)?;
+        let plaintext = super::storage::open(keyring, STORAGE_PURPOSE, username.as_bytes(), &data)?;
+        Ok(Some(bincode::deserialize(&plaintext)?))
+    }
+
+    pub fn save(&self, paths: &Paths, username: &str, keyring: &Keyring) -> crate::Result<()> {
+        let plaintext = Zeroizing::new(bincode::serialize(self)?);
+        let data = super::storage::seal(keyring, STORAGE_PURPOSE, username.as_bytes(), &plaintext)?;
+        crate::util::write_atomic(&Self::path(paths, username), &data)?;
+        Ok(())
+    }
+
+    pub fn signed_secret(&self, id: u32) -> Option<Scalar> {
+        (self.signed.id == id).then(|| Scalar::from_bytes_mod_order(self.signed.secret))
+    }
+
+    pub fn one_time_secret(&self, id: u32) -> Option<Scalar> {
+        self.one_time
+            .iter()
+            .find(|p| p.id == id)
+            .map(|p| Scalar::from_bytes_mod_order(p.secret))
+    }
+
+    pub fn remove_one_time(&mut self, id: u32) {
+        self.one_time.retain(|p| p.id != id);
+    }
+}
+
+fn kdf(dhs: &[MontgomeryPoint]) -> Result<[u8; 32], CryptoError> {
+    let mut ikm = [0xffu8; 32].to_vec();
+    for dh in dhs {
+        if dh.as_bytes() == &[0u8; 32] {
+            return Err(CryptoError::InvalidKey);
+        }
+        ikm.extend_from_slice(dh.as_bytes());
+    }
+
+    let mut secret = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm)
+        .expand(b"rune-x3dh", &mut secret)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(secret)
+}
+
+// Start a handshake with the owner of `bundle`, which must already be verified
+pub fn initiate(
+    keyring: &Keyring,
+    bundle: &PrekeyBundle,
+    one_time: Option<&OneTimePrekey>,
+) -> Result<Handshake, CryptoError> {
+    let identity = curve25519_dalek::edwards::CompressedEdwardsY(bundle.identity)
+        .decompress()
+        .ok_or(CryptoError::InvalidKey)?
+        .to_montgomery();
+    let signed = MontgomeryPoint(bundle.signed_prekey.public);
+
+    let (ephemeral, ephemeral_public) = generate_keypair();
+    let mut dhs = vec![
+        *keyring.diffie_hellman(&signed)?,
+        identity * ephemeral,
+        signed * ephemeral,
+    ];
+    if let Some(one_time) = one_time {
+        dhs.push(MontgomeryPoint(one_time.public) * ephemeral);
+    }
+
+    Ok(Handshake {
+        shared_secret: kdf(&dhs)?,
+        ephemeral_public,
+        prekeys: PrekeyIds {
+            signed: bundle.signed_prekey.id,
+            one_time: one_time.map(|p| p.id),
+        },
+        ratchet_key: bundle.signed_prekey.public,
+    })
+}
+
+// Responder's side of the handshake; returns the shared secret and the signed
+// prekey secret to start the ratchet with. The one-time prekey is left in the
+// store, remove it once the first message has been decrypted.
+pub fn respond(
+    keyring: &Keyring,
+    store: &PrekeyStore,
+    initiator: &EdwardsPoint,
+    ephemeral_public: &[u8; 32],
+    prekeys: &PrekeyIds,
+) -> Result<([u8; 32], Scalar), CryptoError> {
+    let signed = store
+        .signed_secret(prekeys.signed)
+        .ok_or(CryptoError::MissingPrekey)?;
+    let ephemeral = MontgomeryPoint(*ephemeral_public);
+
+    let mut dhs = vec![
+        initiator.to_montgomery() * signed,
+        *keyring.diffie_hellman(&ephemeral)?,
+        ephemeral * signed,
+    ];
+    if let Some(id) = prekeys.one_time {
+        let one_time = store
+            .one_time_secret(id)
+            .ok_or(CryptoError::MissingPrekey)?;
+        dhs.push(ephemeral * one_time);
+    }
+
+    Ok((kdf(&dhs)?, signed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionStore;
+
+    #[test]
+    fn x3dh_round_trip() {
+        let (alice, bob) = (Keyring::generate(), Keyring::generate());
+        let (store, mut bundle) = PrekeyStore::generate(&bob, 2).unwrap();
+        bundle.verify(&bob.public).unwrap();
+
+        // with a one-time prekey, and once they've run out without one
+        for _ in 0..3 {
+            let one_time = bundle.claim_one_time();
+            let handshake = initiate(&alice, &bundle, one_time.as_ref()).unwrap();
+            assert_eq!(handshake.ratchet_key, bundle.signed_prekey.public);
+            assert_eq!(handshake.prekeys.one_time, one_time.map(|p| p.id));
+
+            let (shared_secret, ratchet_secret) = respond(
+                &bob,
+                &store,
+                &alice.public,
+                &handshake.ephemeral_public,
+                &handshake.prekeys,
+            )
+            .unwrap();
+            assert_eq!(shared_secret, handshake.shared_secret);
+            let ratchet_public = (ratchet_secret
+                * curve25519_dalek::constants::ED25519_BASEPOINT_POINT)
+                .to_montgomery();
+            assert_eq!(ratchet_public.to_bytes(), handshake.ratchet_key);
+
+            // someone else claiming to be alice gets another secret
+            let mallory = Keyring::generate();
+            let (other, _) = respond(
+                &bob,
+                &store,
+                &mallory.public,
+                &handshake.ephemeral_public,
+                &handshake.prekeys,
+            )
+            .unwrap();
+            assert_ne!(other, handshake.shared_secret);
+        }
+        assert!(bundle.one_time_prekeys.is_empty());
+    }
+
+    #[test]
+    fn bundles_are_checked_against_the_identity_key() {
+        let (bob, mallory) = (Keyring::generate(), Keyring::generate());
+        let (_, bundle) = PrekeyStore::generate(&bob, 1).unwrap();
+        assert!(matches!(
+            bundle.verify(&mallory.public),
+            Err(CryptoError::BadSignature)
+        ));
+
+        let mut swapped = bundle.clone();
+        swapped.signed_prekey.public = generate_keypair().1;
+        assert!(matches!(
+            swapped.verify(&bob.public),
+            Err(CryptoError::BadSignature)
+        ));
+        let mut forged = bundle.clone();
+        forged.signed_prekey.signature[0] ^= 1;
+        assert!(matches!(
+            forged.verify(&bob.public),
+            Err(CryptoError::BadSignature)
+        ));
+
+        let bytes = bundle.to_bytes();
+        assert!(PrekeyBundle::from_bytes(&bytes).is_ok());
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 1;
+        assert!(PrekeyBundle::from_bytes(&bad_magic).is_err());
+        let mut bad_version = bytes;
+        bad_version[BUNDLE_MAGIC.len()] = BUNDLE_VERSION + 1;
+        assert!(PrekeyBundle::from_bytes(&bad_version).is_err());
+    }
+
+    #[test]
+    fn one_time_prekeys_are_used_once() {
+        let (alice, bob) = (Keyring::generate(), Keyring::generate());
+        let (mut store, mut bundle) = PrekeyStore::generate(&bob, 1).unwrap();
+
+        let id = bundle.one_time_prekeys[0].id;
+        let mut alices = SessionStore::default();
+        alices
+            .initiate(&alice, &bob.public, Some(&mut bundle), false)
+            .unwrap();
+        assert!(bundle.one_time_prekeys.is_empty());
+        let first = alices.encrypt(b"hello bob").unwrap();
+        assert!(store.one_time_secret(id).is_some());
+
+        let mut bobs = SessionStore::default();
+        let plaintext = bobs
+            .decrypt(&bob, Some(&mut store), &alice.public, &first)
+            .unwrap();
+        assert_eq!(plaintext, b"hello bob");
+        assert!(store.one_time_secret(id).is_none());
+
+        // replaying the handshake to a store that no longer has the session
+        // finds the one-time prekey gone
+        let mut fresh = SessionStore::default();
+        assert!(matches!(
+            fresh.decrypt(&bob, Some(&mut store), &alice.public, &first),
+            Err(CryptoError::MissingPrekey)
+        ));
+        assert!(matches!(
+            respond(
+                &bob,
+                &store,
+                &alice.public,
+                &generate_keypair().1,
+                &PrekeyIds {
+                    signed: bundle.signed_prekey.id,
+                    one_time: Some(id),
+                }
+            ),
+            Err(CryptoError::MissingPrekey)
+        ));
+    }
+}
@@ -0,0 +1,629 @@
+// Double Ratchet, following https://signal.org/docs/specifications/doubleratchet/
+//
+// DH ratchet keys are X25519 in the same representation the rest of the crate
+// uses: a scalar mod l and the Montgomery form of its Edwards public point.
+// That lets a responder start from its identity key before ratcheting away
+// from it. With header encryption enabled (section 4 of the spec) the
+// ratchet public key and counters are hidden from anyone but the peer.
+
+use std::collections::VecDeque;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::Scalar;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use super::e2ee::{CryptoError, NONCE_LEN};
+
+// Most message keys we'll derive ahead of time within a single chain
+pub const MAX_SKIP: u32 = 1000;
+// Most skipped message keys we'll hold on to across all chains, oldest evicted first
+const MAX_STORED_SKIPPED: usize = 2000;
+
+type ChainKey = [u8; 32];
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct KeyPair {
+    secret: [u8; 32],
+    public: [u8; 32],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        Self::from_scalar(&Scalar::from_bytes_mod_order(bytes))
+    }
+
+    fn from_scalar(secret: &Scalar) -> Self {
+        let public =
+            (secret * curve25519_dalek::constants::ED25519_BASEPOINT_POINT).to_montgomery();
+        Self {
+            secret: secret.to_bytes(),
+            public: public.to_bytes(),
+        }
+    }
+
+    fn dh(&self, remote: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+        let shared = MontgomeryPoint(*remote) * Scalar::from_bytes_mod_order(self.secret);
+        if shared.as_bytes() == &[0u8; 32] {
+            return Err(CryptoError::InvalidKey);
+        }
+        Ok(shared.to_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub dh: [u8; 32],
+    pub pn: u32,
+    pub n: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct HeaderKeys {
+    send: Option<[u8; 32]>,
+    recv: Option<[u8; 32]>,
+    next_send: [u8; 32],
+    next_recv: [u8; 32],
+}
+
+// A message key derived while skipping ahead. `tag` is the remote ratchet
+// key the chain belongs to, or the chain's header key with header encryption.
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct Skipped {
+    tag: [u8; 32],
+    n: u32,
+    key: [u8; 32],
+}
+
+pub struct RatchetMessage {
+    pub header: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct Ratchet {
+    dh_self: KeyPair,
+    dh_remote: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    send_chain: Option<ChainKey>,
+    recv_chain: Option<ChainKey>,
+    n_send: u32,
+    n_recv: u32,
+    pn: u32,
+    header_keys: Option<HeaderKeys>,
+    // each wipes itself when dropped
+    #[zeroize(skip)]
+    skipped: VecDeque<Skipped>,
+}
+
+impl Ratchet {
+    // The side that starts the session, knowing the responder's initial ratchet key
+    pub fn initiator(
+        shared_secret: &[u8; 32],
+        remote_public: &[u8; 32],
+        header_encryption: bool,
+    ) -> Result<Self, CryptoError> {
+        let dh_self = KeyPair::generate();
+        let (root_key, send_chain, next_send) = kdf_rk(shared_secret, &dh_self.dh(remote_public)?);
+
+        let header_keys = header_encryption.then(|| {
+            let (shared_send, shared_next_recv) = initial_header_keys(shared_secret);
+            HeaderKeys {
+                send: Some(shared_send),
+                recv: None,
+                next_send,
+                next_recv: shared_next_recv,
+            }
+        });
+
+        Ok(Self {
+            dh_self,
+            dh_remote: Some(*remote_public),
+            root_key,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            n_send: 0,
+            n_recv: 0,
+            pn: 0,
+            header_keys,
+            skipped: VecDeque::new(),
+        })
+    }
+
+    // The side that receives the first message, holding the initial ratchet key
+    pub fn responder(
+        shared_secret: &[u8; 32],
+        own_secret: &Scalar,
+        header_encryption: bool,
+    ) -> Self {
+        let header_keys = header_encryption.then(|| {
+            let (shared_recv, shared_next_send) = initial_header_keys(shared_secret);
+            HeaderKeys {
+                send: None,
+                recv: None,
+                next_send: shared_next_send,
+                next_recv: shared_recv,
+            }
+        });
+
+        Self {
+            dh_self: KeyPair::from_scalar(own_secret),
+            dh_remote: None,
+            root_key: *shared_secret,
+            send_chain: None,
+            recv_chain: None,
+            n_send: 0,
+            n_recv: 0,
+            pn: 0,
+            header_keys,
+            skipped: VecDeque::new(),
+        }
+    }
+
+    pub fn header_encryption(&self) -> bool {
+        self.header_keys.is_some()
+    }
+
+    pub fn encrypt(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<RatchetMessage, CryptoError> {
+        // a responder can't send until the first message has been received
+        let send_chain = self.send_chain.ok_or(CryptoError::InvalidKey)?;
+        let (next_chain, message_key) = kdf_ck(&send_chain);
+
+        let header = Header {
+            dh: self.dh_self.public,
+            pn: self.pn,
+            n: self.n_send,
+        };
+        let header_bytes = bincode::serialize(&header).expect("header serialization is infallible");
+        let header = match &self.header_keys {
+            Some(keys) => {
+                let key = keys.send.ok_or(CryptoError::InvalidKey)?;
+                encrypt_header(&key, &header_bytes)
+            }
+            None => header_bytes,
+        };
+
+        let ciphertext = encrypt_with(&message_key, plaintext, &concat(associated_data, &header));
+
+        self.send_chain = Some(next_chain);
+        self.n_send += 1;
+
+        Ok(RatchetMessage { header, ciphertext })
+    }
+
+    // State is only updated if the message authenticates, so a failed attempt
+    // (a forgery, or a message meant for another session) leaves it untouched
+    pub fn decrypt(
+        &mut self,
+        message: &RatchetMessage,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(message, associated_data)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn decrypt_in_place(
+        &mut self,
+        message: &RatchetMessage,
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let ad = concat(associated_data, &message.header);
+
+        if let Some(plaintext) = self.try_skipped(message, &ad) {
+            return Ok(plaintext);
+        }
+
+        let (header, new_chain) = self.read_header(&message.header)?;
+        if new_chain {
+            self.skip_message_keys(header.pn)?;
+            self.dh_ratchet(&header)?;
+        }
+        self.skip_message_keys(header.n)?;
+
+        let recv_chain = self.recv_chain.ok_or(CryptoError::Tampered)?;
+        let (next_chain, message_key) = kdf_ck(&recv_chain);
+        let plaintext = decrypt_with(&message_key, &message.ciphertext, &ad)?;
+
+        self.recv_chain = Some(next_chain);
+        self.n_recv += 1;
+        Ok(plaintext)
+    }
+
+    // Returns the header and whether it starts a new receiving chain
+    fn read_header(&self, header: &[u8]) -> Result<(Header, bool), CryptoError> {
+        match &self.header_keys {
+            None => {
+                let header: Header =
+                    bincode::deserialize(header).map_err(|_| CryptoError::Tampered)?;
+                let new_chain = self.dh_remote != Some(header.dh);
+                Ok((header, new_chain))
+            }
+            Some(keys) => {
+                if let Some(header) = keys.recv.and_then(|key| decrypt_header(&key, header)) {
+                    return Ok((header, false));
+                }
+                decrypt_header(&keys.next_recv, header)
+                    .map(|header| (header, true))
+                    .ok_or(CryptoError::Tampered)
+            }
+        }
+    }
+
+    fn try_skipped(&mut self, message: &RatchetMessage, ad: &[u8]) -> Option<Vec<u8>> {
+        let position = match &self.header_keys {
+            None => {
+                let header: Header = bincode::deserialize(&message.header).ok()?;
+                self.skipped
+                    .iter()
+                    .position(|s| s.tag == header.dh && s.n == header.n)
+            }
+            Some(_) => self.skipped.iter().position(|s| {
+                decrypt_header(&s.tag, &message.header).is_some_and(|header| header.n == s.n)
+            }),
+        }?;
+
+        let plaintext = decrypt_with(&self.skipped[position].key, &message.ciphertext, ad).ok()?;
+        self.skipped.remove(position);
+        Some(plaintext)
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), CryptoError> {
+        let Some(mut chain) = self.recv_chain else {
+            return Ok(());
+        };
+        if until > self.n_recv.saturating_add(MAX_SKIP) {
+            return Err(CryptoError::TooManySkipped);
+        }
+
+        let tag = match &self.header_keys {
+            Some(keys) => keys.recv,
+            None => self.dh_remote,
+        }
+        .ok_or(CryptoError::Tampered)?;
+
+        while self.n_recv < until {
+            let (next_chain, key) = kdf_ck(&chain);
+            self.skipped.push_back(Skipped {
+                tag,
+                n: self.n_recv,
+                key,
+            });
+            if self.skipped.len() > MAX_STORED_SKIPPED {
+                self.skipped.pop_front();
+            }
+            chain = next_chain;
+            self.n_recv += 1;
+        }
+        self.recv_chain = Some(chain);
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &Header) -> Result<(), CryptoError> {
+        self.pn = self.n_send;
+        self.n_send = 0;
+        self.n_recv = 0;
+        self.dh_remote = Some(header.dh);
+
+        let (root_key, recv_chain, next_recv) =
+            kdf_rk(&self.root_key, &self.dh_self.dh(&header.dh)?);
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+
+        self.dh_self = KeyPair::generate();
+        let (root_key, send_chain, next_send) =
+            kdf_rk(&self.root_key, &self.dh_self.dh(&header.dh)?);
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+
+        if let Some(keys) = &mut self.header_keys {
+            keys.send = Some(keys.next_send);
+            keys.recv = Some(keys.next_recv);
+            keys.next_send = next_send;
+            keys.next_recv = next_recv;
+        }
+        Ok(())
+    }
+}
+
+fn concat(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    out.extend_from_slice(a);
+    out.extend_from_slice(b);
+    out
+}
+
+// Returns (root key, chain key, next header key)
+fn kdf_rk(root_key: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], ChainKey, [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(Some(root_key), dh_out);
+    let mut okm = [0u8; 96];
+    hk.expand(b"rune-ratchet-root", &mut okm)
+        .expect("96 bytes is a valid HKDF-SHA256 output length");
+
+    let mut out = ([0u8; 32], [0u8; 32], [0u8; 32]);
+    out.0.copy_from_slice(&okm[..32]);
+    out.1.copy_from_slice(&okm[32..64]);
+    out.2.copy_from_slice(&okm[64..]);
+    out
+}
+
+// Returns (next chain key, message key)
+pub(super) fn kdf_ck(chain_key: &ChainKey) -> (ChainKey, [u8; 32]) {
+    let derive = |constant: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&[constant]);
+        mac.finalize().into_bytes().into()
+    };
+    (derive(0x02), derive(0x01))
+}
+
+// Header keys both sides start from when header encryption is on
+fn initial_header_keys(shared_secret: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut okm = [0u8; 64];
+    hk.expand(b"rune-ratchet-header-keys", &mut okm)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+
+    let mut out = ([0u8; 32], [0u8; 32]);
+    out.0.copy_from_slice(&okm[..32]);
+    out.1.copy_from_slice(&okm[32..]);
+    out
+}
+
+// Message keys are single use, so the nonce can be derived alongside the key
+pub(super) fn encrypt_with(message_key: &[u8; 32], plaintext: &[u8], ad: &[u8]) -> Vec<u8> {
+    let (key, nonce) = expand_message_key(message_key);
+    ChaCha20Poly1305::new(&key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: ad,
+            },
+        )
+        .expect("chacha20poly1305 encryption is infallible for in-memory buffers")
+}
+
+pub(super) fn decrypt_with(
+    message_key: &[u8; 32],
+    ciphertext: &[u8],
+    ad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let (key, nonce) = expand_message_key(message_key);
+    ChaCha20Poly1305::new(&key)
+        .decrypt(
+            &nonce,
+            Payload {
+                msg: ciphertext,
+                aad: ad,
+            },
+        )
+        .map_err(|_| CryptoError::Tampered)
+}
+
+fn expand_message_key(message_key: &[u8; 32]) -> (Key, Nonce) {
+    let hk = Hkdf::<Sha256>::new(None, message_key);
+    let mut okm = [0u8; 32 + NONCE_LEN];
+    hk.expand(b"rune-ratchet-message", &mut okm)
+        .expect("44 bytes is a valid HKDF-SHA256 output length");
+    (*Key::from_slice(&okm[..32]), *Nonce::from_slice(&okm[32..]))
+}
+
+// Header keys are reused across a whole chain, so headers get a random nonce
+fn encrypt_header(header_key: &[u8; 32], header: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut out = nonce.to_vec();
+    out.extend(
+        ChaCha20Poly1305::new(Key::from_slice(header_key))
+            .encrypt(Nonce::from_slice(&nonce), header)
+            .expect("chacha20poly1305 encryption is infallible for in-memory buffers"),
+    );
+    out
+}
+
+fn decrypt_header(header_key: &[u8; 32], encrypted: &[u8]) -> Option<Header> {
+    if encrypted.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+    let header = ChaCha20Poly1305::new(Key::from_slice(header_key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .ok()?;
+    bincode::deserialize(&header).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: &[u8] = b"alice and bob";
+
+    // Alice starts, knowing Bob's initial ratchet key
+    fn pair(header_encryption: bool) -> (Ratchet, Ratchet) {
+        let mut shared_secret = [0u8; 32];
+        OsRng.fill_bytes(&mut shared_secret);
+        let bob_key = KeyPair::generate();
+        let bob_secret = Scalar::from_bytes_mod_order(bob_key.secret);
+        let alice = Ratchet::initiator(&shared_secret, &bob_key.public, header_encryption).unwrap();
+        let bob = Ratchet::responder(&shared_secret, &bob_secret, header_encryption);
+        (alice, bob)
+    }
+
+    fn send(from: &mut Ratchet, text: &str) -> RatchetMessage {
+        from.encrypt(text.as_bytes(), AD).unwrap()
+    }
+
+    fn receive(to: &mut Ratchet, message: &RatchetMessage) -> Result<String, CryptoError> {
+        to.decrypt(message, AD)
+            .map(|plaintext| String::from_utf8(plaintext).unwrap())
+    }
+
+    fn state(ratchet: &Ratchet) -> Vec<u8> {
+        bincode::serialize(ratchet).unwrap()
+    }
+
+    #[test]
+    fn in_order_conversation() {
+        for header_encryption in [false, true] {
+            let (mut alice, mut bob) = pair(header_encryption);
+            // a responder has nothing to send with until it hears from the initiator
+            assert!(bob.encrypt(b"too soon", AD).is_err());
+
+            for round in 0..3 {
+                for i in 0..3 {
+                    let text = format!("alice {} {}", round, i);
+                    assert_eq!(receive(&mut bob, &send(&mut alice, &text)).unwrap(), text);
+                }
+                let text = format!("bob {}", round);
+                assert_eq!(receive(&mut alice, &send(&mut bob, &text)).unwrap(), text);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_order_across_dh_steps() {
+        for header_encryption in [false, true] {
+            let (mut alice, mut bob) = pair(header_encryption);
+            let a0 = send(&mut alice, "a0");
+            let a1 = send(&mut alice, "a1");
+            assert_eq!(receive(&mut bob, &a1).unwrap(), "a1");
+            assert_eq!(bob.skipped.len(), 1);
+
+            // Bob's reply moves Alice on to a new chain before a0 turns up
+            let b0 = send(&mut bob, "b0");
+            assert_eq!(receive(&mut alice, &b0).unwrap(), "b0");
+            let a2 = send(&mut alice, "a2");
+            assert_eq!(receive(&mut bob, &a2).unwrap(), "a2");
+            assert_eq!(receive(&mut bob, &a0).unwrap(), "a0");
+            assert!(bob.skipped.is_empty());
+        }
+    }
+
+    #[test]
+    fn skipped_keys_are_stored_up_to_max_skip() {
+        for header_encryption in [false, true] {
+            let (mut alice, mut bob) = pair(header_encryption);
+            let messages: Vec<_> = (0..=MAX_SKIP + 1)
+                .map(|i| send(&mut alice, &i.to_string()))
+                .collect();
+
+            // the last is one further ahead than we'll skip
+            let before = state(&bob);
+            assert!(matches!(
+                receive(&mut bob, &messages[MAX_SKIP as usize + 1]),
+                Err(CryptoError::TooManySkipped)
+            ));
+            assert_eq!(state(&bob), before);
+
+            // exactly MAX_SKIP ahead is fine, and keeps every key on the way
+            let last = MAX_SKIP as usize;
+            assert_eq!(
+                receive(&mut bob, &messages[last]).unwrap(),
+                last.to_string()
+            );
+            assert_eq!(bob.skipped.len(), last);
+            assert_eq!(receive(&mut bob, &messages[7]).unwrap(), "7");
+            assert_eq!(bob.skipped.len(), last - 1);
+            assert_eq!(
+                receive(&mut bob, &messages[last + 1]).unwrap(),
+                (last + 1).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn header_keys_switch_with_each_dh_step() {
+        let (mut alice, mut bob) = pair(true);
+        let first = send(&mut alice, "first");
+        receive(&mut bob, &first).unwrap();
+        let bob_keys = bob.header_keys.clone().unwrap();
+        assert_eq!(bob_keys.recv, alice.header_keys.as_ref().unwrap().send);
+
+        // headers don't show the ratchet key, and only the peer can read them
+        let reply = send(&mut bob, "reply");
+        assert!(!reply
+            .header
+            .windows(32)
+            .any(|window| window == bob.dh_self.public));
+        let bob_send = bob_keys.send.unwrap();
+        assert!(decrypt_header(&bob_send, &reply.header).is_some());
+        receive(&mut alice, &reply).unwrap();
+
+        // Alice's reply starts a new chain under the next header key, which
+        // the key from before can't read
+        let next = send(&mut alice, "next");
+        let alice_send = alice.header_keys.as_ref().unwrap().send.unwrap();
+        assert_eq!(alice_send, bob_keys.next_recv);
+        assert_ne!(Some(alice_send), bob_keys.recv);
+        assert!(decrypt_header(&bob_keys.recv.unwrap(), &next.header).is_none());
+        assert_eq!(receive(&mut bob, &next).unwrap(), "next");
+        assert_eq!(bob.header_keys.as_ref().unwrap().recv, Some(alice_send));
+    }
+
+    #[test]
+    fn tampering_is_rejected_and_leaves_state_alone() {
+        for header_encryption in [false, true] {
+            let (mut alice, mut bob) = pair(header_encryption);
+            receive(&mut bob, &send(&mut alice, "hello")).unwrap();
+            let message = send(&mut alice, "again");
+            let before = state(&bob);
+
+            let mut body = RatchetMessage {
+                header: message.header.clone(),
+                ciphertext: message.ciphertext.clone(),
+            };
+            body.ciphertext[0] ^= 1;
+            assert!(receive(&mut bob, &body).is_err());
+            assert_eq!(state(&bob), before);
+
+            let mut header = RatchetMessage {
+                header: message.header.clone(),
+                ciphertext: message.ciphertext.clone(),
+            };
+            let last = header.header.len() - 1;
+            header.header[last] ^= 1;
+            assert!(receive(&mut bob, &header).is_err());
+            assert_eq!(state(&bob), before);
+
+            // nor is a message with other associated data taken
+            assert!(bob.decrypt(&message, b"someone else").is_err());
+            assert_eq!(state(&bob), before);
+
+            assert_eq!(receive(&mut bob, &message).unwrap(), "again");
+        }
+    }
+
+    #[test]
+    fn replays_are_rejected() {
+        for header_encryption in [false, true] {
+            let (mut alice, mut bob) = pair(header_encryption);
+            let early = send(&mut alice, "early");
+            let late = send(&mut alice, "late");
+            receive(&mut bob, &late).unwrap();
+            receive(&mut bob, &early).unwrap();
+
+            // both in order and from the skipped keys, a key is only used once
+            for message in [&late, &early] {
+                let before = state(&bob);
+                assert!(receive(&mut bob, message).is_err());
+                assert_eq!(state(&bob), before);
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// A home for secret key material
+//
+// `Secret` keeps its value on the heap, so moving it around doesn't leave
+// copies behind, locks that memory so it isn't swapped out where the OS
+// allows it (mlock on unix, best effort), wipes it on drop and never shows it
+// in `Debug` output. Short-lived buffers holding keys, passphrases or
+// plaintexts use `zeroize::Zeroizing` instead.
+
+use zeroize::Zeroize;
+
+pub struct Secret<T: Zeroize>(Box<T>);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        let secret = Self(Box::new(value));
+        lock(&*secret.0);
+        secret
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Secret<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.expose().clone())
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock(&*self.0);
+    }
+}
+
+impl<T: Zeroize> std::fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+// mlock and munlock work on whole pages, and small secrets often share one,
+// so a page stays locked until the last secret on it is dropped. Pages are
+// counted whether or not mlock succeeded, since munlock is harmless either
+// way.
+#[cfg(unix)]
+static LOCKED_PAGES: std::sync::Mutex<std::collections::BTreeMap<usize, usize>> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+// The start of each page `value` lies on
+#[cfg(unix)]
+fn pages<T>(value: &T) -> impl Iterator<Item = usize> {
+    let page_size = page_size();
+    let start = value as *const T as usize;
+    let end = start + std::mem::size_of::<T>();
+    (start / page_size * page_size..end).step_by(page_size)
+}
+
+#[cfg(unix)]
+fn lock<T>(value: &T) {
+    let page_size = page_size();
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    for page in pages(value) {
+        let count = locked.entry(page).or_insert(0);
+        // SAFETY: the page holds part of `value`, which is live, so it's mapped
+        if *count == 0 && unsafe { libc::mlock(page as *const libc::c_void, page_size) } != 0 {
+            log::debug!("mlock failed: {}", std::io::Error::last_os_error());
+        }
+        *count += 1;
+    }
+}
+
+#[cfg(unix)]
+fn unlock<T>(value: &T) {
+    let page_size = page_size();
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    for page in pages(value) {
+        let Some(count) = locked.get_mut(&page) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            // SAFETY: as in `lock`; unlocking memory that wasn't locked is
+            // harmless
+            unsafe { libc::munlock(page as *const libc::c_void, page_size) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock<T>(_value: &T) {}
+
+#[cfg(not(unix))]
+fn unlock<T>(_value: &T) {}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn count(page: usize) -> usize {
+        LOCKED_PAGES
+            .lock()
+            .unwrap()
+            .get(&page)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn pages_stay_locked_while_any_secret_on_them_lives() {
+        let mut secrets: Vec<Secret<[u8; 32]>> = (0..64).map(|n| Secret::new([n; 32])).collect();
+        let first = secrets.remove(0);
+        let page = pages(first.expose()).next().unwrap();
+        // small allocations made together land on the same page
+        let sharing = secrets
+            .iter()
+            .filter(|secret| pages(secret.expose()).any(|p| p == page))
+            .count();
+        assert!(sharing > 0);
+
+        drop(first);
+        assert!(count(page) >= sharing);
+        drop(secrets);
+    }
+}
@@ -0,0 +1,117 @@
+// Sender keys for group messages, as in Signal's groups
+//
+// Each member of a group has a symmetric chain of their own, stepped with the
+// same KDF as a Double Ratchet sending chain, and hands a copy of it to the
+// rest of the group over their pairwise sessions. A group message is then
+// encrypted once, with the sender's next message key, however many members
+// it goes to. Whoever has a copy can read from the point it was handed over,
+// not before, and nobody can read anything once the chain has been replaced.
+
+use std::collections::VecDeque;
+
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use super::e2ee::CryptoError;
+use super::ratchet::{decrypt_with, encrypt_with, kdf_ck, MAX_SKIP};
+
+// Most skipped message keys we'll hold on to for one sender
+const MAX_STORED_SKIPPED: usize = MAX_SKIP as usize;
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct Skipped {
+    iteration: u32,
+    key: [u8; 32],
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct SenderKey {
+    chain_key: [u8; 32],
+    // the iteration `chain_key` produces the message key for
+    iteration: u32,
+    // each wipes itself when dropped
+    #[zeroize(skip)]
+    skipped: VecDeque<Skipped>,
+}
+
+impl SenderKey {
+    pub fn generate() -> Self {
+        let mut chain_key = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+        Self {
+            chain_key,
+            iteration: 0,
+            skipped: VecDeque::new(),
+        }
+    }
+
+    pub fn iteration(&self) -> u32 {
+        self.iteration
+    }
+
+    // A copy to hand to another member, which reads from here on
+    pub fn share(&self) -> Self {
+        Self {
+            chain_key: self.chain_key,
+            iteration: self.iteration,
+            skipped: VecDeque::new(),
+        }
+    }
+
+    // Encrypt with the next message key, returning its iteration
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<(u32, Vec<u8>), CryptoError> {
+        let iteration = self.iteration;
+        let next = iteration
+            .checked_add(1)
+            .ok_or(CryptoError::TooManySkipped)?;
+        let (chain_key, message_key) = kdf_ck(&self.chain_key);
+        self.chain_key = chain_key;
+        self.iteration = next;
+        Ok((iteration, encrypt_with(&message_key, plaintext, ad)))
+    }
+
+    // Decrypt a message encrypted at `iteration` of the sender's chain. Keys
+    // skipped on the way are kept for messages that arrive out of order. The
+    // chain is only moved on if the message decrypts.
+    pub fn decrypt(
+        &mut self,
+        iteration: u32,
+        ciphertext: &[u8],
+        ad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if iteration < self.iteration {
+            let position = self
+                .skipped
+                .iter()
+                .position(|s| s.iteration == iteration)
+                .ok_or(CryptoError::Tampered)?;
+            let plaintext = decrypt_with(&self.skipped[position].key, ciphertext, ad)?;
+            self.skipped.remove(position);
+            return Ok(plaintext);
+        }
+        if iteration - self.iteration > MAX_SKIP {
+            return Err(CryptoError::TooManySkipped);
+        }
+
+        let mut next = self.clone();
+        while next.iteration < iteration {
+            let (chain_key, key) = kdf_ck(&next.chain_key);
+            next.skipped.push_back(Skipped {
+                iteration: next.iteration,
+                key,
+            });
+            if next.skipped.len() > MAX_STORED_SKIPPED {
+                next.skipped.pop_front();
+            }
+            next.chain_key = chain_key;
+            next.iteration += 1;
+        }
+        let (chain_key, message_key) = kdf_ck(&next.chain_key);
+        let plaintext = decrypt_with(&message_key, ciphertext, ad)?;
+        next.chain_key = chain_key;
+        next.iteration += 1;
+        *self = next;
+        Ok(plaintext)
+    }
+}
@@ -0,0 +1,39 @@
+// Ed25519 signatures with the identity key held in a `Keyring`, see
+// `Keyring::sign`
+//
+// The keyring stores a bare scalar rather than an Ed25519 seed, so signing
+// goes through ed25519-dalek's hazmat API. Signatures are standard Ed25519 and
+// verify against the compressed point written to `public-key.pub`.
+
+use curve25519_dalek::edwards::EdwardsPoint;
+use ed25519_dalek::hazmat::{raw_sign, ExpandedSecretKey};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+use curve25519_dalek::Scalar;
+
+use super::e2ee::CryptoError;
+
+pub const SIGNATURE_LEN: usize = 64;
+
+pub fn sign(secret: &Scalar, public: &EdwardsPoint, message: &[u8]) -> [u8; SIGNATURE_LEN] {
+    // deterministic nonce prefix derived from the secret scalar, as in RFC 8032
+    let mut hasher = Sha512::new();
+    hasher.update(b"rune-ed25519-nonce-prefix");
+    hasher.update(secret.as_bytes());
+    let mut hash_prefix = [0u8; 32];
+    hash_prefix.copy_from_slice(&hasher.finalize()[..32]);
+
+    let esk = ExpandedSecretKey {
+        scalar: *secret,
+        hash_prefix,
+    };
+    raw_sign::<Sha512>(&esk, message, &VerifyingKey::from(*public)).to_bytes()
+}
+
+pub fn verify(public: &EdwardsPoint, message: &[u8], signature: &[u8]) -> Result<(), CryptoError> {
+    let signature = Signature::from_slice(signature).map_err(|_| CryptoError::BadSignature)?;
+    VerifyingKey::from(*public)
+        .verify_strict(message, &signature)
+        .map_err(|_| CryptoError::BadSignature)
+}
@@ -0,0 +1,71 @@
+// Encryption for local state that only the owner of a keyring needs to read,
+// such as sessions and prekey secrets. Each kind of state gets its own key,
+// derived from the identity key and a `purpose` label.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::Scalar;
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use super::e2ee::{CryptoError, NONCE_LEN};
+use super::keyring::Keyring;
+
+// Used through `Keyring::storage_key`, which rune-agent can answer too
+pub fn derive_key(secret: &Scalar, purpose: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, secret.as_bytes())
+        .expand(purpose, &mut *key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+// nonce || ChaCha20-Poly1305(plaintext), with `ad` bound to the ciphertext
+pub fn seal(
+    keyring: &Keyring,
+    purpose: &[u8],
+    ad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&*keyring.storage_key(purpose)?))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: ad,
+            },
+        )
+        .expect("chacha20poly1305 encryption is infallible for in-memory buffers");
+
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+pub fn open(
+    keyring: &Keyring,
+    purpose: &[u8],
+    ad: &[u8],
+    data: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    if data.len() < NONCE_LEN {
+        return Err(CryptoError::Tampered);
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    ChaCha20Poly1305::new(Key::from_slice(&*keyring.storage_key(purpose)?))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: ad,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| CryptoError::Tampered)
+}
@@ -0,0 +1,120 @@
+// Chunked encryption for data too large to hold in memory, such as files
+//
+// This is the STREAM construction (Hoang, Reyhanitabar, Rogaway and Vizár)
+// with ChaCha20-Poly1305, as age uses it: the plaintext is cut into
+// CHUNK_LEN chunks and each is sealed on its own, with a nonce made of a
+// 32-bit chunk counter and a flag set only on the last chunk. Chunks can't be
+// reordered or dropped, and the stream can't be cut short, without
+// decryption failing. Every stream must have a key of its own, so the rest of
+// the nonce is left zero. Only a couple of chunks are in memory at a time.
+
+use std::io::{Read, Write};
+
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::{ChaCha20Poly1305, Key};
+use zeroize::Zeroizing;
+
+use super::e2ee::CryptoError;
+use crate::error::Error;
+
+pub const CHUNK_LEN: usize = 64 * 1024;
+// a chunk and its Poly1305 tag
+pub const SEALED_CHUNK_LEN: usize = CHUNK_LEN + 16;
+
+const NONCE_PREFIX: [u8; 7] = [0u8; 7];
+
+// 2^32 chunks, some 256 TiB, is as far as the counter goes
+fn too_long() -> Error {
+    Error::Invalid("too much data for one encrypted stream".to_string())
+}
+
+// Fill `buf` from `input`, short only at the end of the input
+fn read_full(input: &mut dyn Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// How long `encrypt` makes `len` bytes of plaintext: every chunk gains a
+// tag, and even empty input is one (empty) chunk. None past the 2^32 chunks
+// one stream can hold.
+pub fn sealed_len(len: u64) -> Option<u64> {
+    let chunks = len.div_ceil(CHUNK_LEN as u64).max(1);
+    if chunks > 1 << 32 {
+        return None;
+    }
+    len.checked_add(chunks * (SEALED_CHUNK_LEN - CHUNK_LEN) as u64)
+}
+
+// Encrypt everything in `input` under `key` into `output`
+pub fn encrypt(key: &[u8; 32], input: &mut dyn Read, output: &mut dyn Write) -> crate::Result<()> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut encryptor = EncryptorBE32::from_aead(cipher, GenericArray::from_slice(&NONCE_PREFIX));
+    // which chunk is the last only shows once the next one comes up empty
+    let mut chunk = Zeroizing::new(vec![0u8; CHUNK_LEN]);
+    let mut next = Zeroizing::new(vec![0u8; CHUNK_LEN]);
+    let mut len = read_full(input, &mut chunk)?;
+    loop {
+        let next_len = if len == CHUNK_LEN {
+            read_full(input, &mut next)?
+        } else {
+            0
+        };
+        if next_len == 0 {
+            let sealed = encryptor
+                .encrypt_last(&chunk[..len])
+                .map_err(|_| too_long())?;
+            output.write_all(&sealed)?;
+            return Ok(());
+        }
+        let sealed = encryptor
+            .encrypt_next(&chunk[..len])
+            .map_err(|_| too_long())?;
+        output.write_all(&sealed)?;
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+    }
+}
+
+// Decrypt a stream from `encrypt` into `output`. Plaintext is written as it's
+// authenticated, so on error `output` holds a prefix that should be thrown away.
+pub fn decrypt(key: &[u8; 32], input: &mut dyn Read, output: &mut dyn Write) -> crate::Result<()> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let mut decryptor = DecryptorBE32::from_aead(cipher, GenericArray::from_slice(&NONCE_PREFIX));
+    let mut chunk = vec![0u8; SEALED_CHUNK_LEN];
+    let mut next = vec![0u8; SEALED_CHUNK_LEN];
+    let mut len = read_full(input, &mut chunk)?;
+    loop {
+        let next_len = if len == SEALED_CHUNK_LEN {
+            read_full(input, &mut next)?
+        } else {
+            0
+        };
+        if next_len == 0 {
+            let plaintext = Zeroizing::new(
+                decryptor
+                    .decrypt_last(&chunk[..len])
+                    .map_err(|_| CryptoError::Tampered)?,
+            );
+            output.write_all(&plaintext)?;
+            return Ok(());
+        }
+        let plaintext = Zeroizing::new(
+            decryptor
+                .decrypt_next(&chunk[..len])
+                .map_err(|_| CryptoError::Tampered)?,
+        );
+        output.write_all(&plaintext)?;
+        std::mem::swap(&mut chunk, &mut next);
+        len = next_len;
+    }
+}
@@ -0,0 +1,223 @@
+// Username directory kept by a rune-server relay
+//
+// Registering claims a username for an identity key. The relay hands out a
+// random challenge per connection, and the registration is only accepted if
+// it's signed by the identity key being registered, so nobody can claim a
+// name for a key they don't hold. A username can't be taken by a different
+// key once it's registered; registering the same key again is a no-op.
+// Signing a challenge with the registered key is also how a connection proves
+// it's that user's before fetching their messages or publishing prekeys.
+//
+// The relay keeps each user's prekey bundle too, and hands out every one-time
+// prekey in it only once, so senders who have never met can start sessions
+// with X3DH (see `crate::crypto::prekey`).
+
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+
+use crate::config::Paths;
+use crate::contacts::ContactBook;
+use crate::crypto::e2ee::CryptoError;
+use crate::crypto::keyring::Keyring;
+use crate::crypto::prekey::PrekeyBundle;
+use crate::crypto::sign;
+use crate::relay::{unexpected, RelayClient, Request, Response};
+
+const REGISTRATION_CONTEXT: &[u8] = b"rune-directory-registration";
+const AUTHENTICATION_CONTEXT: &[u8] = b"rune-relay-authentication";
+
+fn registration_message(username: &str, identity: &[u8; 32], challenge: &[u8; 32]) -> Vec<u8> {
+    let mut msg = REGISTRATION_CONTEXT.to_vec();
+    msg.extend_from_slice(challenge);
+    msg.extend_from_slice(identity);
+    msg.extend_from_slice(username.as_bytes());
+    msg
+}
+
+// Server side check that `signature` proves ownership of `identity`
+pub fn verify_registration(
+    username: &str,
+    identity: &[u8; 32],
+    challenge: &[u8; 32],
+    signature: &[u8],
+) -> Result<(), CryptoError> {
+    let public = decompress(identity)?;
+    sign::verify(
+        &public,
+        &registration_message(username, identity, challenge),
+        signature,
+    )
+}
+
+fn authentication_message(username: &str, challenge: &[u8; 32]) -> Vec<u8> {
+    let mut msg = AUTHENTICATION_CONTEXT.to_vec();
+    msg.extend_from_slice(challenge);
+    msg.extend_from_slice(username.as_bytes());
+    msg
+}
+
+// Server side check that `signature` proves the connection is `username`'s,
+// whose registered key is `identity`
+pub fn verify_authentication(
+    username: &str,
+    identity: &[u8; 32],
+    challenge: &[u8; 32],
+    signature: &[u8],
+) -> Result<(), CryptoError> {
+    let public = decompress(identity)?;
+    sign::verify(
+        &public,
+        &authentication_message(username, challenge),
+        signature,
+    )
+}
+
+// Server side check that `bundle` parses and was signed by `identity`
+pub fn verify_prekeys(identity: &[u8; 32], bundle: &[u8]) -> crate::Result<PrekeyBundle> {
+    let bundle = PrekeyBundle::from_bytes(bundle)?;
+    bundle.verify(&decompress(identity)?)?;
+    Ok(bundle)
+}
+
+fn decompress(identity: &[u8; 32]) -> Result<EdwardsPoint, CryptoError> {
+    CompressedEdwardsY(*identity)
+        .decompress()
+        .ok_or(CryptoError::InvalidKey)
+}
+
+impl RelayClient {
+    fn challenge(&mut self) -> std::io::Result<[u8; 32]> {
+        match self.request(&Request::Challenge)? {
+            Response::Challenge { nonce } => Ok(nonce),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Claim `username` for the identity key in `keyring`
+    pub fn register(&mut self, username: &str, keyring: &Keyring) -> std::io::Result<()> {
+        let nonce = self.challenge()?;
+        let identity = keyring.public.compress().to_bytes();
+        let signature = keyring
+            .sign(&registration_message(username, &identity, &nonce))
+            .map_err(std::io::Error::other)?;
+        match self.request(&Request::Register {
+            username: username.to_string(),
+            identity,
+            signature: signature.to_vec(),
+        })? {
+            Response::Registered => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Prove this connection is `username`'s, so it can fetch and acknowledge
+    // their messages. `keyring` must hold the key they registered.
+    pub fn authenticate(&mut self, username: &str, keyring: &Keyring) -> std::io::Result<()> {
+        let nonce = self.challenge()?;
+        let signature = keyring
+            .sign(&authentication_message(username, &nonce))
+            .map_err(std::io::Error::other)?;
+        match self.request(&Request::Authenticate {
+            username: username.to_string(),
+            signature: signature.to_vec(),
+        })? {
+            Response::Authenticated => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn lookup(&mut self, username: &str) -> std::io::Result<EdwardsPoint> {
+        match self.request(&Request::Lookup {
+            username: username.to_string(),
+        })? {
+            Response::Identity { identity } => decompress(&identity).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} has an invalid identity key: {}", username, e),
+                )
+            }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Publish `bundle` as `username`'s, once authenticated as them
+    pub fn publish_prekeys(
+        &mut self,
+        username: &str,
+        bundle: &PrekeyBundle,
+    ) -> std::io::Result<()> {
+        match self.request(&Request::PublishPrekeys {
+            username: username.to_string(),
+            bundle: bundle.to_bytes(),
+        })? {
+            Response::Published => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    // Claim one of `username`'s one-time prekeys, along with the rest of
+    // their bundle. The caller still has to verify it.
+    pub fn claim_prekeys(&mut self, username: &str) -> std::io::Result<Option<PrekeyBundle>> {
+        match self.request(&Request::ClaimPrekeys {
+            username: username.to_string(),
+        })? {
+            Response::Prekeys { bundle: None } => Ok(None),
+            Response::Prekeys {
+                bundle: Some(bundle),
+            } => PrekeyBundle::from_bytes(&bundle).map(Some).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} has an invalid prekey bundle: {}", username, e),
+                )
+            }),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+// `username`'s identity key, from the directory on `server` if one is given,
+// otherwise from their public-key.pub
+pub fn resolve_identity(
+    paths: &Paths,
+    username: &str,
+    server: Option<&str>,
+) -> crate::Result<EdwardsPoint> {
+    match server {
+        Some(server) => {
+            log::debug!("looking up {} in the directory on {}", username, server);
+            Ok(RelayClient::connect(server)?.lookup(username)?)
+        }
+        None => Keyring::load_public_key(paths, username),
+    }
+}
+
+// The key to use for `username`: the one pinned in `contacts`, otherwise
+// `published_identity`
+pub fn known_identity(
+    paths: &Paths,
+    contacts: &ContactBook,
+    username: &str,
+    server: Option<&str>,
+) -> crate::Result<EdwardsPoint> {
+    match contacts
+        .get(username)
+        .and_then(|contact| contact.identity())
+    {
+        Some(pinned) => Ok(pinned),
+        None => published_identity(paths, username, server),
+    }
+}
+
+// `username`'s public-key.pub on this machine, otherwise the key the
+// directory on `server` has for them
+pub fn published_identity(
+    paths: &Paths,
+    username: &str,
+    server: Option<&str>,
+) -> crate::Result<EdwardsPoint> {
+    match Keyring::load_public_key(paths, username) {
+        Err(crate::Error::UnknownContact(_)) if server.is_some() => {
+            resolve_identity(paths, username, server)
+        }
+        result => result,
+    }
+}
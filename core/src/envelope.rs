@@ -0,0 +1,538 @@
+// Wire format for encrypted messages
+//
+// An envelope is bincode encoded, but its first bytes are always the fixed
+// size header `MAGIC || version || suite`, so a decoder can reject a file it
+// doesn't understand before attempting to parse the rest of it.
+//
+// What gets encrypted is a bincode encoded `Payload`, which says what the
+// message holds. The content type is encrypted along with the content, so
+// only the recipients learn it.
+
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::crypto::e2ee::{self, CryptoError, SealedForMany, SealedMessage, Stanza, NONCE_LEN};
+use crate::crypto::keyring::Keyring;
+use crate::crypto::sign;
+
+pub const MAGIC: [u8; 4] = *b"RUNE";
+pub const VERSION: u8 = 1;
+
+const SIGNATURE_CONTEXT: &[u8] = b"rune-envelope-signature";
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownCipherSuite(u8),
+    UnknownContentType(u8),
+    Malformed(bincode::Error),
+}
+
+impl std::fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvelopeError::BadMagic => write!(f, "not a rune envelope"),
+            EnvelopeError::UnsupportedVersion(v) => {
+                write!(
+                    f,
+                    "unsupported envelope version {} (expected {})",
+                    v, VERSION
+                )
+            }
+            EnvelopeError::UnknownCipherSuite(id) => write!(f, "unknown cipher suite {}", id),
+            EnvelopeError::UnknownContentType(id) => write!(f, "unknown content type {}", id),
+            EnvelopeError::Malformed(e) => write!(f, "malformed envelope: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+#[repr(u8)]
+pub enum CipherSuite {
+    // ephemeral X25519 to the recipient's identity key, HKDF-SHA256, ChaCha20-Poly1305
+    X25519ChaCha20Poly1305 = 1,
+    // a message in a Double Ratchet session, see `session`
+    DoubleRatchet = 2,
+    // a piece of an attachment's ciphertext, see `attachment`
+    FileChunk = 3,
+    // like X25519ChaCha20Poly1305, but one message for several recipients
+    // with a stanza each in the header, see `e2ee::encrypt_message_for_many`
+    MultiRecipient = 4,
+    // a message to a group under the sender's sender key, see `group`
+    Group = 5,
+}
+
+impl TryFrom<u8> for CipherSuite {
+    type Error = EnvelopeError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            1 => Ok(CipherSuite::X25519ChaCha20Poly1305),
+            2 => Ok(CipherSuite::DoubleRatchet),
+            3 => Ok(CipherSuite::FileChunk),
+            4 => Ok(CipherSuite::MultiRecipient),
+            5 => Ok(CipherSuite::Group),
+            _ => Err(EnvelopeError::UnknownCipherSuite(id)),
+        }
+    }
+}
+
+impl From<CipherSuite> for u8 {
+    fn from(suite: CipherSuite) -> u8 {
+        suite as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+#[repr(u8)]
+pub enum ContentType {
+    // UTF-8 text
+    Text = 1,
+    // a file's name, size, hash and key, see `attachment`
+    Attachment = 2,
+    // a `group::Control`, only taken over a pairwise session
+    GroupControl = 3,
+}
+
+impl TryFrom<u8> for ContentType {
+    type Error = EnvelopeError;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            1 => Ok(ContentType::Text),
+            2 => Ok(ContentType::Attachment),
+            3 => Ok(ContentType::GroupControl),
+            _ => Err(EnvelopeError::UnknownContentType(id)),
+        }
+    }
+}
+
+impl From<ContentType> for u8 {
+    fn from(content_type: ContentType) -> u8 {
+        content_type as u8
+    }
+}
+
+// The plaintext of a message
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Payload<'a> {
+    pub content_type: ContentType,
+    pub body: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    pub fn new(content_type: ContentType, body: &'a [u8]) -> Self {
+        Self { content_type, body }
+    }
+
+    pub fn text(message: &'a str) -> Self {
+        Self::new(ContentType::Text, message.as_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(bincode::serialize(self).expect("payload serialization is infallible"))
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, EnvelopeError> {
+        bincode::deserialize(bytes).map_err(EnvelopeError::Malformed)
+    }
+}
+
+// Who sent the message, and their Ed25519 signature over the rest of the envelope
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sender {
+    pub username: String,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    magic: [u8; 4],
+    version: u8,
+    pub suite: CipherSuite,
+    // unused (zero) for session messages, whose keys travel in `header`
+    pub ephemeral_public: [u8; 32],
+    pub nonce: [u8; NONCE_LEN],
+    // suite specific header, empty for one-shot messages
+    pub header: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub sender: Option<Sender>,
+}
+
+impl Envelope {
+    // Encrypt an anonymous envelope; call `sign` before handing it to anyone
+    pub fn seal(recipient_public: &EdwardsPoint, message: &[u8]) -> Result<Self, CryptoError> {
+        let sealed = e2ee::encrypt_message_for(recipient_public, message)?;
+
+        Ok(Self {
+            magic: MAGIC,
+            version: VERSION,
+            suite: CipherSuite::X25519ChaCha20Poly1305,
+            ephemeral_public: sealed.ephemeral_public.to_bytes(),
+            nonce: sealed.nonce,
+            header: Vec::new(),
+            ciphertext: sealed.ciphertext,
+            sender: None,
+        })
+    }
+
+    // Encrypt one anonymous envelope for all of `recipients`, as (username,
+    // identity key) pairs. With `hide_recipients` none of them learn who else
+    // it went to; the stanzas never say. Call `sign` before handing it out.
+    pub fn seal_for_many(
+        recipients: &[(&str, EdwardsPoint)],
+        message: &[u8],
+        hide_recipients: bool,
+    ) -> Result<Self, CryptoError> {
+        // the plaintext is (to, message), `to` being empty when hidden
+        let to: Vec<&str> = match hide_recipients {
+            true => Vec::new(),
+            false => recipients.iter().map(|(name, _)| *name).collect(),
+        };
+        let plaintext = Zeroizing::new(
+            bincode::serialize(&(to, message)).expect("envelope serialization is infallible"),
+        );
+        let keys: Vec<EdwardsPoint> = recipients.iter().map(|(_, key)| *key).collect();
+        let sealed = e2ee::encrypt_message_for_many(&keys, &plaintext)?;
+
+        Ok(Self {
+            magic: MAGIC,
+            version: VERSION,
+            suite: CipherSuite::MultiRecipient,
+            ephemeral_public: sealed.ephemeral_public.to_bytes(),
+            nonce: sealed.nonce,
+            header: bincode::serialize(&sealed.stanzas)
+                .expect("envelope serialization is infallible"),
+            ciphertext: sealed.ciphertext,
+            sender: None,
+        })
+    }
+
+    // Wrap a message produced by a `SessionStore`
+    pub fn session(header: Vec<u8>, ciphertext: Vec<u8>) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            suite: CipherSuite::DoubleRatchet,
+            ephemeral_public: [0u8; 32],
+            nonce: [0u8; NONCE_LEN],
+            header,
+            ciphertext,
+            sender: None,
+        }
+    }
+
+    // Wrap part of an attachment, which is already encrypted and isn't signed
+    pub fn file_part(header: Vec<u8>, ciphertext: Vec<u8>) -> Self {
+        Self {
+            suite: CipherSuite::FileChunk,
+            ..Self::session(header, ciphertext)
+        }
+    }
+
+    // Wrap a message produced by `group::GroupCache::encrypt`
+    pub fn group(header: Vec<u8>, ciphertext: Vec<u8>) -> Self {
+        Self {
+            suite: CipherSuite::Group,
+            ..Self::session(header, ciphertext)
+        }
+    }
+
+    pub fn sign(&mut self, username: &str, keyring: &Keyring) -> Result<(), CryptoError> {
+        let signature = keyring.sign(&self.signed_bytes(username))?;
+        self.sender = Some(Sender {
+            username: username.to_string(),
+            signature: signature.to_vec(),
+        });
+        Ok(())
+    }
+
+    // Check that the envelope was signed by `sender_public`. Unsigned
+    // envelopes are rejected just like forged ones.
+    pub fn verify(&self, sender_public: &EdwardsPoint) -> Result<&str, CryptoError> {
+        let sender = self.sender.as_ref().ok_or(CryptoError::BadSignature)?;
+        sign::verify(
+            sender_public,
+            &self.signed_bytes(&sender.username),
+            &sender.signature,
+        )?;
+        Ok(&sender.username)
+    }
+
+    // Everything but the signature itself, prefixed with a context string so
+    // the signature can't be confused with one made for another purpose
+    fn signed_bytes(&self, username: &str) -> Vec<u8> {
+        let mut bytes = SIGNATURE_CONTEXT.to_vec();
+        bincode::serialize_into(
+            &mut bytes,
+            &(
+                &self.magic,
+                self.version,
+                self.suite,
+                &self.ephemeral_public,
+                &self.nonce,
+                &self.header,
+                &self.ciphertext,
+                username,
+            ),
+        )
+        .expect("envelope serialization is infallible");
+        bytes
+    }
+
+    // Decrypt a one-shot message; session messages go through `SessionStore::decrypt`
+    pub fn open(&self, keyring: &Keyring) -> Result<Vec<u8>, CryptoError> {
+        match self.suite {
+            CipherSuite::X25519ChaCha20Poly1305 => {
+                let sealed = SealedMessage {
+                    ephemeral_public: MontgomeryPoint(self.ephemeral_public),
+                    nonce: self.nonce,
+                    ciphertext: self.ciphertext.clone(),
+                };
+                e2ee::decrypt_message_from(keyring, &sealed)
+            }
+            CipherSuite::MultiRecipient => self.open_for_many(keyring).map(|(_, message)| message),
+            CipherSuite::DoubleRatchet | CipherSuite::FileChunk | CipherSuite::Group => {
+                Err(CryptoError::NoSession)
+            }
+        }
+    }
+
+    // Decrypt a multi-recipient message, returning who it was sent to (empty
+    // if they were hidden) along with the message
+    pub fn open_for_many(&self, keyring: &Keyring) -> Result<(Vec<String>, Vec<u8>), CryptoError> {
+        if self.suite != CipherSuite::MultiRecipient {
+            return Err(CryptoError::NoSession);
+        }
+        let stanzas: Vec<Stanza> =
+            bincode::deserialize(&self.header).map_err(|_| CryptoError::Tampered)?;
+        let sealed = SealedForMany {
+            ephemeral_public: MontgomeryPoint(self.ephemeral_public),
+            nonce: self.nonce,
+            stanzas,
+            ciphertext: self.ciphertext.clone(),
+        };
+        let plaintext = Zeroizing::new(e2ee::decrypt_message_for_many(keyring, &sealed)?);
+        bincode::deserialize(&plaintext).map_err(|_| CryptoError::Tampered)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("envelope serialization is infallible")
+    }
+
+    // The suite and header from the start of an envelope, without needing
+    // the rest of it
+    pub fn peek(bytes: &[u8]) -> Result<(CipherSuite, Vec<u8>), EnvelopeError> {
+        #[derive(Deserialize)]
+        struct Prefix {
+            magic: [u8; 4],
+            version: u8,
+            suite: CipherSuite,
+            _ephemeral_public: [u8; 32],
+            _nonce: [u8; NONCE_LEN],
+            header: Vec<u8>,
+        }
+
+        let prefix: Prefix = bincode::deserialize(bytes).map_err(EnvelopeError::Malformed)?;
+        if prefix.magic != MAGIC {
+            return Err(EnvelopeError::BadMagic);
+        }
+        if prefix.version != VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(prefix.version));
+        }
+        Ok((prefix.suite, prefix.header))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        if !bytes.starts_with(&MAGIC) {
+            return Err(EnvelopeError::BadMagic);
+        }
+
+        // a truncated header falls through to bincode, which reports it as malformed
+        if let Some(&version) = bytes.get(MAGIC.len()) {
+            if version != VERSION {
+                return Err(EnvelopeError::UnsupportedVersion(version));
+            }
+        }
+        if let Some(&suite) = bytes.get(MAGIC.len() + 1) {
+            CipherSuite::try_from(suite)?;
+        }
+
+        bincode::deserialize(bytes).map_err(EnvelopeError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope_bytes() -> (Keyring, Vec<u8>) {
+        let bob = Keyring::generate();
+        let envelope = Envelope::seal(&bob.public, b"hello bob").unwrap();
+        (bob, envelope.to_bytes())
+    }
+
+    #[test]
+    fn round_trip() {
+        let (bob, bytes) = envelope_bytes();
+        assert_eq!(&bytes[..4], b"RUNE");
+        assert_eq!(bytes[4], VERSION);
+        assert_eq!(bytes[5], CipherSuite::X25519ChaCha20Poly1305 as u8);
+
+        let envelope = Envelope::from_bytes(&bytes).unwrap();
+        assert_eq!(envelope.version(), VERSION);
+        assert_eq!(envelope.open(&bob).unwrap(), b"hello bob");
+    }
+
+    #[test]
+    fn payloads_carry_their_content_type() {
+        let bytes = Payload::new(ContentType::Attachment, b"details").to_bytes();
+        let payload = Payload::from_bytes(&bytes).unwrap();
+        assert_eq!(payload.content_type, ContentType::Attachment);
+        assert_eq!(payload.body, b"details");
+
+        let mut unknown = bytes.clone();
+        unknown[0] = 9;
+        assert!(Payload::from_bytes(&unknown).is_err());
+        assert!(Payload::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let (_, mut bytes) = envelope_bytes();
+        bytes[0] = b'X';
+        assert!(matches!(
+            Envelope::from_bytes(&bytes),
+            Err(EnvelopeError::BadMagic)
+        ));
+        assert!(matches!(
+            Envelope::from_bytes(b""),
+            Err(EnvelopeError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let (_, mut bytes) = envelope_bytes();
+        for version in [0, VERSION + 1, u8::MAX] {
+            bytes[4] = version;
+            assert!(matches!(
+                Envelope::from_bytes(&bytes),
+                Err(EnvelopeError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_suite_is_rejected() {
+        let (_, mut bytes) = envelope_bytes();
+        for suite in [0, 6, u8::MAX] {
+            bytes[5] = suite;
+            assert!(matches!(
+                Envelope::from_bytes(&bytes),
+                Err(EnvelopeError::UnknownCipherSuite(id)) if id == suite
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_envelopes_are_malformed() {
+        let (_, bytes) = envelope_bytes();
+        for len in [5, 6, 40, bytes.len() - 1] {
+            assert!(matches!(
+                Envelope::from_bytes(&bytes[..len]),
+                Err(EnvelopeError::Malformed(_))
+            ));
+        }
+    }
+
+    fn signed(alice: &Keyring) -> Envelope {
+        let bob = Keyring::generate();
+        let mut envelope = Envelope::seal(&bob.public, b"hello bob").unwrap();
+        envelope.sign("alice", alice).unwrap();
+        envelope
+    }
+
+    #[test]
+    fn signatures_verify_against_the_sender() {
+        let alice = Keyring::generate();
+        let envelope = signed(&alice);
+        assert_eq!(envelope.verify(&alice.public).unwrap(), "alice");
+        // and survive the trip through bytes
+        let envelope = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+        assert_eq!(envelope.verify(&alice.public).unwrap(), "alice");
+    }
+
+    #[test]
+    fn unsigned_envelopes_are_rejected() {
+        let alice = Keyring::generate();
+        let mut envelope = signed(&alice);
+        envelope.sender = None;
+        assert!(matches!(
+            envelope.verify(&alice.public),
+            Err(CryptoError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let (alice, mallory) = (Keyring::generate(), Keyring::generate());
+        assert!(matches!(
+            signed(&mallory).verify(&alice.public),
+            Err(CryptoError::BadSignature)
+        ));
+        assert!(matches!(
+            signed(&alice).verify(&mallory.public),
+            Err(CryptoError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn forged_signatures_are_rejected() {
+        let alice = Keyring::generate();
+        let verify = |envelope: &Envelope| {
+            matches!(
+                envelope.verify(&alice.public),
+                Err(CryptoError::BadSignature)
+            )
+        };
+
+        let mut envelope = signed(&alice);
+        envelope.sender.as_mut().unwrap().signature[0] ^= 1;
+        assert!(verify(&envelope));
+        let mut envelope = signed(&alice);
+        envelope.sender.as_mut().unwrap().signature = vec![0u8; 64];
+        assert!(verify(&envelope));
+        let mut envelope = signed(&alice);
+        envelope.sender.as_mut().unwrap().signature.truncate(32);
+        assert!(verify(&envelope));
+
+        // everything but the signature is covered, the username included
+        let mut envelope = signed(&alice);
+        envelope.sender.as_mut().unwrap().username = "carol".to_string();
+        assert!(verify(&envelope));
+        let mut envelope = signed(&alice);
+        envelope.ciphertext[0] ^= 1;
+        assert!(verify(&envelope));
+        let mut envelope = signed(&alice);
+        envelope.ephemeral_public[0] ^= 1;
+        assert!(verify(&envelope));
+        let mut envelope = signed(&alice);
+        envelope.nonce[0] ^= 1;
+        assert!(verify(&envelope));
+        let mut envelope = signed(&alice);
+        envelope.version ^= 1;
+        assert!(verify(&envelope));
+    }
+}
@@ -0,0 +1,138 @@
+// Errors returned by rune-core
+//
+// The lower level error types (`CryptoError`, `KeyringError`, `ArmorError`,
+// `EnvelopeError`) are kept for the code that produces them and converted
+// into an `Error` with `?`. Frontends match on `Error` to decide what to tell
+// the user, see `main.rs` for how the CLI maps them to exit codes.
+
+use crate::armor::ArmorError;
+use crate::contacts::KeyChanged;
+use crate::crypto::e2ee::CryptoError;
+use crate::crypto::keyring::KeyringError;
+use crate::envelope::EnvelopeError;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Crypto(CryptoError),
+    // the passphrase doesn't decrypt the keyring
+    BadPassphrase,
+    // a keyring that can't be read for reasons other than the passphrase
+    Keyring(KeyringError),
+    Armor(ArmorError),
+    MalformedEnvelope(EnvelopeError),
+    // stored state (sessions, prekeys, contacts, keys) that can't be parsed
+    Malformed(String),
+    // we have no key for this username
+    UnknownContact(String),
+    // no keyring for this username on this machine
+    UnknownProfile(String),
+    // we aren't in a group by this name or id
+    UnknownGroup(String),
+    NoActiveProfile,
+    InvalidUsername(String),
+    UsernameTaken(String),
+    // a contact presented a key other than the one we pinned
+    KeyChanged(KeyChanged),
+    // rune.toml couldn't be read
+    Config(String),
+    // the request doesn't make sense as given, e.g. mismatched passphrases
+    Invalid(String),
+    // rune-agent isn't running or couldn't do what was asked
+    Agent(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Crypto(e) => write!(f, "{}", e),
+            Error::BadPassphrase => write!(f, "wrong passphrase for keyring"),
+            Error::Keyring(e) => write!(f, "{}", e),
+            Error::Armor(e) => write!(f, "{}", e),
+            Error::MalformedEnvelope(e) => write!(f, "{}", e),
+            Error::Malformed(e) => write!(f, "{}", e),
+            Error::UnknownContact(username) => write!(
+                f,
+                "{} isn't a known contact, add them with `rune contact add {}`",
+                username, username
+            ),
+            Error::UnknownProfile(username) => write!(f, "no profile named {}", username),
+            Error::UnknownGroup(group) => write!(f, "you aren't in a group called {}", group),
+            Error::NoActiveProfile => write!(
+                f,
+                "no active profile, run `rune register <username>` or `rune profile use <username>`"
+            ),
+            Error::InvalidUsername(username) => write!(f, "invalid username {:?}", username),
+            Error::UsernameTaken(username) => write!(f, "username {} already exists", username),
+            Error::KeyChanged(e) => write!(f, "{}", e),
+            Error::Config(e) => write!(f, "{}", e),
+            Error::Invalid(e) => write!(f, "{}", e),
+            Error::Agent(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Crypto(e) => Some(e),
+            Error::Keyring(e) => Some(e),
+            Error::Armor(e) => Some(e),
+            Error::MalformedEnvelope(e) => Some(e),
+            Error::KeyChanged(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<CryptoError> for Error {
+    fn from(e: CryptoError) -> Self {
+        match e {
+            CryptoError::Agent(e) => Error::Agent(e),
+            e => Error::Crypto(e),
+        }
+    }
+}
+
+impl From<KeyringError> for Error {
+    fn from(e: KeyringError) -> Self {
+        match e {
+            KeyringError::BadPassphrase => Error::BadPassphrase,
+            e => Error::Keyring(e),
+        }
+    }
+}
+
+impl From<ArmorError> for Error {
+    fn from(e: ArmorError) -> Self {
+        Error::Armor(e)
+    }
+}
+
+impl From<EnvelopeError> for Error {
+    fn from(e: EnvelopeError) -> Self {
+        Error::MalformedEnvelope(e)
+    }
+}
+
+impl From<KeyChanged> for Error {
+    fn from(e: KeyChanged) -> Self {
+        Error::KeyChanged(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Malformed(e.to_string())
+    }
+}
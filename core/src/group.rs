@@ -0,0 +1,547 @@
+// Group conversations
+//
+// A group is described by a `GroupInfo`: its name, members, admins and an
+// epoch, signed by the admin who made that version of it. Admins send each new
+// version to the members over their pairwise sessions, and a member only takes
+// it if it's signed by someone who was an admin in the version they already
+// had, with a later epoch. Every change of membership moves the epoch on,
+// which retires every sender key: whoever was removed can't read anything
+// sent afterwards, and whoever was added can't read anything from before.
+//
+// Messages use sender keys (see `crypto::sender_key`). Before a member first
+// sends in an epoch they hand their new key to everyone else, again over
+// pairwise sessions. A group message is then a single envelope with the
+// `Group` suite, encrypted once and signed by the sender's identity key like
+// any other, delivered to each member.
+//
+// Members keep their own copy of the group under
+// <data dir>/<user>/groups/<id>.group, encrypted like sessions.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::config::Paths;
+use crate::contacts::ContactBook;
+use crate::crypto::e2ee::CryptoError;
+use crate::crypto::keyring::Keyring;
+use crate::crypto::sender_key::SenderKey;
+use crate::crypto::{sign, storage};
+use crate::envelope::{ContentType, Envelope, Payload};
+use crate::error::Error;
+
+const STORAGE_PURPOSE: &[u8] = b"rune-group-storage";
+const SIGNATURE_CONTEXT: &[u8] = b"rune-group-info-signature";
+pub const MAX_NAME_LEN: usize = 64;
+
+pub type GroupId = [u8; 16];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub username: String,
+    // compressed Edwards identity key, as in public-key.pub
+    identity: [u8; 32],
+}
+
+impl Member {
+    pub fn new(username: &str, identity: &EdwardsPoint) -> Self {
+        Self {
+            username: username.to_string(),
+            identity: identity.compress().to_bytes(),
+        }
+    }
+
+    pub fn identity(&self) -> Option<EdwardsPoint> {
+        CompressedEdwardsY(self.identity).decompress()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupInfo {
+    pub id: GroupId,
+    pub name: String,
+    pub epoch: u64,
+    pub members: Vec<Member>,
+    // always members too
+    pub admins: Vec<String>,
+    // the admin who made this version, and their signature over the rest
+    pub signer: String,
+    signature: Vec<u8>,
+}
+
+impl GroupInfo {
+    // A new group of `members`, which should include `owner`, with `owner`
+    // as its only admin
+    pub fn new(
+        name: &str,
+        owner: &str,
+        keyring: &Keyring,
+        members: Vec<Member>,
+    ) -> crate::Result<Self> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.chars().any(char::is_control) {
+            return Err(Error::Invalid(format!("invalid group name {:?}", name)));
+        }
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+
+        let mut info = Self {
+            id,
+            name: name.to_string(),
+            epoch: 0,
+            members,
+            admins: vec![owner.to_string()],
+            signer: owner.to_string(),
+            signature: Vec::new(),
+        };
+        info.sign(owner, keyring)?;
+        Ok(info)
+    }
+
+    // The next version of the group, with a new epoch, made by `admin`
+    pub fn next(
+        &self,
+        admin: &str,
+        keyring: &Keyring,
+        members: Vec<Member>,
+        mut admins: Vec<String>,
+    ) -> crate::Result<Self> {
+        if !self.is_admin(admin) {
+            return Err(Error::Invalid(format!(
+                "only admins of {} can change its members",
+                self.name
+            )));
+        }
+        admins.retain(|admin| members.iter().any(|m| &m.username == admin));
+        let mut next = Self {
+            epoch: self.epoch + 1,
+            members,
+            admins,
+            ..self.clone()
+        };
+        next.sign(admin, keyring)?;
+        Ok(next)
+    }
+
+    fn sign(&mut self, admin: &str, keyring: &Keyring) -> Result<(), CryptoError> {
+        self.signer = admin.to_string();
+        self.signature = keyring.sign(&self.signed_bytes())?.to_vec();
+        Ok(())
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = SIGNATURE_CONTEXT.to_vec();
+        bincode::serialize_into(
+            &mut bytes,
+            &(
+                &self.id,
+                &self.name,
+                self.epoch,
+                &self.members,
+                &self.admins,
+                &self.signer,
+            ),
+        )
+        .expect("group serialization is infallible");
+        bytes
+    }
+
+    // Check that this version was signed by one of its own admins
+    pub fn verify(&self) -> Result<(), CryptoError> {
+        let signer = self
+            .member(&self.signer)
+            .filter(|_| self.is_admin(&self.signer))
+            .and_then(Member::identity)
+            .ok_or(CryptoError::BadSignature)?;
+        sign::verify(&signer, &self.signed_bytes(), &self.signature)
+    }
+
+    pub fn member(&self, username: &str) -> Option<&Member> {
+        self.members.iter().find(|m| m.username == username)
+    }
+
+    pub fn is_member(&self, username: &str) -> bool {
+        self.member(username).is_some()
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|admin| admin == username)
+    }
+
+    pub fn id_hex(&self) -> String {
+        hex::encode(self.id)
+    }
+
+    // What changed between `self` and `next`, e.g. "added carol, removed dave"
+    fn changes(&self, next: &GroupInfo) -> String {
+        let mut changes = Vec::new();
+        for member in &next.members {
+            if !self.is_member(&member.username) {
+                changes.push(format!("added {}", member.username));
+            }
+        }
+        for member in &self.members {
+            if !next.is_member(&member.username) {
+                changes.push(format!("removed {}", member.username));
+            }
+        }
+        for admin in &next.admins {
+            if !self.is_admin(admin) {
+                changes.push(format!("made {} an admin", admin));
+            }
+        }
+        if changes.is_empty() {
+            changes.push(format!("updated {}", next.name));
+        }
+        changes.join(", ")
+    }
+}
+
+// Sent to members over pairwise sessions
+#[derive(Serialize, Deserialize)]
+pub enum Control {
+    // a new version of the group
+    Update(GroupInfo),
+    // the sender's key for `group` at `epoch`
+    SenderKey {
+        group: GroupId,
+        epoch: u64,
+        key: SenderKey,
+    },
+}
+
+impl Control {
+    pub fn to_payload(&self) -> Zeroizing<Vec<u8>> {
+        let body =
+            Zeroizing::new(bincode::serialize(self).expect("group serialization is infallible"));
+        Payload::new(ContentType::GroupControl, &body).to_bytes()
+    }
+
+    // The control message in the body of a `GroupControl` payload
+    pub fn from_body(body: &[u8]) -> crate::Result<Self> {
+        Ok(bincode::deserialize(body)?)
+    }
+}
+
+// Contents of `Envelope::header` for the `Group` suite
+#[derive(Serialize, Deserialize)]
+struct GroupHeader {
+    group: GroupId,
+    epoch: u64,
+    iteration: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReceivedKey {
+    sender: String,
+    epoch: u64,
+    key: SenderKey,
+}
+
+// One member's copy of a group
+#[derive(Serialize, Deserialize)]
+pub struct Group {
+    pub info: GroupInfo,
+    // our sender key and the epoch it's for
+    own: Option<(u64, SenderKey)>,
+    received: Vec<ReceivedKey>,
+}
+
+impl Group {
+    pub fn new(info: GroupInfo) -> Self {
+        Self {
+            info,
+            own: None,
+            received: Vec::new(),
+        }
+    }
+
+    fn dir(paths: &Paths, owner: &str) -> PathBuf {
+        paths.user(owner).join("groups")
+    }
+
+    pub fn path(paths: &Paths, owner: &str, id: &GroupId) -> PathBuf {
+        Self::dir(paths, owner).join(format!("{}.group", hex::encode(id)))
+    }
+
+    pub fn load(
+        paths: &Paths,
+        owner: &str,
+        keyring: &Keyring,
+        id: &GroupId,
+    ) -> crate::Result<Option<Self>> {
+        let path = Self::path(paths, owner, id);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = std::fs::read(&path)?;
+        let plaintext = storage::open(keyring, STORAGE_PURPOSE, id, &data)?;
+        Ok(Some(bincode::deserialize(&plaintext)?))
+    }
+
+    pub fn save(&self, paths: &Paths, owner: &str, keyring: &Keyring) -> crate::Result<()> {
+        let path = Self::path(paths, owner, &self.info.id);
+        std::fs::create_dir_all(Self::dir(paths, owner))?;
+
+        let plaintext = Zeroizing::new(bincode::serialize(self)?);
+        let data = storage::seal(keyring, STORAGE_PURPOSE, &self.info.id, &plaintext)?;
+        log::debug!("writing group state to {}", path.display());
+        crate::util::write_atomic(&path, &data)?;
+        Ok(())
+    }
+
+    // Every group `owner` has been in, by name
+    pub fn list(paths: &Paths, owner: &str, keyring: &Keyring) -> crate::Result<Vec<Self>> {
+        let dir = Self::dir(paths, owner);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut groups = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".group"))
+                .and_then(|id| hex::decode(id).ok())
+                .and_then(|id| GroupId::try_from(id).ok());
+            if let Some(group) = match id {
+                Some(id) => Self::load(paths, owner, keyring, &id)?,
+                None => None,
+            } {
+                groups.push(group);
+            }
+        }
+        groups.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+        Ok(groups)
+    }
+
+    // The group called `name`, or with `name` as its hex id
+    pub fn find(paths: &Paths, owner: &str, keyring: &Keyring, name: &str) -> crate::Result<Self> {
+        let mut found: Vec<Self> = Self::list(paths, owner, keyring)?
+            .into_iter()
+            .filter(|group| group.info.name == name || group.info.id_hex() == name)
+            .collect();
+        match found.len() {
+            0 => Err(Error::UnknownGroup(name.to_string())),
+            1 => Ok(found.remove(0)),
+            _ => Err(Error::Invalid(format!(
+                "you're in several groups called {}, name one by its id from `rune group list`",
+                name
+            ))),
+        }
+    }
+
+    // Move on to a new version of the group. Sender keys from before the
+    // previous epoch go, the previous one stays for messages still in flight.
+    pub fn update(&mut self, info: GroupInfo) {
+        if !matches!(&self.own, Some((epoch, _)) if *epoch == info.epoch) {
+            self.own = None;
+        }
+        self.received.retain(|r| r.epoch + 1 >= info.epoch);
+        self.info = info;
+    }
+
+    // Whether we have to make a sender key, and hand it out, before sending
+    pub fn needs_sender_key(&self) -> bool {
+        !matches!(&self.own, Some((epoch, _)) if *epoch == self.info.epoch)
+    }
+
+    // A new sender key for the current epoch, returning the message that
+    // hands it to the other members
+    pub fn new_sender_key(&mut self) -> Control {
+        let key = SenderKey::generate();
+        let control = Control::SenderKey {
+            group: self.info.id,
+            epoch: self.info.epoch,
+            key: key.share(),
+        };
+        self.own = Some((self.info.epoch, key));
+        control
+    }
+
+    // Encrypt `message` for the group; call `Envelope::sign` before sending
+    // it, and save the group first so no message key is used twice
+    pub fn encrypt(&mut self, message: &[u8]) -> Result<Envelope, CryptoError> {
+        let epoch = self.info.epoch;
+        let key = match &mut self.own {
+            Some((own_epoch, key)) if *own_epoch == epoch => key,
+            _ => return Err(CryptoError::NoSession),
+        };
+        let header = bincode::serialize(&GroupHeader {
+            group: self.info.id,
+            epoch,
+            iteration: key.iteration(),
+        })
+        .expect("group serialization is infallible");
+        let (_, ciphertext) = key.encrypt(message, &header)?;
+        Ok(Envelope::group(header, ciphertext))
+    }
+}
+
+// Groups loaded while reading messages, saved together at the end
+pub struct GroupCache<'a> {
+    paths: &'a Paths,
+    owner: &'a str,
+    keyring: &'a Keyring,
+    groups: HashMap<GroupId, Group>,
+}
+
+impl<'a> GroupCache<'a> {
+    pub fn new(paths: &'a Paths, owner: &'a str, keyring: &'a Keyring) -> Self {
+        Self {
+            paths,
+            owner,
+            keyring,
+            groups: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, id: &GroupId) -> crate::Result<Option<&mut Group>> {
+        Ok(match self.groups.entry(*id) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => Group::load(self.paths, self.owner, self.keyring, id)?
+                .map(|group| entry.insert(group)),
+        })
+    }
+
+    // Apply a control message `sender` sent over their session. A new version
+    // of a group returns the group's name and a line for the user about what
+    // changed. Members' keys are checked against any we've pinned, but never
+    // pinned from here: they're only the sender's word, and stay in the group.
+    pub fn apply(
+        &mut self,
+        contacts: &ContactBook,
+        sender: &str,
+        sender_public: &EdwardsPoint,
+        control: Control,
+    ) -> crate::Result<Option<(String, String)>> {
+        let owner = self.owner;
+        let is_sender = |info: &GroupInfo| {
+            info.member(sender).and_then(Member::identity) == Some(*sender_public)
+        };
+
+        match control {
+            Control::Update(info) => {
+                if info.signer != sender || !is_sender(&info) {
+                    return Err(Error::Invalid(format!(
+                        "{} sent an update to {} made by {}",
+                        sender, info.name, info.signer
+                    )));
+                }
+                info.verify()?;
+                for member in &info.members {
+                    let identity = member.identity().ok_or_else(|| {
+                        Error::Malformed(format!("{} has an invalid key", member.username))
+                    })?;
+                    if member.username == owner && identity != self.keyring.public {
+                        return Err(Error::Invalid(format!(
+                            "{} sent an update to {} with someone else's key for you",
+                            sender, info.name
+                        )));
+                    }
+                    contacts.compare(&member.username, &identity)?;
+                }
+
+                let name = info.name.clone();
+                let notice = match self.get(&info.id)? {
+                    Some(group) => {
+                        if info.epoch <= group.info.epoch || !group.info.is_admin(sender) {
+                            return Err(Error::Invalid(format!(
+                                "ignoring an outdated or unauthorised update to {} from {}",
+                                name, sender
+                            )));
+                        }
+                        let notice = match info.is_member(owner) {
+                            true => group.info.changes(&info),
+                            false => format!("removed you from {}", name),
+                        };
+                        group.update(info);
+                        notice
+                    }
+                    None if info.is_member(owner) => {
+                        let members: Vec<&str> =
+                            info.members.iter().map(|m| m.username.as_str()).collect();
+                        let notice = format!("added you to {} ({})", name, members.join(", "));
+                        self.groups.insert(info.id, Group::new(info));
+                        notice
+                    }
+                    None => {
+                        return Err(Error::Invalid(format!(
+                            "{} sent an update to {}, which you aren't in",
+                            sender, name
+                        )))
+                    }
+                };
+                Ok(Some((name, notice)))
+            }
+            Control::SenderKey { group, epoch, key } => {
+                let group = self
+                    .get(&group)?
+                    .ok_or_else(|| Error::UnknownGroup(hex::encode(group)))?;
+                if !is_sender(&group.info) {
+                    return Err(Error::Invalid(format!(
+                        "{} isn't a member of {}",
+                        sender, group.info.name
+                    )));
+                }
+                // a key for an epoch we've already left behind is no use
+                if epoch >= group.info.epoch {
+                    group
+                        .received
+                        .retain(|r| !(r.sender == sender && r.epoch == epoch));
+                    group.received.push(ReceivedKey {
+                        sender: sender.to_string(),
+                        epoch,
+                        key,
+                    });
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // Decrypt a group message from `sender`, returning the group's name
+    // along with it
+    pub fn decrypt(
+        &mut self,
+        sender: &str,
+        sender_public: &EdwardsPoint,
+        envelope: &Envelope,
+    ) -> crate::Result<(String, Vec<u8>)> {
+        let header: GroupHeader = bincode::deserialize(&envelope.header)?;
+        let group = self
+            .get(&header.group)?
+            .ok_or_else(|| Error::UnknownGroup(hex::encode(header.group)))?;
+        if group.info.member(sender).and_then(Member::identity) != Some(*sender_public) {
+            return Err(Error::Invalid(format!(
+                "{} isn't a member of {}",
+                sender, group.info.name
+            )));
+        }
+
+        let received = group
+            .received
+            .iter_mut()
+            .find(|r| r.sender == sender && r.epoch == header.epoch)
+            .ok_or(CryptoError::NoSession)?;
+        let plaintext =
+            received
+                .key
+                .decrypt(header.iteration, &envelope.ciphertext, &envelope.header)?;
+        Ok((group.info.name.clone(), plaintext))
+    }
+
+    pub fn save(self) -> crate::Result<()> {
+        for group in self.groups.values() {
+            group.save(self.paths, self.owner, self.keyring)?;
+        }
+        Ok(())
+    }
+}
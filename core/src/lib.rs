@@ -1,2 +1,21 @@
+pub mod agent;
+pub mod armor;
+pub mod attachment;
+pub mod client;
 pub mod cmd;
+pub mod config;
+pub mod contacts;
 pub mod crypto;
+pub mod directory;
+pub mod envelope;
+pub mod error;
+pub mod group;
+pub mod mailbox;
+pub mod passphrase;
+pub mod profile;
+pub mod relay;
+pub mod session;
+pub mod transport;
+pub mod util;
+pub use client::RuneClient;
+pub use error::{Error, Result};
@@ -0,0 +1,256 @@
+// Incoming message storage, laid out like a Maildir
+//
+// <data dir>/<user>/mailbox/
+//   tmp/  envelopes being written
+//   new/  delivered, unread envelopes
+//   cur/  envelopes that have been read
+//   archive/  envelopes put aside after reading
+//
+// Each envelope is written to tmp/ under a unique name and renamed into new/,
+// so concurrent deliveries never clobber each other and readers never see a
+// partially written file. Message ids start with the delivery time in
+// milliseconds and a per-process sequence number, so sorting by id sorts by
+// arrival.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::{rngs::OsRng, RngCore};
+
+use crate::config::Paths;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId(String);
+
+// Orders messages delivered by this process within the same millisecond
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+impl MessageId {
+    fn generate() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        Self(format!(
+            "{:013}-{:08x}-{:016x}",
+            millis,
+            sequence,
+            OsRng.next_u64()
+        ))
+    }
+
+    // Milliseconds since the unix epoch at which the message was delivered
+    pub fn timestamp(&self) -> u64 {
+        self.0
+            .split('-')
+            .next()
+            .and_then(|millis| millis.parse().ok())
+            .unwrap_or_default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::str::FromStr for MessageId {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid = !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+        if valid {
+            Ok(Self(s.to_string()))
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid message id {:?}", s),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageState {
+    Unread,
+    Read,
+    Archived,
+}
+
+impl MessageState {
+    fn dir(self) -> &'static str {
+        match self {
+            MessageState::Unread => "new",
+            MessageState::Read => "cur",
+            MessageState::Archived => "archive",
+        }
+    }
+}
+
+impl std::fmt::Display for MessageState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageState::Unread => write!(f, "unread"),
+            MessageState::Read => write!(f, "read"),
+            MessageState::Archived => write!(f, "archived"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MailboxEntry {
+    pub id: MessageId,
+    pub state: MessageState,
+    pub size: u64,
+}
+
+impl MailboxEntry {
+    pub fn timestamp(&self) -> u64 {
+        self.id.timestamp()
+    }
+}
+
+pub struct Mailbox {
+    root: PathBuf,
+}
+
+impl Mailbox {
+    pub fn path(paths: &Paths, username: &str) -> PathBuf {
+        paths.user(username).join("mailbox")
+    }
+
+    pub fn open(paths: &Paths, username: &str) -> std::io::Result<Self> {
+        Self::open_at(Self::path(paths, username))
+    }
+
+    pub fn open_at(root: PathBuf) -> std::io::Result<Self> {
+        for dir in ["tmp", "new", "cur", "archive"] {
+            std::fs::create_dir_all(root.join(dir))?;
+        }
+        Ok(Self { root })
+    }
+
+    // Store a serialized envelope as a new, unread message
+    pub fn deliver(&self, envelope: &[u8]) -> std::io::Result<MessageId> {
+        use std::io::Write;
+
+        let id = MessageId::generate();
+        let tmp_path = self.root.join("tmp").join(id.as_str());
+        let result = (|| {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            file.write_all(envelope)?;
+            file.sync_all()?;
+            std::fs::rename(&tmp_path, self.entry_path(&id, MessageState::Unread))
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        result.map(|_| {
+            log::debug!("delivered {} to {}", id, self.root.display());
+            id
+        })
+    }
+
+    // All messages that haven't been archived, oldest first
+    pub fn list(&self) -> std::io::Result<Vec<MailboxEntry>> {
+        let mut entries = self.list_state(MessageState::Unread)?;
+        entries.extend(self.list_state(MessageState::Read)?);
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
+
+    pub fn unread(&self) -> std::io::Result<Vec<MailboxEntry>> {
+        let mut entries = self.list_state(MessageState::Unread)?;
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
+
+    pub fn get(&self, id: &MessageId) -> std::io::Result<MailboxEntry> {
+        for state in [
+            MessageState::Unread,
+            MessageState::Read,
+            MessageState::Archived,
+        ] {
+            if let Ok(metadata) = std::fs::metadata(self.entry_path(id, state)) {
+                return Ok(MailboxEntry {
+                    id: id.clone(),
+                    state,
+                    size: metadata.len(),
+                });
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no message {}", id),
+        ))
+    }
+
+    pub fn read(&self, entry: &MailboxEntry) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.entry_path(&entry.id, entry.state))
+    }
+
+    // The first `len` bytes of a message, or all of it if it's shorter
+    pub fn read_prefix(&self, entry: &MailboxEntry, len: usize) -> std::io::Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut bytes = Vec::with_capacity(len);
+        std::fs::File::open(self.entry_path(&entry.id, entry.state))?
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn mark_read(&self, entry: &MailboxEntry) -> std::io::Result<()> {
+        self.move_to(entry, MessageState::Read)
+    }
+
+    pub fn archive(&self, entry: &MailboxEntry) -> std::io::Result<()> {
+        self.move_to(entry, MessageState::Archived)
+    }
+
+    pub fn remove(&self, entry: &MailboxEntry) -> std::io::Result<()> {
+        std::fs::remove_file(self.entry_path(&entry.id, entry.state))
+    }
+
+    fn move_to(&self, entry: &MailboxEntry, state: MessageState) -> std::io::Result<()> {
+        if entry.state == state {
+            return Ok(());
+        }
+        std::fs::rename(
+            self.entry_path(&entry.id, entry.state),
+            self.entry_path(&entry.id, state),
+        )
+    }
+
+    fn entry_path(&self, id: &MessageId, state: MessageState) -> PathBuf {
+        self.root.join(state.dir()).join(id.as_str())
+    }
+
+    fn list_state(&self, state: MessageState) -> std::io::Result<Vec<MailboxEntry>> {
+        let mut entries = Vec::new();
+        for dir_entry in std::fs::read_dir(self.root.join(state.dir()))? {
+            let dir_entry = dir_entry?;
+            let Some(id) = file_id(&dir_entry.path()) else {
+                continue;
+            };
+            entries.push(MailboxEntry {
+                id,
+                state,
+                size: dir_entry.metadata()?.len(),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+fn file_id(path: &Path) -> Option<MessageId> {
+    path.file_name()?.to_str()?.parse().ok()
+}
@@ -1,14 +1,90 @@
-use clap::App;
+use std::path::Path;
 
-mod cmd;
-mod crypto;
+use clap::{App, Arg, ArgMatches};
 
-use cmd::key::KeyCmd;
-use cmd::register::{prompt_passphrase, register_create_home, RegisterCmd};
-use cmd::send::{ReceiveCmd, SendCmd};
-use crypto::keyring::Keyring;
+use rune_core::client::{Profile, RuneClient};
+use rune_core::cmd::agent::AgentCmd;
+use rune_core::cmd::contact::ContactCmd;
+use rune_core::cmd::group::GroupCmd;
+use rune_core::cmd::inbox::{InboxCmd, ReceivedMessage};
+use rune_core::cmd::key::KeyCmd;
+use rune_core::cmd::profile::ProfileCmd;
+use rune_core::cmd::register::{prompt, RegisterCmd};
+use rune_core::cmd::send::{ReceiveCmd, SendCmd};
+use rune_core::passphrase::Passphrases;
+use rune_core::Error;
 
-use crate::crypto::keyring::KeyringEncryptor;
+// Exit status for `e`, from sysexits.h where one fits
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::KeyChanged(_) => 3,
+        Error::Invalid(_) | Error::InvalidUsername(_) => 64,
+        Error::Malformed(_)
+        | Error::MalformedEnvelope(_)
+        | Error::Armor(_)
+        | Error::Crypto(_)
+        | Error::Keyring(_) => 65,
+        Error::UnknownContact(_)
+        | Error::UnknownProfile(_)
+        | Error::UnknownGroup(_)
+        | Error::NoActiveProfile => 67,
+        Error::Agent(_) => 69,
+        Error::UsernameTaken(_) => 73,
+        Error::Io(_) => 74,
+        Error::BadPassphrase => 77,
+        Error::Config(_) => 78,
+    }
+}
+
+// `username`'s keyring from rune-agent, or else ask for their passphrase and
+// decrypt it, offering to upgrade one in the old format
+fn unlock(
+    client: &RuneClient,
+    passphrases: &mut Passphrases,
+    username: &str,
+) -> rune_core::Result<Profile> {
+    if let Some(profile) = client.unlock_from_agent(username) {
+        return Ok(profile);
+    }
+    let passphrase = passphrases.ask(&format!("Passphrase for {}'s keyring", username))?;
+    let profile = client.unlock(username, &passphrase)?;
+    if client.is_legacy(username)? {
+        let answer = prompt("Your keyring uses an old, weaker format. Upgrade it now? [Y/n] ")?;
+        if matches!(answer.as_str(), "" | "y" | "Y" | "yes") {
+            client.change_passphrase(username, &passphrase, &passphrase)?;
+        }
+    }
+    Ok(profile)
+}
+
+// "alice", "alice (to bob, carol)" for a message to several people or
+// "alice in team" for one to a group
+fn from(msg: &ReceivedMessage) -> String {
+    match &msg.group {
+        Some(group) => format!("{} in {}", msg.sender, group),
+        None if msg.to.is_empty() => msg.sender.clone(),
+        None => format!("{} (to {})", msg.sender, msg.to.join(", ")),
+    }
+}
+
+// Save the file `msg` carries, if any, into `dir`
+fn save_attachment(
+    client: &RuneClient,
+    owner: &str,
+    msg: &ReceivedMessage,
+    dir: &Path,
+) -> rune_core::Result<()> {
+    if let Some(attachment) = &msg.attachment {
+        let path = client.save_attachment(owner, attachment, dir)?;
+        println!(
+            "  saved {} ({} bytes, sha256 {})",
+            path.display(),
+            attachment.size,
+            attachment.sha256_hex()
+        );
+    }
+    Ok(())
+}
 
 fn main() {
     pretty_env_logger::try_init().ok();
@@ -19,75 +95,454 @@ fn main() {
         .subcommand_required(true)
         .arg_required_else_help(true)
         .author("@phasewalk1 <3")
+        .arg(
+            Arg::with_name("transport")
+                .long("transport")
+                .takes_value(true)
+                .global(true)
+                .help("How to deliver messages: fs, relay or relay:<addr> [env: RUNE_TRANSPORT]"),
+        )
+        .arg(
+            Arg::with_name("as")
+                .long("as")
+                .takes_value(true)
+                .global(true)
+                .help("Act as this identity instead of the active profile"),
+        )
+        .arg(
+            Arg::with_name("passphrase-fd")
+                .long("passphrase-fd")
+                .takes_value(true)
+                .value_name("FD")
+                .global(true)
+                .help("Read passphrases from this file descriptor, one per line [env: RUNE_PASSPHRASE_FILE reads them from a file]"),
+        )
         .subcommand(RegisterCmd::default().0)
         .subcommand(KeyCmd::default().0)
+        .subcommand(ContactCmd::default().0)
+        .subcommand(GroupCmd::default().0)
+        .subcommand(ProfileCmd::default().0)
         .subcommand(SendCmd::default().0)
         .subcommand(ReceiveCmd::default().0)
+        .subcommand(InboxCmd::default().0)
+        .subcommand(AgentCmd::default().0)
         .get_matches();
 
+    if let Err(e) = run(&matches) {
+        eprintln!("Error: {}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn run(matches: &ArgMatches) -> rune_core::Result<()> {
+    let mut client = RuneClient::from_env(matches.value_of("transport"))?;
+    let fd = matches
+        .value_of("passphrase-fd")
+        .map(|fd| {
+            fd.parse()
+                .map_err(|_| Error::Invalid(format!("invalid file descriptor {:?}", fd)))
+        })
+        .transpose()?;
+    let mut passphrases = Passphrases::resolve(fd, client.config())?;
+
     if let Some(matches) = matches.subcommand_matches("register") {
         let username = matches.value_of("username").unwrap();
-        log::debug!("received username arg: {}", username);
-        // TODO:
-        // Implement some username uniqueness check with the backend server once
-        // we have that up and running
-        log::debug!("creating a home for user at $HOME/.config/rune");
-        register_create_home(&username).unwrap();
-        log::debug!("created $HOME/.config/rune/{}", username);
-
-        // generate a keypair
-        let keypair = Keyring::generate();
-        log::debug!("generated keypair: {:?}", keypair);
-        keypair.save_public_key(username).unwrap();
-        // save secret key to a file, prompt user for passphrase to encrypt it
-        let passphrase = prompt_passphrase().unwrap();
-
-        let encryptor = KeyringEncryptor::from(keypair);
-        let home_dir = dirs::home_dir().unwrap();
-
-        let path = format!(
-            "{}/.config/rune/{}/keyring.enc",
-            home_dir.display(),
-            username
-        );
-        std::fs::create_dir_all(format!("{}/.config/rune/{}", home_dir.display(), username))
-            .unwrap();
-        encryptor.encrypt(&path, &passphrase).unwrap();
-        log::debug!("saved encrypted keyring to {}", path);
+        let passphrase =
+            passphrases.ask_new(&format!("New passphrase for {}'s keyring", username))?;
+        client.register(username, &passphrase)?;
+        println!("Registered {}, now the active profile", username);
     }
 
     if let Some(matches) = matches.subcommand_matches("key") {
-        let display = matches.value_of("display");
-        if let Some(_) = display {
-            // TODO: hack
-            let enc_path = dirs::home_dir()
-                .unwrap()
-                .join(".config/rune/phasewalk1/keyring.enc");
-            if let Some(enc_path) = enc_path.to_str() {
-                let keyring =
-                    KeyringEncryptor::decrypt(enc_path, &prompt_passphrase().unwrap()).unwrap();
-                KeyCmd::unsafe_display(keyring);
+        if matches.subcommand_matches("passwd").is_some() {
+            let username = client.whoami(matches.value_of("as"))?;
+            let old = passphrases.ask(&format!("Current passphrase for {}'s keyring", username))?;
+            // fail early on a typo rather than after asking for the new passphrase
+            client.unlock(&username, &old)?;
+            let new = passphrases.ask_new(&format!("New passphrase for {}'s keyring", username))?;
+            client.change_passphrase(&username, &old, &new)?;
+            println!("Passphrase changed");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("fingerprint") {
+            let username = client.whoami(matches.value_of("as"))?;
+            print!(
+                "{}",
+                client.fingerprint(&username, matches.value_of("contact"))?
+            );
+        }
+
+        if let Some(matches) = matches.subcommand_matches("export") {
+            let username = client.whoami(matches.value_of("as"))?;
+            let armored = if matches.is_present("secret") {
+                let passphrase =
+                    passphrases.ask(&format!("Passphrase for {}'s keyring", username))?;
+                let export_passphrase = passphrases.ask_new("Passphrase to protect the export")?;
+                client.export_secret(&username, &passphrase, &export_passphrase)?
+            } else {
+                client.export_public(&username)?
+            };
+
+            match matches.value_of("output") {
+                Some(path) => std::fs::write(path, armored)?,
+                None => print!("{}", armored),
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("import") {
+            use rune_core::armor::{ArmorKind, Armored};
+
+            let text = match matches.value_of("file") {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let force = matches.is_present("force");
+
+            let armored = Armored::decode(&text)?;
+            match armored.kind {
+                ArmorKind::PublicKey => {
+                    let username = client.import_public(&armored, matches.value_of("as"), force)?;
+                    println!("Imported public key for {}", username);
+                }
+                ArmorKind::SecretKey => {
+                    let passphrase = passphrases.ask("Passphrase for the exported keyring")?;
+                    let username = client.import_secret(
+                        &armored,
+                        matches.value_of("as"),
+                        &passphrase,
+                        force,
+                    )?;
+                    println!("Imported keyring for {}", username);
+                }
             }
         }
     }
 
-    if let Some(matches) = matches.subcommand_matches("send") {
-        use crate::cmd::send::send_message;
+    if let Some(matches) = matches.subcommand_matches("profile") {
+        use rune_core::cmd::profile::print_profiles;
 
-        let username = matches.value_of("recipient").unwrap();
-        let message = matches.value_of("message").unwrap();
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                let profiles = client.profiles()?;
+                if profiles.is_empty() {
+                    println!("No profiles, create one with `rune register <username>`");
+                } else {
+                    print_profiles(&profiles, client.whoami(None).ok().as_deref());
+                }
+            }
+            Some(("use", matches)) => {
+                let username = matches.value_of("username").unwrap();
+                client.use_profile(username)?;
+                println!("Now acting as {}", username);
+            }
+            Some(("remove", matches)) => {
+                let username = matches.value_of("username").unwrap();
+                let confirmed = matches.is_present("yes")
+                    || prompt(&format!(
+                        "This deletes the keyring, contacts and messages for {}. \
+                         Type the username to confirm: ",
+                        username
+                    ))
+                    .map(|answer| answer == username)
+                    .unwrap_or(false);
+                if confirmed {
+                    client.remove_profile(username)?;
+                    println!("Removed {}", username);
+                } else {
+                    println!("{} was not removed", username);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
 
-        let keyring = Keyring::load().unwrap();
+    if let Some(matches) = matches.subcommand_matches("group") {
+        use rune_core::cmd::group::print_groups;
 
-        send_message(username, message).unwrap();
+        let owner = client.whoami(matches.value_of("as"))?;
+        let usernames = |matches: &ArgMatches| -> Vec<String> {
+            matches
+                .values_of("usernames")
+                .into_iter()
+                .flatten()
+                .flat_map(|names| names.split(','))
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let profile = unlock(&client, &mut passphrases, &owner)?;
+        match matches.subcommand() {
+            Some(("create", matches)) => {
+                let members = usernames(matches);
+                let members: Vec<&str> = members.iter().map(String::as_str).collect();
+                let info =
+                    client.create_group(&profile, matches.value_of("name").unwrap(), &members)?;
+                println!("Created {} ({})", info.name, info.id_hex());
+            }
+            Some(("add", matches)) => {
+                let members = usernames(matches);
+                let members: Vec<&str> = members.iter().map(String::as_str).collect();
+                let info = client.add_to_group(
+                    &profile,
+                    matches.value_of("group").unwrap(),
+                    &members,
+                    matches.is_present("admin"),
+                )?;
+                println!("Updated {}, now at epoch {}", info.name, info.epoch);
+            }
+            Some(("remove", matches)) => {
+                let members = usernames(matches);
+                let members: Vec<&str> = members.iter().map(String::as_str).collect();
+                let info = client.remove_from_group(
+                    &profile,
+                    matches.value_of("group").unwrap(),
+                    &members,
+                )?;
+                println!("Updated {}, now at epoch {}", info.name, info.epoch);
+            }
+            Some(("send", matches)) => client.send_to_group(
+                &profile,
+                matches.value_of("group").unwrap(),
+                matches.value_of("message").unwrap(),
+            )?,
+            Some(("list", _)) => {
+                let groups = client.groups(&profile)?;
+                if groups.is_empty() {
+                    println!(
+                        "No groups, create one with `rune group create <name> <usernames>...`"
+                    );
+                } else {
+                    print_groups(&groups, &owner);
+                }
+            }
+            _ => unreachable!("a subcommand is required"),
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("agent") {
+        use rune_core::cmd::agent::print_unlocked;
+
+        match matches.subcommand() {
+            Some(("unlock", matches)) => {
+                let timeout = matches
+                    .value_of("timeout")
+                    .map(|timeout| {
+                        humantime::parse_duration(timeout)
+                            .map_err(|e| Error::Invalid(format!("bad timeout: {}", e)))
+                    })
+                    .transpose()?;
+                let username = client.whoami(matches.value_of("as"))?;
+                let mut agent = client.agent()?;
+                let passphrase =
+                    passphrases.ask(&format!("Passphrase for {}'s keyring", username))?;
+                let held = agent.unlock(&username, &passphrase, timeout)?;
+                println!(
+                    "Unlocked {}'s keyring for {}",
+                    username,
+                    humantime::format_duration(held)
+                );
+            }
+            Some(("lock", matches)) => {
+                let count = client.agent()?.lock(matches.value_of("username"))?;
+                println!(
+                    "Locked {} keyring{}",
+                    count,
+                    if count == 1 { "" } else { "s" }
+                );
+            }
+            Some(("status", _)) => match client.agent() {
+                Ok(mut agent) => {
+                    println!(
+                        "rune-agent is running on {}",
+                        client.paths().agent_socket().display()
+                    );
+                    print_unlocked(&agent.status()?);
+                }
+                Err(e) => println!("rune-agent isn't running ({})", e),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("contact") {
+        use rune_core::cmd::contact::{print_contacts, read_public_key};
+
+        let owner = client.whoami(matches.value_of("as"))?;
+        match matches.subcommand() {
+            Some(("add", matches)) => {
+                let username = matches.value_of("username").unwrap();
+                let identity = match matches.value_of("key") {
+                    Some(path) => Some(read_public_key(&std::fs::read_to_string(path)?)?),
+                    None => None,
+                };
+                client.add_contact(
+                    &owner,
+                    username,
+                    matches.value_of("name"),
+                    identity.as_ref(),
+                    matches.is_present("force"),
+                )?;
+                println!("Added {}", username);
+            }
+            Some(("list", _)) => {
+                let contacts = client.contacts(&owner)?;
+                if contacts.list().is_empty() {
+                    println!("No contacts");
+                } else {
+                    print_contacts(&contacts);
+                }
+            }
+            Some(("remove", matches)) => {
+                let username = matches.value_of("username").unwrap();
+                client.remove_contact(&owner, username)?;
+                println!("Removed {}", username);
+            }
+            Some(("verify", matches)) => {
+                let username = matches.value_of("username").unwrap();
+                print!("{}", client.safety_number(&owner, username)?);
+                if !matches.is_present("yes") {
+                    let answer = prompt(&format!(
+                        "Does it match the one {} sees for you? [y/N] ",
+                        username
+                    ))?;
+                    if !answer.trim().eq_ignore_ascii_case("y") {
+                        println!("{} was not marked as verified", username);
+                        return Ok(());
+                    }
+                }
+                client.verify_contact(&owner, username)?;
+                println!("Marked {} as verified", username);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("send") {
+        let mut recipients: Vec<&str> = Vec::new();
+        for recipient in matches.value_of("recipient").unwrap().split(',') {
+            let recipient = recipient.trim();
+            if !recipient.is_empty() && !recipients.contains(&recipient) {
+                recipients.push(recipient);
+            }
+        }
+        let header_encryption = matches.is_present("encrypt-headers");
+        if recipients.is_empty() {
+            return Err(Error::Invalid("no recipient given".to_string()));
+        }
+        if recipients.len() > 1 && matches.is_present("file") {
+            return Err(Error::Invalid(
+                "files can only be sent to one recipient at a time".to_string(),
+            ));
+        }
+
+        let sender = client.whoami(matches.value_of("as"))?;
+        let profile = unlock(&client, &mut passphrases, &sender)?;
+        match (recipients.as_slice(), matches.value_of("file")) {
+            ([recipient], Some(path)) => {
+                let attachment =
+                    client.send_file(&profile, recipient, Path::new(path), header_encryption)?;
+                println!(
+                    "Sent {} to {} (sha256 {})",
+                    attachment.describe(),
+                    recipient,
+                    attachment.sha256_hex()
+                );
+            }
+            ([recipient], None) => client.send(
+                &profile,
+                recipient,
+                matches.value_of("message").unwrap(),
+                header_encryption,
+            )?,
+            (recipients, _) => client.send_to_many(
+                &profile,
+                recipients,
+                matches.value_of("message").unwrap(),
+                matches.is_present("hide-recipients"),
+            )?,
+        }
     }
 
     if let Some(matches) = matches.subcommand_matches("receive") {
-        use crate::cmd::send::receive_message;
+        use rune_core::cmd::inbox::AfterRead;
 
         let sender = matches.value_of("sender").unwrap();
+        let receiver = client.whoami(matches.value_of("as"))?;
+        let profile = unlock(&client, &mut passphrases, &receiver)?;
+        client.fetch(&profile)?;
 
-        let msg = receive_message(sender, "emobitstream");
-        println!("{}", msg.unwrap());
+        let pending = client.unread_from(&receiver, sender)?;
+        if pending.is_empty() {
+            println!("No unread messages from {}", sender);
+            return Ok(());
+        }
+        let dir = Path::new(matches.value_of("output-dir").unwrap_or("."));
+        let opened = client.read(&profile, pending, AfterRead::MarkRead)?;
+        for skipped in &opened.skipped {
+            eprintln!("{}", skipped);
+        }
+        for msg in opened.received {
+            println!("{}: {}", from(&msg), msg.body.as_str());
+            save_attachment(&client, &receiver, &msg, dir)?;
+        }
     }
+
+    if let Some(matches) = matches.subcommand_matches("inbox") {
+        use rune_core::cmd::inbox::{print_inbox, AfterRead};
+        use rune_core::mailbox::MessageId;
+
+        let username = client.whoami(matches.value_of("as"))?;
+        let ids = matches
+            .values_of("ids")
+            .into_iter()
+            .flatten()
+            .map(|id| {
+                id.parse()
+                    .map_err(|e: std::io::Error| Error::Invalid(e.to_string()))
+            })
+            .collect::<rune_core::Result<Vec<MessageId>>>()?;
+        // one passphrase prompt, for fetching and reading
+        let profile = unlock(&client, &mut passphrases, &username)?;
+        client.fetch(&profile)?;
+
+        let items = if ids.is_empty() {
+            client.inbox(&username, matches.is_present("all"))?
+        } else {
+            client.find_messages(&username, &ids)?
+        };
+
+        // naming messages means reading them
+        if !matches.is_present("read") && ids.is_empty() {
+            if items.is_empty() {
+                println!("No messages");
+            } else {
+                print_inbox(&items);
+            }
+            return Ok(());
+        }
+        if items.is_empty() {
+            println!("No messages to read");
+            return Ok(());
+        }
+
+        let after = if matches.is_present("delete") {
+            AfterRead::Delete
+        } else if matches.is_present("archive") {
+            AfterRead::Archive
+        } else {
+            AfterRead::MarkRead
+        };
+        let dir = Path::new(matches.value_of("output-dir").unwrap_or("."));
+        let opened = client.read(&profile, items, after)?;
+        for skipped in &opened.skipped {
+            eprintln!("{}", skipped);
+        }
+        for msg in opened.received {
+            println!("[{}] {}: {}", msg.id, from(&msg), msg.body.as_str());
+            save_attachment(&client, &username, &msg, dir)?;
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,324 @@
+// Where passphrases come from
+//
+// At a terminal they're read with echo off, and new ones are typed twice and
+// checked for strength. Scripts and CI can supply them instead, in order of
+// precedence:
+//
+//   --passphrase-fd <fd>          one per line from an inherited descriptor
+//   $RUNE_PASSPHRASE_FILE         one per line from a file
+//   $RUNE_PINENTRY, or `pinentry` in rune.toml
+//                                 ask a pinentry program, as gpg-agent does
+//
+// Passphrases read from lines (including a piped stdin) are used as given: a
+// new one isn't confirmed, and a weak one only gets a warning. Commands that
+// need several passphrases, like `rune key passwd`, read one line each.
+
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::process::{Command, Stdio};
+
+use zeroize::Zeroizing;
+
+use crate::config::Config;
+use crate::error::Error;
+
+pub const FILE_ENV: &str = "RUNE_PASSPHRASE_FILE";
+pub const PINENTRY_ENV: &str = "RUNE_PINENTRY";
+
+// shorter passphrases are refused outright
+const MIN_LEN: usize = 8;
+// rough guessing cost below which a passphrase is refused
+const MIN_BITS: f64 = 50.0;
+// tries to type a good, matching new passphrase before giving up
+const ATTEMPTS: usize = 3;
+
+enum Source {
+    Terminal,
+    Lines(Box<dyn BufRead>),
+    Pinentry(String),
+}
+
+pub struct Passphrases {
+    source: Source,
+}
+
+impl Passphrases {
+    // Read from the terminal, or stdin if it isn't one
+    pub fn terminal() -> Self {
+        Self {
+            source: Source::Terminal,
+        }
+    }
+
+    // `fd` if given (from --passphrase-fd), otherwise the first of
+    // $RUNE_PASSPHRASE_FILE, $RUNE_PINENTRY and `pinentry` in `config`
+    // that's set, otherwise the terminal
+    pub fn resolve(fd: Option<i32>, config: &Config) -> crate::Result<Self> {
+        let source = if let Some(fd) = fd {
+            Source::Lines(Box::new(BufReader::new(open_fd(fd)?)))
+        } else if let Some(path) = std::env::var_os(FILE_ENV).filter(|path| !path.is_empty()) {
+            let file = std::fs::File::open(&path).map_err(|e| {
+                Error::Config(format!("{} ({}): {}", FILE_ENV, path.to_string_lossy(), e))
+            })?;
+            Source::Lines(Box::new(BufReader::new(file)))
+        } else if let Some(program) = std::env::var(PINENTRY_ENV)
+            .ok()
+            .filter(|program| !program.is_empty())
+            .or_else(|| config.pinentry.clone())
+        {
+            Source::Pinentry(program)
+        } else {
+            Source::Terminal
+        };
+        Ok(Self { source })
+    }
+
+    fn interactive(&self) -> bool {
+        match self.source {
+            Source::Terminal => std::io::stdin().is_terminal(),
+            Source::Lines(_) => false,
+            Source::Pinentry(_) => true,
+        }
+    }
+
+    fn read(&mut self, prompt: &str, error: Option<&str>) -> crate::Result<Zeroizing<String>> {
+        match &mut self.source {
+            Source::Terminal if std::io::stdin().is_terminal() => {
+                if let Some(error) = error {
+                    eprintln!("{}", error);
+                }
+                Ok(Zeroizing::new(rpassword::prompt_password(format!(
+                    "{}: ",
+                    prompt
+                ))?))
+            }
+            Source::Terminal => read_line(&mut std::io::stdin().lock()),
+            Source::Lines(lines) => read_line(lines),
+            Source::Pinentry(program) => pinentry(program, prompt, error),
+        }
+    }
+
+    // An existing passphrase, `prompt` says what it's for
+    pub fn ask(&mut self, prompt: &str) -> crate::Result<Zeroizing<String>> {
+        self.read(prompt, None)
+    }
+
+    // A passphrase being set. When someone's there to type it, it has to be
+    // strong enough and typed twice.
+    pub fn ask_new(&mut self, prompt: &str) -> crate::Result<Zeroizing<String>> {
+        if !self.interactive() {
+            let passphrase = self.read(prompt, None)?;
+            if let Some(weakness) = weakness(&passphrase) {
+                eprintln!("WARNING: weak passphrase, {}", weakness);
+            }
+            return Ok(passphrase);
+        }
+
+        let mut error = None;
+        for _ in 0..ATTEMPTS {
+            let passphrase = self.read(prompt, error.as_deref())?;
+            if let Some(weakness) = weakness(&passphrase) {
+                error = Some(format!("passphrase too weak, {}", weakness));
+                continue;
+            }
+            if self.read("Repeat to confirm", None)? != passphrase {
+                error = Some("passphrases don't match".to_string());
+                continue;
+            }
+            return Ok(passphrase);
+        }
+        Err(Error::Invalid(error.unwrap_or_default()))
+    }
+}
+
+// What's wrong with `passphrase` as protection for a keyring, if anything
+pub fn weakness(passphrase: &str) -> Option<String> {
+    if passphrase.chars().count() < MIN_LEN {
+        return Some(format!("use at least {} characters", MIN_LEN));
+    }
+    if estimated_bits(passphrase) < MIN_BITS {
+        return Some("use more words, or mix in capitals, digits and symbols".to_string());
+    }
+    None
+}
+
+// Length times the bits per character of the character classes used, with
+// repeats only counted up to twice the number of distinct characters
+fn estimated_bits(passphrase: &str) -> f64 {
+    let has = |f: fn(&char) -> bool| passphrase.chars().any(|c| f(&c));
+    let mut pool = 0;
+    if has(char::is_ascii_lowercase) {
+        pool += 26;
+    }
+    if has(char::is_ascii_uppercase) {
+        pool += 26;
+    }
+    if has(char::is_ascii_digit) {
+        pool += 10;
+    }
+    if has(|c| c.is_ascii_punctuation() || *c == ' ') {
+        pool += 33;
+    }
+    if has(|c| !c.is_ascii()) {
+        pool += 100;
+    }
+
+    let mut distinct: Vec<char> = passphrase.chars().collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+    let length = passphrase.chars().count().min(2 * distinct.len());
+    length as f64 * (pool as f64).log2()
+}
+
+fn read_line(reader: &mut dyn BufRead) -> crate::Result<Zeroizing<String>> {
+    let mut line = Zeroizing::new(String::new());
+    if reader.read_line(&mut line)? == 0 {
+        return Err(Error::Invalid("no passphrase given".to_string()));
+    }
+    let len = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> crate::Result<std::fs::File> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 0 {
+        return Err(Error::Invalid(format!("invalid file descriptor {}", fd)));
+    }
+    // read from a duplicate, so dropping the `File` leaves `fd` open; it may
+    // well be stdin
+    // SAFETY: dup only reads the descriptor table
+    let dup = unsafe { libc::dup(fd) };
+    if dup < 0 {
+        return Err(Error::Invalid(format!(
+            "can't read from file descriptor {}: {}",
+            fd,
+            std::io::Error::last_os_error()
+        )));
+    }
+    // SAFETY: `dup` is a new descriptor that nothing else owns
+    Ok(unsafe { std::fs::File::from_raw_fd(dup) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> crate::Result<std::fs::File> {
+    Err(Error::Invalid(
+        "--passphrase-fd is only supported on unix".to_string(),
+    ))
+}
+
+// Ask `program` for a passphrase over the Assuan protocol pinentry speaks
+fn pinentry(program: &str, prompt: &str, error: Option<&str>) -> crate::Result<Zeroizing<String>> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Config(format!("can't run pinentry {}: {}", program, e)))?;
+    let mut input = child.stdin.take().expect("stdin is piped");
+    let mut output = BufReader::new(child.stdout.take().expect("stdout is piped"));
+
+    let mut converse = || -> crate::Result<Zeroizing<String>> {
+        // the greeting
+        assuan_response(&mut output)?;
+        let mut commands = vec![
+            format!("SETDESC {}", assuan_escape(prompt)),
+            "SETPROMPT Passphrase:".to_string(),
+        ];
+        if let Some(error) = error {
+            commands.push(format!("SETERROR {}", assuan_escape(error)));
+        }
+        for command in commands {
+            writeln!(input, "{}", command)?;
+            assuan_response(&mut output)?;
+        }
+        writeln!(input, "GETPIN")?;
+        assuan_response(&mut output)
+    };
+    let result = converse();
+
+    let _ = writeln!(input, "BYE");
+    drop(input);
+    let _ = child.wait();
+    result
+}
+
+// The data lines up to the next OK, or the ERR as an error
+fn assuan_response(output: &mut dyn BufRead) -> crate::Result<Zeroizing<String>> {
+    let mut data = Zeroizing::new(String::new());
+    loop {
+        let mut line = Zeroizing::new(String::new());
+        if output.read_line(&mut line)? == 0 {
+            return Err(Error::Invalid("pinentry exited early".to_string()));
+        }
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(data);
+        }
+        if let Some(err) = line.strip_prefix("ERR ") {
+            // "<code> <description>", e.g. when the dialog was cancelled
+            let description = err.split_once(' ').map_or(err, |(_, d)| d);
+            return Err(Error::Invalid(format!("pinentry: {}", description)));
+        }
+        if let Some(chunk) = line.strip_prefix("D ") {
+            data.push_str(&assuan_unescape(chunk));
+        }
+        // status lines (S) and comments (#) don't matter here
+    }
+}
+
+fn assuan_escape(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn assuan_unescape(text: &str) -> Zeroizing<String> {
+    let mut bytes = Zeroizing::new(Vec::with_capacity(text.len()));
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    Zeroizing::new(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_from_a_descriptor_leaves_it_open() {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends of the pipe
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read, write] = fds;
+        // SAFETY: `write` is the pipe's write end, `fds` owns nothing else
+        let mut writer = unsafe { <std::fs::File as std::os::fd::FromRawFd>::from_raw_fd(write) };
+        writer.write_all(b"hunter2\n").unwrap();
+        drop(writer);
+
+        let mut reader = BufReader::new(open_fd(read).unwrap());
+        assert_eq!(read_line(&mut reader).unwrap().as_str(), "hunter2");
+        drop(reader);
+        // SAFETY: F_GETFD only checks the descriptor is open
+        assert_ne!(unsafe { libc::fcntl(read, libc::F_GETFD) }, -1);
+
+        assert!(open_fd(-1).is_err());
+        // SAFETY: `read` is still ours to close
+        unsafe { libc::close(read) };
+    }
+}
@@ -0,0 +1,79 @@
+// Local identities and which one is active
+//
+// A profile is a user with a keyring on this machine, at
+// <data dir>/<user>/keyring.enc. Directories without a keyring hold someone
+// else's public key and queues and aren't profiles. The active profile's
+// username is kept in <data dir>/me; commands act as it unless `--as <user>`
+// says otherwise.
+
+use crate::config::Paths;
+use crate::crypto::keyring::KeyringEncryptor;
+use crate::error::Error;
+
+pub fn exists(paths: &Paths, username: &str) -> bool {
+    crate::relay::valid_username(username) && KeyringEncryptor::path(paths, username).exists()
+}
+
+// Usernames with a keyring on this machine, sorted
+pub fn list(paths: &Paths) -> crate::Result<Vec<String>> {
+    let mut profiles = Vec::new();
+    let entries = match std::fs::read_dir(paths.data_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(profiles),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(username) = entry.file_name().to_str() {
+            if exists(paths, username) {
+                profiles.push(username.to_string());
+            }
+        }
+    }
+    profiles.sort();
+    Ok(profiles)
+}
+
+// The active profile's username
+pub fn active(paths: &Paths) -> crate::Result<String> {
+    match std::fs::read_to_string(paths.active_profile()) {
+        Ok(username) => Ok(username.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::NoActiveProfile),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn set_active(paths: &Paths, username: &str) -> crate::Result<()> {
+    if !exists(paths, username) {
+        return Err(Error::UnknownProfile(username.to_string()));
+    }
+    Ok(crate::util::write_atomic(
+        &paths.active_profile(),
+        username.as_bytes(),
+    )?)
+}
+
+// `username` if given (from `--as`), otherwise the active profile
+pub fn resolve(paths: &Paths, username: Option<&str>) -> crate::Result<String> {
+    match username {
+        Some(username) if exists(paths, username) => Ok(username.to_string()),
+        Some(username) => Err(Error::UnknownProfile(username.to_string())),
+        None => active(paths),
+    }
+}
+
+// Delete `username`'s keyring, sessions, contacts and mailbox. If it was the
+// active profile there's no active profile afterwards.
+pub fn remove(paths: &Paths, username: &str) -> crate::Result<()> {
+    if !exists(paths, username) {
+        return Err(Error::UnknownProfile(username.to_string()));
+    }
+    std::fs::remove_dir_all(paths.user(username))?;
+    if active(paths).ok().as_deref() == Some(username) {
+        std::fs::remove_file(paths.active_profile())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,216 @@
+// Protocol spoken between clients and a rune-server relay
+//
+// A relay is a store-and-forward queue of envelopes keyed by recipient
+// username. Clients open a TCP connection and exchange frames, each a
+// big-endian u32 length followed by a bincode encoded `Request` or
+// `Response`. Every request gets exactly one response, and a connection can
+// carry any number of requests.
+//
+// The relay only ever sees envelopes, which are already encrypted and signed
+// end to end, so it doesn't need to be trusted with message contents. It also
+// keeps the username directory and users' prekey bundles, see
+// `crate::directory`. Anyone can deliver, but fetching or acknowledging a
+// user's messages, or publishing their prekeys, takes a connection
+// authenticated as them, by signing a challenge with their registered key.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+// Largest frame either side will accept
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+// Roughly how much one fetch returns, well inside a frame
+pub const FETCH_BATCH_LEN: u64 = 8 * 1024 * 1024;
+
+// The first of `entries`, oldest first, that make up a batch for `Fetch`
+pub fn fetch_batch<T>(entries: Vec<T>, size: impl Fn(&T) -> u64) -> Vec<T> {
+    let mut total = 0;
+    entries
+        .into_iter()
+        .take_while(|entry| {
+            let first = total == 0;
+            total += size(entry).max(1);
+            first || total <= FETCH_BATCH_LEN
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    // Queue `envelope` for `recipient`
+    Deliver {
+        recipient: String,
+        envelope: Vec<u8>,
+    },
+    // The oldest messages queued for `recipient`, up to about
+    // FETCH_BATCH_LEN bytes but at least one; fetch again after acking them
+    // for the rest
+    Fetch {
+        recipient: String,
+    },
+    // Delete fetched messages once they've been stored by the recipient
+    Ack {
+        recipient: String,
+        ids: Vec<String>,
+    },
+    // A fresh nonce for this connection to sign in a `Register` or
+    // `Authenticate` request
+    Challenge,
+    // Prove this connection is `username`'s, signed over the last challenge
+    // with the key registered for them, before `Fetch` or `Ack`
+    Authenticate {
+        username: String,
+        signature: Vec<u8>,
+    },
+    // Claim `username` for `identity`, signed over the last challenge
+    Register {
+        username: String,
+        identity: [u8; 32],
+        signature: Vec<u8>,
+    },
+    // The identity key registered for `username`
+    Lookup {
+        username: String,
+    },
+    // Publish a prekey bundle for `username`, replacing any earlier one.
+    // Takes a connection authenticated as them.
+    PublishPrekeys {
+        username: String,
+        bundle: Vec<u8>,
+    },
+    // `username`'s prekey bundle with at most one of its one-time prekeys,
+    // which the relay then drops so nobody else is given it
+    ClaimPrekeys {
+        username: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub id: String,
+    pub envelope: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Delivered { id: String },
+    Messages(Vec<QueuedMessage>),
+    Acked { removed: usize },
+    Challenge { nonce: [u8; 32] },
+    Authenticated,
+    Registered,
+    Identity { identity: [u8; 32] },
+    Published,
+    // None if they haven't published a bundle
+    Prekeys { bundle: Option<Vec<u8>> },
+    Error(String),
+}
+
+// Usernames double as directory names on both ends, so keep them simple
+pub fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= 64
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && !username.starts_with('.')
+}
+
+pub fn write_frame<T: Serialize>(stream: &mut impl Write, value: &T) -> std::io::Result<()> {
+    let body = bincode::serialize(value)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let len = u32::try_from(body.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+// Returns `Ok(None)` if the peer closed the connection between frames
+pub fn read_frame<T: DeserializeOwned>(stream: &mut impl Read) -> std::io::Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body)?;
+    bincode::deserialize(&body)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+// A connection to a relay
+pub struct RelayClient {
+    stream: TcpStream,
+}
+
+impl RelayClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+
+    pub fn request(&mut self, request: &Request) -> std::io::Result<Response> {
+        write_frame(&mut self.stream, request)?;
+        match read_frame(&mut self.stream)? {
+            Some(Response::Error(e)) => Err(std::io::Error::other(format!("relay: {}", e))),
+            Some(response) => Ok(response),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "relay closed the connection",
+            )),
+        }
+    }
+
+    pub fn deliver(&mut self, recipient: &str, envelope: &[u8]) -> std::io::Result<String> {
+        match self.request(&Request::Deliver {
+            recipient: recipient.to_string(),
+            envelope: envelope.to_vec(),
+        })? {
+            Response::Delivered { id } => Ok(id),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn fetch(&mut self, recipient: &str) -> std::io::Result<Vec<QueuedMessage>> {
+        match self.request(&Request::Fetch {
+            recipient: recipient.to_string(),
+        })? {
+            Response::Messages(messages) => Ok(messages),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn ack(&mut self, recipient: &str, ids: &[String]) -> std::io::Result<usize> {
+        match self.request(&Request::Ack {
+            recipient: recipient.to_string(),
+            ids: ids.to_vec(),
+        })? {
+            Response::Acked { removed } => Ok(removed),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+pub(crate) fn unexpected(response: Response) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("unexpected relay response {:?}", response),
+    )
+}
@@ -0,0 +1,445 @@
+// Per-contact Double Ratchet sessions
+//
+// Sessions are stored under <data dir>/<user>/sessions/<contact>.session,
+// encrypted with a key derived from the owner's identity key. A session is
+// started with an X3DH handshake against the contact's published prekey
+// bundle, whose signed prekey becomes the contact's initial ratchet key. For
+// contacts without a bundle we fall back to an ephemeral plus static-static
+// X25519 exchange with their identity key, and derive their initial ratchet
+// key from its result. The initiator attaches the handshake to every message until the
+// contact replies, so the session survives the first messages being lost or
+// reordered.
+//
+// A store can hold a few sessions per contact, most recently used first. That
+// resolves both sides starting a session at the same time: each side accepts
+// the other's session, trial decryption picks the right one for incoming
+// messages, and both converge on whichever session was last used.
+
+use std::path::PathBuf;
+
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::Scalar;
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::config::Paths;
+use crate::crypto::e2ee::CryptoError;
+use crate::crypto::keyring::Keyring;
+use crate::crypto::prekey::{self, PrekeyBundle, PrekeyIds, PrekeyStore};
+use crate::crypto::ratchet::{Ratchet, RatchetMessage};
+use crate::crypto::storage;
+use crate::envelope::{CipherSuite, Envelope};
+
+const MAX_SESSIONS: usize = 4;
+const STORAGE_PURPOSE: &[u8] = b"rune-session-storage";
+
+// Sent by the initiator until it hears back, so the contact can derive the session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInit {
+    pub ephemeral_public: [u8; 32],
+    pub header_encryption: bool,
+    // the contact's prekeys used for X3DH, or None for the identity key handshake
+    pub prekeys: Option<PrekeyIds>,
+}
+
+// Contents of `Envelope::header` for the `DoubleRatchet` suite
+#[derive(Serialize, Deserialize)]
+struct SessionHeader {
+    init: Option<SessionInit>,
+    ratchet_header: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    // the initiator's ephemeral key, identifying the session on both sides
+    id: [u8; 32],
+    pending_init: Option<SessionInit>,
+    associated_data: Vec<u8>,
+    ratchet: Ratchet,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct SessionStore {
+    sessions: Vec<Session>,
+}
+
+impl SessionStore {
+    pub fn path(paths: &Paths, owner: &str, contact: &str) -> PathBuf {
+        paths
+            .user(owner)
+            .join(format!("sessions/{}.session", contact))
+    }
+
+    pub fn exists(paths: &Paths, owner: &str, contact: &str) -> bool {
+        Self::path(paths, owner, contact).exists()
+    }
+
+    // Load the sessions `owner` has with `contact`, or an empty store if there are none yet
+    pub fn load(
+        paths: &Paths,
+        owner: &str,
+        keyring: &Keyring,
+        contact: &str,
+    ) -> crate::Result<Self> {
+        let path = Self::path(paths, owner, contact);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = std::fs::read(&path)?;
+        let plaintext = storage::open(keyring, STORAGE_PURPOSE, contact.as_bytes(), &data)?;
+        Ok(bincode::deserialize(&plaintext)?)
+    }
+
+    pub fn save(
+        &self,
+        paths: &Paths,
+        owner: &str,
+        keyring: &Keyring,
+        contact: &str,
+    ) -> crate::Result<()> {
+        let path = Self::path(paths, owner, contact);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        let plaintext = Zeroizing::new(bincode::serialize(self)?);
+        let data = storage::seal(keyring, STORAGE_PURPOSE, contact.as_bytes(), &plaintext)?;
+        log::debug!("writing session state to {}", path.display());
+        crate::util::write_atomic(&path, &data)?;
+        Ok(())
+    }
+
+    pub fn is_established(&self) -> bool {
+        !self.sessions.is_empty()
+    }
+
+    // Start a new session with `contact_public`. If they've published a prekey
+    // bundle, a one-time prekey is claimed from it and the caller must save the
+    // bundle back so it isn't reused.
+    pub fn initiate(
+        &mut self,
+        keyring: &Keyring,
+        contact_public: &EdwardsPoint,
+        bundle: Option<&mut PrekeyBundle>,
+        header_encryption: bool,
+    ) -> Result<(), CryptoError> {
+        let session = match bundle {
+            Some(bundle) => {
+                bundle.verify(contact_public)?;
+                let one_time = bundle.claim_one_time();
+                let handshake = prekey::initiate(keyring, bundle, one_time.as_ref())?;
+
+                Session::initiator(
+                    keyring,
+                    contact_public,
+                    &handshake.shared_secret,
+                    &handshake.ratchet_key,
+                    SessionInit {
+                        ephemeral_public: handshake.ephemeral_public,
+                        header_encryption,
+                        prekeys: Some(handshake.prekeys.clone()),
+                    },
+                )?
+            }
+            None => {
+                let mut bytes = [0u8; 32];
+                OsRng.fill_bytes(&mut bytes);
+                let ephemeral = Scalar::from_bytes_mod_order(bytes);
+                let ephemeral_public = (ephemeral
+                    * curve25519_dalek::constants::ED25519_BASEPOINT_POINT)
+                    .to_montgomery();
+
+                let contact = contact_public.to_montgomery();
+                let shared_secret =
+                    initial_secret(&(contact * ephemeral), &*keyring.diffie_hellman(&contact)?)?;
+                let ratchet_key = (initial_ratchet_secret(&shared_secret)
+                    * curve25519_dalek::constants::ED25519_BASEPOINT_POINT)
+                    .to_montgomery();
+
+                Session::initiator(
+                    keyring,
+                    contact_public,
+                    &shared_secret,
+                    ratchet_key.as_bytes(),
+                    SessionInit {
+                        ephemeral_public: ephemeral_public.to_bytes(),
+                        header_encryption,
+                        prekeys: None,
+                    },
+                )?
+            }
+        };
+
+        self.promote(session);
+        Ok(())
+    }
+
+    // Encrypt with the most recently used session; see `initiate` to start one
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Envelope, CryptoError> {
+        let session = self.sessions.first_mut().ok_or(CryptoError::NoSession)?;
+        let message = session
+            .ratchet
+            .encrypt(plaintext, &session.associated_data)?;
+        let header = SessionHeader {
+            init: session.pending_init.clone(),
+            ratchet_header: message.header,
+        };
+
+        Ok(Envelope::session(
+            bincode::serialize(&header).expect("header serialization is infallible"),
+            message.ciphertext,
+        ))
+    }
+
+    // `prekeys` is needed to accept a session the contact started with X3DH;
+    // a one-time prekey it consumes is removed, so save it afterwards
+    pub fn decrypt(
+        &mut self,
+        keyring: &Keyring,
+        prekeys: Option<&mut PrekeyStore>,
+        contact_public: &EdwardsPoint,
+        envelope: &Envelope,
+    ) -> Result<Vec<u8>, CryptoError> {
+        if envelope.suite != CipherSuite::DoubleRatchet {
+            return Err(CryptoError::NoSession);
+        }
+        let header: SessionHeader =
+            bincode::deserialize(&envelope.header).map_err(|_| CryptoError::Tampered)?;
+        let message = RatchetMessage {
+            header: header.ratchet_header,
+            ciphertext: envelope.ciphertext.clone(),
+        };
+
+        // a handshake we haven't seen before starts a new session with the contact
+        if let Some(init) = &header.init {
+            if !self.sessions.iter().any(|s| s.id == init.ephemeral_public) {
+                let mut session =
+                    Session::responder(keyring, prekeys.as_deref(), contact_public, init)?;
+                let plaintext = session
+                    .ratchet
+                    .decrypt(&message, &session.associated_data)?;
+
+                if let (Some(store), Some(ids)) = (prekeys, &init.prekeys) {
+                    if let Some(id) = ids.one_time {
+                        store.remove_one_time(id);
+                    }
+                }
+                self.promote(session);
+                return Ok(plaintext);
+            }
+        }
+
+        for index in 0..self.sessions.len() {
+            let session = &mut self.sessions[index];
+            if let Ok(plaintext) = session.ratchet.decrypt(&message, &session.associated_data) {
+                // hearing back means the contact has the session, stop sending the handshake
+                session.pending_init = None;
+                let session = self.sessions.remove(index);
+                self.promote(session);
+                return Ok(plaintext);
+            }
+        }
+
+        Err(CryptoError::NoSession)
+    }
+
+    fn promote(&mut self, session: Session) {
+        self.sessions.insert(0, session);
+        self.sessions.truncate(MAX_SESSIONS);
+    }
+}
+
+impl Session {
+    fn initiator(
+        keyring: &Keyring,
+        contact_public: &EdwardsPoint,
+        shared_secret: &[u8; 32],
+        ratchet_key: &[u8; 32],
+        init: SessionInit,
+    ) -> Result<Self, CryptoError> {
+        let ratchet = Ratchet::initiator(shared_secret, ratchet_key, init.header_encryption)?;
+
+        Ok(Self {
+            id: init.ephemeral_public,
+            pending_init: Some(init),
+            associated_data: associated_data(&keyring.public, contact_public),
+            ratchet,
+        })
+    }
+
+    fn responder(
+        keyring: &Keyring,
+        prekeys: Option<&PrekeyStore>,
+        contact_public: &EdwardsPoint,
+        init: &SessionInit,
+    ) -> Result<Self, CryptoError> {
+        let (shared_secret, ratchet_secret) = match &init.prekeys {
+            Some(ids) => {
+                let store = prekeys.ok_or(CryptoError::MissingPrekey)?;
+                prekey::respond(keyring, store, contact_public, &init.ephemeral_public, ids)?
+            }
+            None => {
+                let ephemeral = MontgomeryPoint(init.ephemeral_public);
+                let shared_secret = initial_secret(
+                    &*keyring.diffie_hellman(&ephemeral)?,
+                    &*keyring.diffie_hellman(&contact_public.to_montgomery())?,
+                )?;
+                (shared_secret, initial_ratchet_secret(&shared_secret))
+            }
+        };
+        let ratchet = Ratchet::responder(&shared_secret, &ratchet_secret, init.header_encryption);
+
+        Ok(Self {
+            id: init.ephemeral_public,
+            pending_init: None,
+            associated_data: associated_data(contact_public, &keyring.public),
+            ratchet,
+        })
+    }
+}
+
+// Both identity keys, initiator first
+fn associated_data(initiator: &EdwardsPoint, responder: &EdwardsPoint) -> Vec<u8> {
+    let mut ad = initiator.compress().to_bytes().to_vec();
+    ad.extend_from_slice(responder.compress().as_bytes());
+    ad
+}
+
+fn initial_secret(
+    ephemeral_static: &MontgomeryPoint,
+    static_static: &MontgomeryPoint,
+) -> Result<[u8; 32], CryptoError> {
+    for dh in [ephemeral_static, static_static] {
+        if dh.as_bytes() == &[0u8; 32] {
+            return Err(CryptoError::InvalidKey);
+        }
+    }
+
+    let mut ikm = [0xffu8; 32].to_vec();
+    ikm.extend_from_slice(ephemeral_static.as_bytes());
+    ikm.extend_from_slice(static_static.as_bytes());
+
+    let mut secret = [0u8; 32];
+    Hkdf::<Sha256>::new(None, &ikm)
+        .expand(b"rune-session-init", &mut secret)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Ok(secret)
+}
+
+// The responder's first ratchet key in a session started without prekeys.
+// Both sides can work it out from the handshake, and unlike the identity key
+// it's different for every session and doesn't take a secret rune-agent
+// won't hand out.
+fn initial_ratchet_secret(shared_secret: &[u8; 32]) -> Scalar {
+    let mut bytes = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand(b"rune-session-ratchet", &mut *bytes)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(store: &SessionStore) -> Vec<u8> {
+        bincode::serialize(store).unwrap()
+    }
+
+    fn decrypt(
+        store: &mut SessionStore,
+        keyring: &Keyring,
+        from: &Keyring,
+        envelope: &Envelope,
+    ) -> Result<Vec<u8>, CryptoError> {
+        store.decrypt(keyring, None, &from.public, envelope)
+    }
+
+    #[test]
+    fn handshake_is_sent_until_the_contact_replies() {
+        for header_encryption in [false, true] {
+            let (alice, bob) = (Keyring::generate(), Keyring::generate());
+            let (mut alices, mut bobs) = (SessionStore::default(), SessionStore::default());
+            alices
+                .initiate(&alice, &bob.public, None, header_encryption)
+                .unwrap();
+
+            // the first message is lost, the second still starts the session
+            let _lost = alices.encrypt(b"one").unwrap();
+            let two = alices.encrypt(b"two").unwrap();
+            assert_eq!(decrypt(&mut bobs, &bob, &alice, &two).unwrap(), b"two");
+
+            let reply = bobs.encrypt(b"three").unwrap();
+            assert_eq!(
+                decrypt(&mut alices, &alice, &bob, &reply).unwrap(),
+                b"three"
+            );
+            assert!(alices.sessions[0].pending_init.is_none());
+            let four = alices.encrypt(b"four").unwrap();
+            let header: SessionHeader = bincode::deserialize(&four.header).unwrap();
+            assert!(header.init.is_none());
+            assert_eq!(decrypt(&mut bobs, &bob, &alice, &four).unwrap(), b"four");
+        }
+    }
+
+    #[test]
+    fn tampered_and_replayed_envelopes_change_nothing() {
+        let (alice, bob) = (Keyring::generate(), Keyring::generate());
+        let (mut alices, mut bobs) = (SessionStore::default(), SessionStore::default());
+        alices.initiate(&alice, &bob.public, None, true).unwrap();
+        let hello = alices.encrypt(b"hello").unwrap();
+
+        // a forged first message doesn't leave a session behind
+        let mut tampered = alices.encrypt(b"forged").unwrap();
+        tampered.ciphertext[0] ^= 1;
+        assert!(decrypt(&mut bobs, &bob, &alice, &tampered).is_err());
+        assert!(!bobs.is_established());
+
+        decrypt(&mut bobs, &bob, &alice, &hello).unwrap();
+        let before = state(&bobs);
+        assert!(decrypt(&mut bobs, &bob, &alice, &tampered).is_err());
+        assert!(matches!(
+            decrypt(&mut bobs, &bob, &alice, &hello),
+            Err(CryptoError::NoSession)
+        ));
+        assert_eq!(state(&bobs), before);
+
+        let next = alices.encrypt(b"next").unwrap();
+
+        assert_eq!(decrypt(&mut bobs, &bob, &alice, &next).unwrap(), b"next");
+    }
+
+    #[test]
+    fn simultaneous_starts_converge() {
+        let (alice, bob) = (Keyring::generate(), Keyring::generate());
+        let (mut alices, mut bobs) = (SessionStore::default(), SessionStore::default());
+        alices.initiate(&alice, &bob.public, None, false).unwrap();
+        bobs.initiate(&bob, &alice.public, None, false).unwrap();
+
+        let from_alice = alices.encrypt(b"hi bob").unwrap();
+        let from_bob = bobs.encrypt(b"hi alice").unwrap();
+        assert_eq!(
+            decrypt(&mut bobs, &bob, &alice, &from_alice).unwrap(),
+            b"hi bob"
+        );
+        assert_eq!(
+            decrypt(&mut alices, &alice, &bob, &from_bob).unwrap(),
+            b"hi alice"
+        );
+        assert_eq!(alices.sessions.len(), 2);
+
+        // both end up on the session each last received on, and keep talking
+        for _ in 0..2 {
+            let message = alices.encrypt(b"ping").unwrap();
+            assert_eq!(decrypt(&mut bobs, &bob, &alice, &message).unwrap(), b"ping");
+            let message = bobs.encrypt(b"pong").unwrap();
+            assert_eq!(
+                decrypt(&mut alices, &alice, &bob, &message).unwrap(),
+                b"pong"
+            );
+        }
+    }
+}
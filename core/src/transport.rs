@@ -0,0 +1,252 @@
+// How envelopes get from a sender to the recipient's mailbox
+//
+// A `Transport` queues envelopes for recipients until they fetch and
+// acknowledge them. Fetched envelopes are moved into the recipient's local
+// `Mailbox` by `fetch_into_mailbox`, and everything after that (listing,
+// verifying, decrypting) works the same whichever transport delivered them.
+//
+// - `FsTransport` queues into <data dir>/<user>/incoming, for users sharing
+//   a data directory
+// - `RelayClient` talks to a rune-server relay
+// - `MemoryTransport` keeps queues in memory, for tests
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{Config, Paths};
+use crate::crypto::keyring::Keyring;
+use crate::mailbox::{Mailbox, MessageId};
+use crate::relay::{fetch_batch, valid_username, QueuedMessage, RelayClient, DEFAULT_ADDR};
+
+pub trait Transport {
+    // Queue `envelope` for `recipient`, returning the queued message id
+    fn deliver(&mut self, recipient: &str, envelope: &[u8]) -> std::io::Result<String>;
+    // The oldest messages queued for `recipient`, a batch of about
+    // FETCH_BATCH_LEN bytes at a time (see `Request::Fetch`)
+    fn fetch(&mut self, recipient: &str) -> std::io::Result<Vec<QueuedMessage>>;
+    // Drop fetched messages from the queue
+    fn ack(&mut self, recipient: &str, ids: &[String]) -> std::io::Result<()>;
+    // Prove we're `username` before fetching or acknowledging their
+    // messages, for transports that need to know
+    fn authenticate(&mut self, _username: &str, _keyring: &Keyring) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Move everything queued for `owner` into their mailbox, a batch at a time,
+// returning how many messages arrived. Messages are only acknowledged once
+// they're stored.
+pub fn fetch_into_mailbox(
+    transport: &mut dyn Transport,
+    paths: &Paths,
+    owner: &str,
+    keyring: &Keyring,
+) -> std::io::Result<usize> {
+    transport.authenticate(owner, keyring)?;
+    let mut fetched = HashSet::new();
+    loop {
+        let pending = transport.fetch(owner)?;
+        // a transport that ignores acks would otherwise keep us here forever
+        if pending.iter().all(|message| fetched.contains(&message.id)) {
+            break;
+        }
+
+        let mailbox = Mailbox::open(paths, owner)?;
+        let mut ids = Vec::new();
+        for message in pending {
+            if fetched.insert(message.id.clone()) {
+                mailbox.deliver(&message.envelope)?;
+            }
+            ids.push(message.id);
+        }
+        transport.ack(owner, &ids)?;
+    }
+    log::debug!("fetched {} messages for {}", fetched.len(), owner);
+    Ok(fetched.len())
+}
+
+fn invalid_username(username: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid username {:?}", username),
+    )
+}
+
+pub struct FsTransport {
+    root: PathBuf,
+}
+
+impl FsTransport {
+    // Queue under the data directory in `paths`
+    pub fn new(paths: &Paths) -> Self {
+        Self::at(paths.data_dir())
+    }
+
+    // Queue under `root`/<user>/incoming
+    pub fn at(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn queue(&self, username: &str) -> std::io::Result<Mailbox> {
+        if !valid_username(username) {
+            return Err(invalid_username(username));
+        }
+        Mailbox::open_at(self.root.join(username).join("incoming"))
+    }
+}
+
+impl Transport for FsTransport {
+    fn deliver(&mut self, recipient: &str, envelope: &[u8]) -> std::io::Result<String> {
+        Ok(self.queue(recipient)?.deliver(envelope)?.to_string())
+    }
+
+    fn fetch(&mut self, recipient: &str) -> std::io::Result<Vec<QueuedMessage>> {
+        let queue = self.queue(recipient)?;
+        let mut messages = Vec::new();
+        for entry in fetch_batch(queue.unread()?, |entry| entry.size) {
+            messages.push(QueuedMessage {
+                envelope: queue.read(&entry)?,
+                id: entry.id.to_string(),
+            });
+        }
+        Ok(messages)
+    }
+
+    fn ack(&mut self, recipient: &str, ids: &[String]) -> std::io::Result<()> {
+        let queue = self.queue(recipient)?;
+        for id in ids {
+            let id: MessageId = id.parse()?;
+            queue.remove(&queue.get(&id)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for RelayClient {
+    fn deliver(&mut self, recipient: &str, envelope: &[u8]) -> std::io::Result<String> {
+        RelayClient::deliver(self, recipient, envelope)
+    }
+
+    fn fetch(&mut self, recipient: &str) -> std::io::Result<Vec<QueuedMessage>> {
+        RelayClient::fetch(self, recipient)
+    }
+
+    fn ack(&mut self, recipient: &str, ids: &[String]) -> std::io::Result<()> {
+        RelayClient::ack(self, recipient, ids).map(|_| ())
+    }
+
+    fn authenticate(&mut self, username: &str, keyring: &Keyring) -> std::io::Result<()> {
+        RelayClient::authenticate(self, username, keyring)
+    }
+}
+
+// Clones share the same queues, so a sender and a recipient in one process
+// can each hold their own handle
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    queues: Arc<Mutex<HashMap<String, Vec<QueuedMessage>>>>,
+    next_id: Arc<Mutex<u64>>,
+}
+
+impl Transport for MemoryTransport {
+    fn deliver(&mut self, recipient: &str, envelope: &[u8]) -> std::io::Result<String> {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            format!("{:016x}", *next_id)
+        };
+        self.queues
+            .lock()
+            .unwrap()
+            .entry(recipient.to_string())
+            .or_default()
+            .push(QueuedMessage {
+                id: id.clone(),
+                envelope: envelope.to_vec(),
+            });
+        Ok(id)
+    }
+
+    fn fetch(&mut self, recipient: &str) -> std::io::Result<Vec<QueuedMessage>> {
+        Ok(self
+            .queues
+            .lock()
+            .unwrap()
+            .get(recipient)
+            .map(|queue| fetch_batch(queue.clone(), |m| m.envelope.len() as u64))
+            .unwrap_or_default())
+    }
+
+    fn ack(&mut self, recipient: &str, ids: &[String]) -> std::io::Result<()> {
+        if let Some(queue) = self.queues.lock().unwrap().get_mut(recipient) {
+            queue.retain(|m| !ids.contains(&m.id));
+        }
+        Ok(())
+    }
+}
+
+// Which transport to use, from `--transport`, $RUNE_TRANSPORT or rune.toml:
+// "fs" (the default), "relay" for a relay on `DEFAULT_ADDR` or "relay:<addr>"
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum TransportSpec {
+    #[default]
+    Fs,
+    Relay(String),
+}
+
+impl TransportSpec {
+    pub const ENV: &'static str = "RUNE_TRANSPORT";
+
+    // `flag` if given, otherwise $RUNE_TRANSPORT, otherwise the one in
+    // `config`, otherwise the filesystem
+    pub fn resolve(flag: Option<&str>, config: &Config) -> crate::Result<Self> {
+        match flag
+            .map(str::to_string)
+            .or_else(|| std::env::var(Self::ENV).ok())
+        {
+            Some(spec) => spec.parse(),
+            None => Ok(config.transport.clone().unwrap_or_default()),
+        }
+    }
+
+    pub fn open(&self, paths: &Paths) -> std::io::Result<Box<dyn Transport>> {
+        match self {
+            TransportSpec::Fs => Ok(Box::new(FsTransport::new(paths))),
+            TransportSpec::Relay(addr) => Ok(Box::new(RelayClient::connect(addr.as_str())?)),
+        }
+    }
+
+    // Where to register usernames and look up keys, if anywhere
+    pub fn directory(&self) -> Option<&str> {
+        match self {
+            TransportSpec::Fs => None,
+            TransportSpec::Relay(addr) => Some(addr),
+        }
+    }
+}
+
+impl std::str::FromStr for TransportSpec {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "fs" => Ok(TransportSpec::Fs),
+            _ if s == "relay" => Ok(TransportSpec::Relay(DEFAULT_ADDR.to_string())),
+            Some(("relay", addr)) if !addr.is_empty() => Ok(TransportSpec::Relay(addr.to_string())),
+            _ => Err(crate::Error::Invalid(format!(
+                "unknown transport {:?}, expected fs, relay or relay:<addr>",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for TransportSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportSpec::Fs => write!(f, "fs"),
+            TransportSpec::Relay(addr) => write!(f, "relay:{}", addr),
+        }
+    }
+}
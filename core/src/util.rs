@@ -0,0 +1,29 @@
+use std::io::Write;
+use std::path::Path;
+
+// Write `bytes` to a temporary file next to `path` and rename it into place,
+// so readers (and a crash mid-write) only ever see the old or the new contents
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    use rand::{rngs::OsRng, RngCore};
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no file name"))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{:016x}.tmp",
+        file_name.to_string_lossy(),
+        OsRng.next_u64()
+    ));
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
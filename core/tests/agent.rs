@@ -0,0 +1,127 @@
+#![cfg(unix)]
+
+use std::time::Duration;
+
+use rune_core::agent::Agent;
+use rune_core::armor::Armored;
+use rune_core::cmd::inbox::AfterRead;
+use rune_core::crypto::prekey::PrekeyBundle;
+use rune_core::transport::MemoryTransport;
+use rune_core::{Error, RuneClient};
+
+mod common;
+use common::TestHome;
+
+// Run an agent for `home` in the background, once it's listening
+fn start_agent(home: &TestHome) {
+    let agent = Agent::new(home.paths(), Duration::from_secs(60));
+    std::thread::spawn(move || agent.run());
+    while !home.paths().agent_socket().exists() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// The text of `sender`'s unread messages to `owner`, read through `client`
+fn read_from(client: &RuneClient, owner: &rune_core::client::Profile, sender: &str) -> Vec<String> {
+    let pending = client.unread_from(owner.username(), sender).unwrap();
+    client
+        .read(owner, pending, AfterRead::MarkRead)
+        .unwrap()
+        .received
+        .into_iter()
+        .map(|msg| msg.body.to_string())
+        .collect()
+}
+
+#[test]
+fn agent_holds_keyrings_without_handing_out_secrets() {
+    let home = TestHome::new("agent");
+    let mut client = home.client();
+    common::register(&client, "alice");
+    common::register(&client, "bob");
+
+    assert!(client.unlock_from_agent("alice").is_none());
+    start_agent(&home);
+    let socket = home.paths().agent_socket();
+
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let mut agent = client.agent().unwrap();
+    assert!(matches!(
+        agent.unlock("alice", "bob's passphrase", None),
+        Err(Error::BadPassphrase)
+    ));
+    // turned down, and the agent carries on
+    assert!(matches!(
+        agent.unlock("alice", "alice's passphrase", Some(Duration::MAX)),
+        Err(Error::Agent(_))
+    ));
+    agent.unlock("alice", "alice's passphrase", None).unwrap();
+    agent
+        .unlock("bob", "bob's passphrase", Some(Duration::from_secs(5)))
+        .unwrap();
+    let status = agent.status().unwrap();
+    assert_eq!(status.len(), 2);
+    assert!(status[0].username == "alice" && status[0].expires_in > 5);
+
+    // signing, the handshake and reading all go through the agent
+    let alice = client.unlock_from_agent("alice").unwrap();
+    let bob = client.unlock_from_agent("bob").unwrap();
+    assert!(alice.keyring().secret().is_none() && bob.keyring().secret().is_none());
+    client.send(&alice, "bob", "hello bob", false).unwrap();
+    client.fetch(&bob).unwrap();
+    assert_eq!(read_from(&client, &bob, "alice"), ["hello bob"]);
+
+    assert_eq!(agent.lock(Some("bob")).unwrap(), 1);
+    assert!(client.unlock_from_agent("bob").is_none());
+    // a keyring that was locked in the meantime can't be used any more
+    assert!(matches!(
+        client.send(&bob, "alice", "still there?", false),
+        Err(Error::Agent(_))
+    ));
+    assert_eq!(agent.lock(None).unwrap(), 1);
+    assert!(agent.status().unwrap().is_empty());
+}
+
+#[test]
+fn sessions_without_prekeys_are_accepted_through_the_agent() {
+    // alice and bob on machines of their own, bob with no prekeys published
+    let transport = MemoryTransport::default();
+    let (alice_home, bob_home) = (TestHome::new("agent-alice"), TestHome::new("agent-bob"));
+    let mut alices = alice_home
+        .client()
+        .with_transport(Box::new(transport.clone()));
+    let mut bobs = bob_home.client().with_transport(Box::new(transport));
+    let alice = common::register(&alices, "alice");
+    common::register(&bobs, "bob");
+    let bob_key = Armored::decode(&bobs.export_public("bob").unwrap()).unwrap();
+    alices.import_public(&bob_key, None, false).unwrap();
+    let alice_key = Armored::decode(&alices.export_public("alice").unwrap()).unwrap();
+    bobs.import_public(&alice_key, None, false).unwrap();
+    // only bob's identity key made it to alice's machine
+    assert!(PrekeyBundle::load(&alice_home.paths(), "bob")
+        .unwrap()
+        .is_none());
+
+    start_agent(&bob_home);
+    bobs.agent()
+        .unwrap()
+        .unlock("bob", &common::passphrase("bob"), None)
+        .unwrap();
+    let bob = bobs.unlock_from_agent("bob").unwrap();
+    assert!(bob.keyring().secret().is_none());
+
+    alices.send(&alice, "bob", "hello bob", false).unwrap();
+    bobs.fetch(&bob).unwrap();
+    assert_eq!(read_from(&bobs, &bob, "alice"), ["hello bob"]);
+
+    // and the session carries on in both directions
+    bobs.send(&bob, "alice", "hello alice", false).unwrap();
+    alices.fetch(&alice).unwrap();
+    assert_eq!(read_from(&alices, &alice, "bob"), ["hello alice"]);
+    alices.send(&alice, "bob", "bye", false).unwrap();
+    bobs.fetch(&bob).unwrap();
+    assert_eq!(read_from(&bobs, &bob, "alice"), ["bye"]);
+}
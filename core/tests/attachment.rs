@@ -0,0 +1,89 @@
+use rune_core::attachment::PART_LEN;
+use rune_core::cmd::inbox::AfterRead;
+use rune_core::envelope::{CipherSuite, Envelope};
+use rune_core::mailbox::Mailbox;
+use rune_core::Error;
+
+mod common;
+use common::TestHome;
+
+#[test]
+fn files_round_trip_in_parts() {
+    let home = TestHome::new("attachment");
+    let out = home.join("out");
+    std::fs::create_dir_all(&out).unwrap();
+    let mut client = home.client();
+    let alice = common::register(&client, "alice");
+    let bob = common::register(&client, "bob");
+
+    // a few parts, the last of them short
+    let data: Vec<u8> = (0..PART_LEN * 2 + 12345)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect();
+    for name in ["report.bin", "truncated.bin"] {
+        let path = home.join(name);
+        std::fs::write(&path, &data).unwrap();
+        let sent = client.send_file(&alice, "bob", &path, false).unwrap();
+        assert_eq!((sent.size, sent.parts), (data.len() as u64, 3));
+    }
+    client.fetch(&bob).unwrap();
+
+    // only the attachment messages are listed, not their parts
+    let pending = client.unread_from("bob", "alice").unwrap();
+    assert_eq!(pending.len(), 2);
+    let received = client
+        .read(&bob, pending, AfterRead::MarkRead)
+        .unwrap()
+        .received;
+    let attachment = received[0].attachment.as_ref().unwrap();
+    assert_eq!(
+        received[0].body.as_str(),
+        format!("sent report.bin ({} bytes)", data.len())
+    );
+
+    let saved = client.save_attachment("bob", attachment, &out).unwrap();
+    assert_eq!(saved, out.join("report.bin"));
+    assert_eq!(std::fs::read(&saved).unwrap(), data);
+    assert!(matches!(
+        client.save_attachment("bob", attachment, &out),
+        Err(Error::Invalid(_))
+    ));
+
+    // losing a part of the second file means it can't be saved, and nothing
+    // is left behind
+    let mailbox = Mailbox::open(&home.paths(), "bob").unwrap();
+    let part = mailbox
+        .list()
+        .unwrap()
+        .into_iter()
+        .find(|entry| {
+            let envelope = Envelope::from_bytes(&mailbox.read(entry).unwrap()).unwrap();
+            envelope.suite == CipherSuite::FileChunk
+        })
+        .unwrap();
+    mailbox.remove(&part).unwrap();
+    let truncated = received[1].attachment.as_ref().unwrap();
+    assert!(matches!(
+        client.save_attachment("bob", truncated, &out),
+        Err(Error::Malformed(_))
+    ));
+    assert_eq!(std::fs::read_dir(&out).unwrap().count(), 1);
+}
+
+#[test]
+fn text_is_never_taken_for_an_attachment() {
+    let home = TestHome::new("content-type");
+    let mut client = home.client();
+    let alice = common::register(&client, "alice");
+    let bob = common::register(&client, "bob");
+
+    // what a file message used to start with
+    let lookalike = "\0RFA and then some";
+    client.send(&alice, "bob", lookalike, false).unwrap();
+    client.fetch(&bob).unwrap();
+    let pending = client.unread_from("bob", "alice").unwrap();
+    let opened = client.read(&bob, pending, AfterRead::MarkRead).unwrap();
+    assert!(opened.skipped.is_empty());
+    assert_eq!(opened.received[0].body.as_str(), lookalike);
+    assert!(opened.received[0].attachment.is_none());
+}
@@ -0,0 +1,66 @@
+// Fixtures shared by the integration tests. Each test file only uses some of
+// them.
+#![allow(dead_code)]
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use rune_core::client::Profile;
+use rune_core::config::{Config, Paths};
+use rune_core::transport::{MemoryTransport, TransportSpec};
+use rune_core::RuneClient;
+
+// A RUNE_HOME of its own for one test, deleted when it goes out of scope so
+// a failing test doesn't leave it behind
+pub struct TestHome {
+    path: PathBuf,
+}
+
+impl TestHome {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rune-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        // keep the KDF cheap, registering and unlocking both run it
+        std::fs::write(
+            path.join("rune.toml"),
+            "[kdf]\nm_cost = 8\nt_cost = 1\np_cost = 1\n",
+        )
+        .unwrap();
+        Self { path }
+    }
+
+    pub fn paths(&self) -> Paths {
+        Paths::at(&self.path)
+    }
+
+    // A client for this home that delivers through memory
+    pub fn client(&self) -> RuneClient {
+        let config = Config::load(self.paths()).unwrap();
+        RuneClient::new(config, TransportSpec::Fs)
+            .with_transport(Box::new(MemoryTransport::default()))
+    }
+}
+
+impl Deref for TestHome {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestHome {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn passphrase(username: &str) -> String {
+    format!("{}'s passphrase", username)
+}
+
+// Register `username` under `passphrase(username)`
+pub fn register(client: &RuneClient, username: &str) -> Profile {
+    client.register(username, &passphrase(username)).unwrap()
+}
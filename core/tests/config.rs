@@ -0,0 +1,32 @@
+use rune_core::config::{Paths, HOME_ENV};
+
+// The only test in this file, since it changes the environment
+#[test]
+fn old_installs_keep_their_data_in_the_config_dir() {
+    let home = std::env::temp_dir().join(format!("rune-config-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&home);
+    let old = home.join(".config/rune");
+    std::fs::create_dir_all(old.join("alice")).unwrap();
+    std::env::set_var("HOME", &home);
+    for var in [HOME_ENV, "XDG_CONFIG_HOME", "XDG_DATA_HOME"] {
+        std::env::remove_var(var);
+    }
+
+    // a directory without a keyring isn't a user
+    let paths = Paths::from_env().unwrap();
+    assert_eq!(paths.data_dir(), home.join(".local/share/rune"));
+
+    // as left by versions that never wrote an active profile
+    std::fs::write(old.join("alice/keyring.enc"), b"keyring").unwrap();
+    let paths = Paths::from_env().unwrap();
+    assert_eq!(paths.config_dir(), old);
+    assert_eq!(paths.data_dir(), old);
+    assert!(paths.user("alice").join("keyring.enc").exists());
+
+    // until something is written to the new data directory
+    std::fs::create_dir_all(home.join(".local/share/rune")).unwrap();
+    let paths = Paths::from_env().unwrap();
+    assert_eq!(paths.data_dir(), home.join(".local/share/rune"));
+
+    std::fs::remove_dir_all(home).unwrap();
+}
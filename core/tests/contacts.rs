@@ -0,0 +1,68 @@
+use rune_core::cmd::inbox::{AfterRead, Verification};
+use rune_core::crypto::keyring::Keyring;
+use rune_core::Error;
+
+mod common;
+use common::TestHome;
+
+#[test]
+fn pinned_contacts_need_no_local_key() {
+    let home = TestHome::new("contacts");
+    let paths = home.paths();
+    let mut client = home.client();
+    let alice = common::register(&client, "alice");
+    let bob = common::register(&client, "bob");
+
+    assert!(matches!(
+        client.send(&bob, "carol", "hello?", false),
+        Err(Error::UnknownContact(_))
+    ));
+    assert!(matches!(
+        client.unread_from("bob", "carol"),
+        Err(Error::UnknownContact(_))
+    ));
+
+    // bob has alice pinned, so her public-key.pub isn't needed any more
+    client
+        .add_contact("bob", "alice", None, None, false)
+        .unwrap();
+    std::fs::remove_file(Keyring::public_key_path(&paths, "alice")).unwrap();
+
+    client.send(&bob, "alice", "still there?", false).unwrap();
+    client.send(&alice, "bob", "yes", false).unwrap();
+    client.fetch(&bob).unwrap();
+    let pending = client.unread_from("bob", "alice").unwrap();
+    let received = client
+        .read(&bob, pending, AfterRead::MarkRead)
+        .unwrap()
+        .received;
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body.as_str(), "yes");
+}
+
+#[test]
+fn messages_under_a_changed_key_are_skipped() {
+    let home = TestHome::new("changed");
+    let mut client = home.client();
+    let alice = common::register(&client, "alice");
+    let bob = common::register(&client, "bob");
+
+    // bob pinned some other key for alice
+    let other = Keyring::generate();
+    client
+        .add_contact("bob", "alice", None, Some(&other.public), false)
+        .unwrap();
+
+    client.send(&alice, "bob", "it's me", false).unwrap();
+    client.fetch(&bob).unwrap();
+    let pending = client.unread_from("bob", "alice").unwrap();
+    let opened = client.read(&bob, pending, AfterRead::MarkRead).unwrap();
+    assert!(opened.received.is_empty());
+    assert_eq!(opened.skipped.len(), 1);
+    assert_eq!(opened.skipped[0].verification, Verification::KeyChanged);
+    assert!(opened.skipped[0]
+        .to_string()
+        .contains("rune contact add --force alice"));
+    // left unread, to read once the new key is trusted
+    assert_eq!(client.inbox("bob", false).unwrap().len(), 1);
+}
@@ -0,0 +1,157 @@
+use rune_core::client::Profile;
+use rune_core::cmd::inbox::{AfterRead, ReceivedMessage};
+use rune_core::{Error, RuneClient};
+
+mod common;
+use common::TestHome;
+
+fn read_all(client: &mut RuneClient, profile: &Profile) -> Vec<ReceivedMessage> {
+    client.fetch(profile).unwrap();
+    let items = client.inbox(profile.username(), false).unwrap();
+    client
+        .read(profile, items, AfterRead::MarkRead)
+        .unwrap()
+        .received
+}
+
+fn bodies(received: &[ReceivedMessage]) -> Vec<(&str, &str)> {
+    received
+        .iter()
+        .map(|msg| (msg.sender.as_str(), msg.body.as_str()))
+        .collect()
+}
+
+#[test]
+fn groups_rotate_keys_when_members_leave() {
+    let home = TestHome::new("group");
+    let mut client = home.client();
+    let alice = common::register(&client, "alice");
+    let bob = common::register(&client, "bob");
+    let carol = common::register(&client, "carol");
+
+    let info = client
+        .create_group(&alice, "team", &["bob", "carol"])
+        .unwrap();
+    assert_eq!((info.epoch, info.admins.clone()), (0, vec!["alice".into()]));
+    for profile in [&bob, &carol] {
+        let received = read_all(&mut client, profile);
+        assert_eq!(
+            bodies(&received),
+            [("alice", "added you to team (alice, bob, carol)")]
+        );
+        assert_eq!(received[0].group.as_deref(), Some("team"));
+    }
+
+    // one envelope per member, each readable under the sender's key
+    client.send_to_group(&alice, "team", "hello team").unwrap();
+    client.send_to_group(&bob, "team", "hi alice").unwrap();
+    for profile in [&bob, &carol] {
+        let received = read_all(&mut client, profile);
+        let messages: Vec<_> = received
+            .iter()
+            .filter(|msg| msg.sender == "alice")
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body.as_str(), "hello team");
+        assert_eq!(messages[0].group.as_deref(), Some("team"));
+    }
+    let received = read_all(&mut client, &alice);
+    assert_eq!(bodies(&received), [("bob", "hi alice")]);
+
+    // only admins can change who's in it
+    assert!(matches!(
+        client.remove_from_group(&bob, "team", &["carol"]),
+        Err(Error::Invalid(_))
+    ));
+
+    let info = client
+        .remove_from_group(&alice, "team", &["carol"])
+        .unwrap();
+    assert_eq!(info.epoch, 1);
+    assert!(!info.is_member("carol"));
+    let received = read_all(&mut client, &carol);
+    assert_eq!(bodies(&received), [("alice", "removed you from team")]);
+    let received = read_all(&mut client, &bob);
+    assert_eq!(bodies(&received), [("alice", "removed carol")]);
+
+    // carol gets nothing sent after, and can't send to the group either
+    client.send_to_group(&alice, "team", "just us now").unwrap();
+    assert_eq!(
+        bodies(&read_all(&mut client, &bob)),
+        [("alice", "just us now")]
+    );
+    assert!(read_all(&mut client, &carol).is_empty());
+    assert!(matches!(
+        client.send_to_group(&carol, "team", "wait"),
+        Err(Error::Invalid(_))
+    ));
+
+    let groups = client.groups(&carol).unwrap();
+    assert_eq!(groups.len(), 1);
+    assert!(!groups[0].info.is_member("carol"));
+    assert!(matches!(
+        client.send_to_group(&alice, "other", "hello"),
+        Err(Error::UnknownGroup(_))
+    ));
+}
+
+#[test]
+fn group_updates_never_pin_keys() {
+    use rune_core::contacts::ContactBook;
+    use rune_core::group::{Control, GroupCache, GroupInfo, Member};
+
+    let home = TestHome::new("group-pins");
+    let paths = home.paths();
+    let client = home.client();
+    let alice = common::register(&client, "alice");
+    let bob = common::register(&client, "bob");
+    let mallory = common::register(&client, "mallory");
+    let public = |profile: &Profile| profile.keyring().public;
+
+    let mut contacts = ContactBook::load(&paths, "bob").unwrap();
+    contacts.check("alice", &public(&alice)).unwrap();
+    contacts.check("mallory", &public(&mallory)).unwrap();
+    contacts.save(&paths, "bob").unwrap();
+
+    // mallory's group, claiming her own key is alice's and dave's
+    let forged = |members: &[&str]| {
+        let mut list = vec![
+            Member::new("mallory", &public(&mallory)),
+            Member::new("bob", &public(&bob)),
+        ];
+        list.extend(
+            members
+                .iter()
+                .map(|name| Member::new(name, &public(&mallory))),
+        );
+        GroupInfo::new("forged", "mallory", mallory.keyring(), list).unwrap()
+    };
+
+    let mut groups = GroupCache::new(&paths, "bob", bob.keyring());
+    assert!(matches!(
+        groups.apply(
+            &contacts,
+            "mallory",
+            &public(&mallory),
+            Control::Update(forged(&["alice"]))
+        ),
+        Err(Error::KeyChanged(_))
+    ));
+    // taken, since bob has never heard of dave, but not pinned
+    groups
+        .apply(
+            &contacts,
+            "mallory",
+            &public(&mallory),
+            Control::Update(forged(&["dave"])),
+        )
+        .unwrap();
+    groups.save().unwrap();
+
+    let contacts = ContactBook::load(&paths, "bob").unwrap();
+    assert!(contacts.get("dave").is_none());
+    assert_eq!(
+        contacts.get("alice").unwrap().identity(),
+        Some(public(&alice))
+    );
+}
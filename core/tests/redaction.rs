@@ -0,0 +1,96 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use base64::Engine;
+use rune_core::crypto::keyring::KeyringEncryptor;
+
+mod common;
+use common::TestHome;
+
+const PASSPHRASE: &str = "correct horse battery staple";
+const MESSAGE: &str = "meet me at the usual place";
+
+// Run the CLI against `home` with debug logging, answering every passphrase
+// prompt with PASSPHRASE
+fn rune(home: &Path, args: &[&str]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rune-core"))
+        .args(args)
+        .env("RUNE_HOME", home)
+        .env("RUST_LOG", "debug")
+        .env_remove("RUNE_PASSPHRASE_FILE")
+        .env_remove("RUNE_PINENTRY")
+        .env_remove("RUNE_TRANSPORT")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for _ in 0..4 {
+        let _ = writeln!(stdin, "{}", PASSPHRASE);
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "rune {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+// The ways a secret key might end up in a log line
+fn renderings(secret: &[u8; 32]) -> Vec<String> {
+    vec![
+        hex::encode(secret),
+        hex::encode_upper(secret),
+        base64::engine::general_purpose::STANDARD.encode(secret),
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(secret),
+        format!("{:?}", secret),
+    ]
+}
+
+#[test]
+fn debug_logging_never_prints_secrets() {
+    let home = TestHome::new("redaction");
+
+    let mut outputs = vec![
+        rune(&home, &["register", "alice"]),
+        rune(&home, &["register", "bob"]),
+        rune(&home, &["--as", "alice", "send", "bob", MESSAGE]),
+    ];
+    let received = rune(&home, &["--as", "bob", "receive", "alice"]);
+    assert!(String::from_utf8_lossy(&received.stdout).contains(MESSAGE));
+    // the message belongs on stdout, never in the logs
+    assert!(!String::from_utf8_lossy(&received.stderr).contains(MESSAGE));
+    outputs.push(received);
+
+    // otherwise there's nothing to check
+    assert!(outputs
+        .iter()
+        .any(|output| String::from_utf8_lossy(&output.stderr).contains("DEBUG")));
+
+    let paths = home.paths();
+    let mut secrets = vec![PASSPHRASE.to_string()];
+    for username in ["alice", "bob"] {
+        let keyring =
+            KeyringEncryptor::decrypt(KeyringEncryptor::path(&paths, username), PASSPHRASE)
+                .unwrap();
+        let secret = keyring.secret().unwrap().to_bytes();
+        secrets.extend(renderings(&secret));
+
+        let debug = format!("{:?}", keyring);
+        assert!(renderings(&secret).iter().all(|s| !debug.contains(s)));
+    }
+
+    for output in &outputs {
+        for stream in [&output.stdout, &output.stderr] {
+            let text = String::from_utf8_lossy(stream);
+            for secret in &secrets {
+                assert!(!text.contains(secret.as_str()), "leaked {}", secret);
+            }
+        }
+    }
+}
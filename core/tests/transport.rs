@@ -0,0 +1,236 @@
+use rune_core::cmd::inbox::{list_inbox, open_messages, AfterRead, Verification};
+use rune_core::cmd::send::{send_message, send_to_many};
+use rune_core::config::{Config, Paths};
+use rune_core::crypto::e2ee::CryptoError;
+use rune_core::crypto::keyring::Keyring;
+use rune_core::crypto::prekey::PrekeyStore;
+use rune_core::envelope::Envelope;
+use rune_core::mailbox::Mailbox;
+use rune_core::transport::{fetch_into_mailbox, MemoryTransport, TransportSpec};
+use rune_core::{Error, RuneClient};
+
+mod common;
+use common::TestHome;
+
+fn register(paths: &Paths, username: &str) -> Keyring {
+    std::fs::create_dir_all(paths.user(username)).unwrap();
+
+    let keyring = Keyring::generate();
+    keyring.save_public_key(paths, username).unwrap();
+    let (prekeys, bundle) = PrekeyStore::generate(&keyring, 4).unwrap();
+    prekeys.save(paths, username, &keyring).unwrap();
+    bundle.save(paths, username).unwrap();
+    keyring
+}
+
+fn receive(
+    paths: &Paths,
+    transport: &mut MemoryTransport,
+    username: &str,
+    keyring: &Keyring,
+) -> Vec<String> {
+    fetch_into_mailbox(transport, paths, username, keyring).unwrap();
+    let items = list_inbox(paths, username, false, None).unwrap();
+    assert!(items
+        .iter()
+        .all(|item| item.verification == Verification::Verified));
+    open_messages(paths, username, keyring, items, AfterRead::MarkRead)
+        .unwrap()
+        .received
+        .into_iter()
+        .map(|msg| msg.body.to_string())
+        .collect()
+}
+
+#[test]
+fn messages_round_trip_over_memory_transport() {
+    let home = TestHome::new("transport");
+    let paths = home.paths();
+
+    let alice = register(&paths, "alice");
+    let bob = register(&paths, "bob");
+    let mut transport = MemoryTransport::default();
+
+    for message in ["hello", "are you there?"] {
+        send_message(
+            &paths,
+            "alice",
+            &alice,
+            "bob",
+            message,
+            false,
+            &mut transport,
+            None,
+        )
+        .unwrap();
+    }
+    assert_eq!(
+        receive(&paths, &mut transport, "bob", &bob),
+        ["hello", "are you there?"]
+    );
+    // everything was acknowledged
+    assert_eq!(
+        fetch_into_mailbox(&mut transport, &paths, "bob", &bob).unwrap(),
+        0
+    );
+
+    send_message(
+        &paths,
+        "bob",
+        &bob,
+        "alice",
+        "hi alice",
+        true,
+        &mut transport,
+        None,
+    )
+    .unwrap();
+    assert_eq!(
+        receive(&paths, &mut transport, "alice", &alice),
+        ["hi alice"]
+    );
+}
+
+#[test]
+fn client_registers_sends_and_reads() {
+    let home = TestHome::new("client");
+    let mut client = home.client();
+
+    let alice = common::register(&client, "alice");
+    common::register(&client, "bob");
+    assert!(matches!(
+        client.register("bob", "again"),
+        Err(Error::UsernameTaken(_))
+    ));
+    assert_eq!(client.whoami(None).unwrap(), "bob");
+
+    assert!(matches!(
+        client.unlock("bob", "alice's passphrase"),
+        Err(Error::BadPassphrase)
+    ));
+    let bob = client.unlock("bob", "bob's passphrase").unwrap();
+
+    client.send(&alice, "bob", "hello bob", false).unwrap();
+    assert_eq!(client.fetch(&bob).unwrap(), 1);
+    let pending = client.unread_from("bob", "alice").unwrap();
+    let bodies: Vec<String> = client
+        .read(&bob, pending, AfterRead::MarkRead)
+        .unwrap()
+        .received
+        .into_iter()
+        .map(|msg| msg.body.to_string())
+        .collect();
+    assert_eq!(bodies, ["hello bob"]);
+    // alice was pinned when her first message was read
+    assert!(client.contacts("bob").unwrap().get("alice").is_some());
+}
+
+#[test]
+fn failed_registration_leaves_nothing_behind() {
+    let home = TestHome::new("failed-register");
+    // costs Argon2 refuses, so the keyring can't be encrypted
+    let mut config = Config::load(home.paths()).unwrap();
+    config.kdf.m_cost = 1;
+    let client = RuneClient::new(config, TransportSpec::Fs);
+    assert!(client.register("alice", "alice's passphrase").is_err());
+
+    // no home, and nothing half-built beside where it would be
+    let entries: Vec<_> = std::fs::read_dir(&*home)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, ["rune.toml"]);
+    assert!(client.profiles().unwrap().is_empty());
+
+    // so trying again works
+    let client = home.client();
+    common::register(&client, "alice");
+    assert_eq!(client.whoami(None).unwrap(), "alice");
+}
+
+#[test]
+fn read_session_messages_are_skipped_when_read_again() {
+    let home = TestHome::new("reread");
+    let mut client = home.client();
+    let alice = common::register(&client, "alice");
+    let bob = common::register(&client, "bob");
+    common::register(&client, "carol");
+
+    client
+        .send(&alice, "bob", "over the session", false)
+        .unwrap();
+    client
+        .send_to_many(&alice, &["bob", "carol"], "to both", false)
+        .unwrap();
+    client.fetch(&bob).unwrap();
+    let items = client.inbox("bob", false).unwrap();
+    let opened = client.read(&bob, items, AfterRead::MarkRead).unwrap();
+    assert_eq!(opened.received.len(), 2);
+
+    // like `inbox --all --read`: the session message's keys are gone, the
+    // other one can still be opened
+    let items = client.inbox("bob", true).unwrap();
+    let opened = client.read(&bob, items, AfterRead::MarkRead).unwrap();
+    let bodies: Vec<&str> = opened.received.iter().map(|m| m.body.as_str()).collect();
+    assert_eq!(bodies, ["to both"]);
+    assert_eq!(opened.skipped.len(), 1);
+    assert!(opened.skipped[0].to_string().contains("already read"));
+
+    // and the session itself carries on
+    client.send(&alice, "bob", "still going", false).unwrap();
+    client.fetch(&bob).unwrap();
+    let items = client.inbox("bob", false).unwrap();
+    let opened = client.read(&bob, items, AfterRead::MarkRead).unwrap();
+    assert_eq!(opened.received[0].body.as_str(), "still going");
+}
+
+#[test]
+fn one_envelope_reaches_several_recipients() {
+    let home = TestHome::new("multi");
+    let paths = home.paths();
+
+    let alice = register(&paths, "alice");
+    let bob = register(&paths, "bob");
+    let carol = register(&paths, "carol");
+    let dave = register(&paths, "dave");
+    let mut transport = MemoryTransport::default();
+
+    for hide_recipients in [false, true] {
+        send_to_many(
+            &paths,
+            "alice",
+            &alice,
+            &["bob", "carol"],
+            "standup moved to 10",
+            hide_recipients,
+            &mut transport,
+            None,
+        )
+        .unwrap();
+    }
+
+    fetch_into_mailbox(&mut transport, &paths, "bob", &bob).unwrap();
+    fetch_into_mailbox(&mut transport, &paths, "carol", &carol).unwrap();
+    let bobs = Mailbox::open(&paths, "bob").unwrap();
+    let carols = Mailbox::open(&paths, "carol").unwrap();
+    let envelope = bobs.read(&bobs.list().unwrap()[0]).unwrap();
+    assert_eq!(envelope, carols.read(&carols.list().unwrap()[0]).unwrap());
+    // someone it wasn't sent to can't open it
+    assert!(matches!(
+        Envelope::from_bytes(&envelope).unwrap().open(&dave),
+        Err(CryptoError::NotRecipient)
+    ));
+
+    for (username, keyring) in [("bob", &bob), ("carol", &carol)] {
+        let items = list_inbox(&paths, username, false, None).unwrap();
+        let received = open_messages(&paths, username, keyring, items, AfterRead::MarkRead)
+            .unwrap()
+            .received;
+        assert_eq!(received.len(), 2);
+        assert!(received
+            .iter()
+            .all(|msg| msg.body.as_str() == "standup moved to 10"));
+        assert_eq!(received[0].to, ["bob", "carol"]);
+        assert!(received[1].to.is_empty());
+    }
+}
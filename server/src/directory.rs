@@ -0,0 +1,141 @@
+// Registered usernames, one file per user holding their compressed identity
+// key. An entry is written to a temporary file first and then hard linked
+// into place, which fails if the name is already there, so two clients
+// racing for the same name can't both get it and a crash can't leave a
+// partly written entry behind.
+//
+// Next to each entry is the user's prekey bundle, <name>.bundle. Claiming a
+// one-time prekey rewrites the bundle without it, under a lock so two senders
+// are never given the same one.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use rand::{rngs::OsRng, RngCore};
+use rune_core::relay::valid_username;
+
+pub struct Directory {
+    root: PathBuf,
+    // held while a bundle is read and written back
+    bundles: Mutex<()>,
+}
+
+impl Directory {
+    pub fn open(root: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            bundles: Mutex::new(()),
+        })
+    }
+
+    fn entry_path(&self, username: &str) -> std::io::Result<PathBuf> {
+        self.user_path(username, "pub")
+    }
+
+    fn bundle_path(&self, username: &str) -> std::io::Result<PathBuf> {
+        self.user_path(username, "bundle")
+    }
+
+    fn user_path(&self, username: &str, extension: &str) -> std::io::Result<PathBuf> {
+        if !valid_username(username) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid username {:?}", username),
+            ));
+        }
+        Ok(self.root.join(format!("{}.{}", username, extension)))
+    }
+
+    // Callers must have checked the registration was signed by `identity`
+    pub fn register(&self, username: &str, identity: &[u8; 32]) -> std::io::Result<()> {
+        let path = self.entry_path(username)?;
+        let tmp_path = self
+            .root
+            .join(format!(".{}.pub.{:016x}.tmp", username, OsRng.next_u64()));
+        let linked = (|| {
+            let mut file = std::fs::File::create(&tmp_path)?;
+            file.write_all(identity)?;
+            file.sync_all()?;
+            std::fs::hard_link(&tmp_path, &path)
+        })();
+        let _ = std::fs::remove_file(&tmp_path);
+
+        match linked {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                if &self.lookup(username)? == identity {
+                    Ok(())
+                } else {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("username {} is taken", username),
+                    ))
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn lookup(&self, username: &str) -> std::io::Result<[u8; 32]> {
+        let bytes = match std::fs::read(self.entry_path(username)?) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no user named {}", username),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        bytes.try_into().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("corrupt directory entry for {}", username),
+            )
+        })
+    }
+
+    // Replace `username`'s prekey bundle. Callers must have checked the
+    // connection is theirs; the bundle is checked against their key here.
+    pub fn publish_prekeys(&self, username: &str, bundle: &[u8]) -> std::io::Result<()> {
+        let identity = self.lookup(username)?;
+        rune_core::directory::verify_prekeys(&identity, bundle).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("bad prekey bundle for {}: {}", username, e),
+            )
+        })?;
+        let path = self.bundle_path(username)?;
+        let _lock = self.bundles.lock().unwrap_or_else(|e| e.into_inner());
+        rune_core::util::write_atomic(&path, bundle)
+    }
+
+    // `username`'s prekey bundle with at most one one-time prekey, which is
+    // removed from the stored bundle
+    pub fn claim_prekeys(&self, username: &str) -> std::io::Result<Option<Vec<u8>>> {
+        use rune_core::crypto::prekey::PrekeyBundle;
+
+        let path = self.bundle_path(username)?;
+        let _lock = self.bundles.lock().unwrap_or_else(|e| e.into_inner());
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut bundle = PrekeyBundle::from_bytes(&bytes).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("corrupt prekey bundle for {}: {}", username, e),
+            )
+        })?;
+        let one_time = bundle.claim_one_time();
+        if one_time.is_some() {
+            rune_core::util::write_atomic(&path, &bundle.to_bytes())?;
+        }
+        bundle.one_time_prekeys = one_time.into_iter().collect();
+        Ok(Some(bundle.to_bytes()))
+    }
+}
@@ -0,0 +1,246 @@
+// Store-and-forward relay for rune envelopes
+//
+// See `rune_core::relay` for the protocol. The storage directory holds the
+// message queues under queues/ and the username directory, with users' prekey
+// bundles, under directory/.
+// Each connection is served on its own thread. `Server::spawn` runs the relay
+// in the background of the current process, which is how the integration
+// tests use it.
+
+pub mod directory;
+pub mod storage;
+
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use rand::{rngs::OsRng, RngCore};
+use rune_core::relay::{read_frame, write_frame, Request, Response};
+
+use directory::Directory;
+use storage::FileStorage;
+
+struct State {
+    storage: FileStorage,
+    directory: Directory,
+}
+
+pub struct Server {
+    listener: TcpListener,
+    state: Arc<State>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Server {
+    // Listen on `addr` (use port 0 for any free port), keeping queues under `storage_dir`
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        storage_dir: impl Into<PathBuf>,
+    ) -> std::io::Result<Self> {
+        let storage_dir = storage_dir.into();
+        let state = State {
+            storage: FileStorage::open(storage_dir.join("queues"))?,
+            directory: Directory::open(storage_dir.join("directory"))?,
+        };
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            state: Arc::new(state),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Serve connections until shut down through a `ServerHandle`
+    pub fn run(self) -> std::io::Result<()> {
+        log::info!("relay listening on {}", self.local_addr()?);
+        for stream in self.listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let state = self.state.clone();
+            std::thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = serve(stream, &state) {
+                    log::warn!("connection from {:?} failed: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // Run the relay on a background thread
+    pub fn spawn(self) -> std::io::Result<ServerHandle> {
+        let addr = self.local_addr()?;
+        let shutdown = self.shutdown.clone();
+        let thread = std::thread::spawn(move || self.run());
+        Ok(ServerHandle {
+            addr,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+}
+
+// A relay running in the background; it's stopped when the handle is dropped
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl ServerHandle {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn shutdown(mut self) -> std::io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> std::io::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it sees the flag
+        let _ = TcpStream::connect(self.addr);
+        thread
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("relay thread panicked")))
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+// What a connection has done so far
+#[derive(Default)]
+struct Connection {
+    // the last challenge handed out, good for one registration or
+    // authentication
+    challenge: Option<[u8; 32]>,
+    // whose messages this connection may fetch and acknowledge, and whose
+    // prekeys it may publish
+    authenticated: Option<String>,
+}
+
+impl Connection {
+    fn take_challenge(&mut self, action: &str) -> std::io::Result<[u8; 32]> {
+        self.challenge
+            .take()
+            .ok_or_else(|| denied(format!("request a challenge before {}", action)))
+    }
+
+    fn check_recipient(&self, recipient: &str) -> std::io::Result<()> {
+        self.check_user(recipient, "fetch their messages")
+    }
+
+    fn check_user(&self, username: &str, action: &str) -> std::io::Result<()> {
+        match self.authenticated.as_deref() {
+            Some(authenticated) if authenticated == username => Ok(()),
+            _ => Err(denied(format!(
+                "authenticate as {} to {}",
+                username, action
+            ))),
+        }
+    }
+}
+
+fn denied(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, message)
+}
+
+fn serve(mut stream: TcpStream, state: &State) -> std::io::Result<()> {
+    let mut connection = Connection::default();
+    while let Some(request) = read_frame::<Request>(&mut stream)? {
+        let response = handle(state, &mut connection, request)
+            .unwrap_or_else(|e| Response::Error(e.to_string()));
+        write_frame(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+fn handle(
+    state: &State,
+    connection: &mut Connection,
+    request: Request,
+) -> std::io::Result<Response> {
+    match request {
+        Request::Deliver {
+            recipient,
+            envelope,
+        } => {
+            let id = state.storage.deliver(&recipient, &envelope)?;
+            log::debug!("queued {} for {}", id, recipient);
+            Ok(Response::Delivered { id })
+        }
+        Request::Fetch { recipient } => {
+            connection.check_recipient(&recipient)?;
+            Ok(Response::Messages(state.storage.fetch(&recipient)?))
+        }
+        Request::Ack { recipient, ids } => {
+            connection.check_recipient(&recipient)?;
+            let removed = state.storage.ack(&recipient, &ids)?;
+            log::debug!("{} acknowledged {} messages", recipient, removed);
+            Ok(Response::Acked { removed })
+        }
+        Request::Challenge => {
+            let mut nonce = [0u8; 32];
+            OsRng.fill_bytes(&mut nonce);
+            connection.challenge = Some(nonce);
+            Ok(Response::Challenge { nonce })
+        }
+        Request::Authenticate {
+            username,
+            signature,
+        } => {
+            let nonce = connection.take_challenge("authenticating")?;
+            let identity = state.directory.lookup(&username)?;
+            rune_core::directory::verify_authentication(&username, &identity, &nonce, &signature)
+                .map_err(|e| denied(format!("authentication as {} rejected: {}", username, e)))?;
+            log::debug!("connection authenticated as {}", username);
+            connection.authenticated = Some(username);
+            Ok(Response::Authenticated)
+        }
+        Request::Register {
+            username,
+            identity,
+            signature,
+        } => {
+            let nonce = connection.take_challenge("registering")?;
+            rune_core::directory::verify_registration(&username, &identity, &nonce, &signature)
+                .map_err(|e| denied(format!("registration for {} rejected: {}", username, e)))?;
+            state.directory.register(&username, &identity)?;
+            log::info!("registered {}", username);
+            Ok(Response::Registered)
+        }
+        Request::Lookup { username } => Ok(Response::Identity {
+            identity: state.directory.lookup(&username)?,
+        }),
+        Request::PublishPrekeys { username, bundle } => {
+            connection.check_user(&username, "publish their prekeys")?;
+            state.directory.publish_prekeys(&username, &bundle)?;
+            log::debug!("{} published prekeys", username);
+            Ok(Response::Published)
+        }
+        Request::ClaimPrekeys { username } => Ok(Response::Prekeys {
+            bundle: state.directory.claim_prekeys(&username)?,
+        }),
+    }
+}
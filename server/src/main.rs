@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg};
+
+use rune_core::relay::DEFAULT_ADDR;
+use rune_server::Server;
+
+fn main() {
+    pretty_env_logger::try_init().ok();
+
+    let matches = App::new("rune-server")
+        .about("store-and-forward relay for rune messages")
+        .version("0.1.0")
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .default_value(DEFAULT_ADDR)
+                .help("Address to listen on"),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .long("storage")
+                .takes_value(true)
+                .help("Directory to queue messages in [default: $XDG_DATA_HOME/rune-server]"),
+        )
+        .get_matches();
+
+    let storage = match matches.value_of("storage").map(PathBuf::from) {
+        Some(dir) => dir,
+        None => match dirs::data_dir() {
+            Some(dir) => dir.join("rune-server"),
+            None => {
+                eprintln!("no data directory for this user, pass --storage");
+                std::process::exit(1);
+            }
+        },
+    };
+    let listen = matches.value_of("listen").unwrap();
+
+    let server = match Server::bind(listen, &storage) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("failed to start relay on {}: {}", listen, e);
+            std::process::exit(1);
+        }
+    };
+    log::info!("queueing messages in {}", storage.display());
+    if let Err(e) = server.run() {
+        eprintln!("relay stopped: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,64 @@
+// Queued envelopes, one Maildir-style mailbox per recipient under the
+// storage root. Delivery into a mailbox is atomic, so a crash never leaves a
+// half written envelope in a queue.
+
+use std::path::PathBuf;
+
+use rune_core::mailbox::{Mailbox, MessageId};
+use rune_core::relay::{fetch_batch, valid_username, QueuedMessage};
+
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(root: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn mailbox(&self, recipient: &str) -> std::io::Result<Mailbox> {
+        if !valid_username(recipient) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid username {:?}", recipient),
+            ));
+        }
+        Mailbox::open_at(self.root.join(recipient))
+    }
+
+    pub fn deliver(&self, recipient: &str, envelope: &[u8]) -> std::io::Result<String> {
+        Ok(self.mailbox(recipient)?.deliver(envelope)?.to_string())
+    }
+
+    pub fn fetch(&self, recipient: &str) -> std::io::Result<Vec<QueuedMessage>> {
+        let mailbox = self.mailbox(recipient)?;
+        let mut messages = Vec::new();
+        for entry in fetch_batch(mailbox.unread()?, |entry| entry.size) {
+            messages.push(QueuedMessage {
+                envelope: mailbox.read(&entry)?,
+                id: entry.id.to_string(),
+            });
+        }
+        Ok(messages)
+    }
+
+    // Delete acknowledged messages, returning how many were still queued
+    pub fn ack(&self, recipient: &str, ids: &[String]) -> std::io::Result<usize> {
+        let mailbox = self.mailbox(recipient)?;
+        let mut removed = 0;
+        for id in ids {
+            let id: MessageId = id.parse()?;
+            match mailbox.get(&id) {
+                Ok(entry) => {
+                    mailbox.remove(&entry)?;
+                    removed += 1;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+}
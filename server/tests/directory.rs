@@ -0,0 +1,174 @@
+use rune_core::crypto::keyring::Keyring;
+use rune_core::relay::{RelayClient, Request, Response};
+use rune_server::Server;
+
+#[test]
+fn usernames_are_unique_and_resolve_to_keys() {
+    let dir = std::env::temp_dir().join(format!("rune-directory-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = Server::bind("127.0.0.1:0", &dir).unwrap().spawn().unwrap();
+
+    let alice = Keyring::generate();
+    let mallory = Keyring::generate();
+
+    let mut client = RelayClient::connect(server.addr()).unwrap();
+    client.register("alice", &alice).unwrap();
+    // registering the same key again is fine, another key is not
+    client.register("alice", &alice).unwrap();
+    assert!(client.register("alice", &mallory).is_err());
+
+    let mut other = RelayClient::connect(server.addr()).unwrap();
+    assert_eq!(other.lookup("alice").unwrap(), alice.public);
+    assert!(other.lookup("bob").is_err());
+
+    // a registration must be signed over a challenge by the key being registered
+    let forged = Request::Register {
+        username: "bob".to_string(),
+        identity: mallory.public.compress().to_bytes(),
+        signature: vec![0u8; 64],
+    };
+    assert!(other.request(&forged).is_err());
+    let Response::Challenge { .. } = other.request(&Request::Challenge).unwrap() else {
+        panic!("expected a challenge");
+    };
+    assert!(other.request(&forged).is_err());
+    assert!(other.lookup("bob").is_err());
+
+    drop(server);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn senders_are_looked_up_in_the_directory() {
+    use rune_core::cmd::inbox::{AfterRead, Verification};
+    use rune_core::config::{Config, Paths};
+    use rune_core::transport::TransportSpec;
+    use rune_core::RuneClient;
+
+    let dir = std::env::temp_dir().join(format!("rune-senders-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = Server::bind("127.0.0.1:0", dir.join("relay"))
+        .unwrap()
+        .spawn()
+        .unwrap();
+
+    // alice and bob on different machines, sharing only the relay
+    let machine = |name: &str| {
+        let home = dir.join(name);
+        std::fs::create_dir_all(&home).unwrap();
+        std::fs::write(
+            home.join("rune.toml"),
+            "[kdf]\nm_cost = 8\nt_cost = 1\np_cost = 1\n",
+        )
+        .unwrap();
+        let spec = TransportSpec::Relay(server.addr().to_string());
+        RuneClient::new(Config::load(Paths::at(&home)).unwrap(), spec)
+    };
+    let mut alices = machine("alice");
+    let mut bobs = machine("bob");
+    let alice = alices.register("alice", "alice's passphrase").unwrap();
+    let bob = bobs.register("bob", "bob's passphrase").unwrap();
+
+    alices
+        .send(&alice, "bob", "hello from afar", false)
+        .unwrap();
+    bobs.fetch(&bob).unwrap();
+    let items = bobs.inbox("bob", false).unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].verification, Verification::Verified);
+
+    let opened = bobs.read(&bob, items, AfterRead::MarkRead).unwrap();
+    assert!(opened.skipped.is_empty());
+    assert_eq!(opened.received[0].body.as_str(), "hello from afar");
+
+    // alice's session started from the first one-time prekey bob published
+    let bundle = RelayClient::connect(server.addr())
+        .unwrap()
+        .claim_prekeys("bob")
+        .unwrap()
+        .unwrap();
+    assert_eq!(bundle.one_time_prekeys[0].id, 1);
+
+    drop(server);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn racing_registrations_leave_one_whole_entry() {
+    use rune_server::directory::Directory;
+
+    let dir = std::env::temp_dir().join(format!("rune-race-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let directory = std::sync::Arc::new(Directory::open(&dir).unwrap());
+
+    let identities: Vec<[u8; 32]> = (0..8u8).map(|n| [n; 32]).collect();
+    let winners: Vec<[u8; 32]> = identities
+        .iter()
+        .map(|&identity| {
+            let directory = directory.clone();
+            std::thread::spawn(move || directory.register("alice", &identity).map(|_| identity))
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .filter_map(|thread| thread.join().unwrap().ok())
+        .collect();
+
+    assert_eq!(winners.len(), 1);
+    assert_eq!(directory.lookup("alice").unwrap(), winners[0]);
+    // no temporary files left behind
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn each_one_time_prekey_is_claimed_once() {
+    use rune_core::crypto::prekey::PrekeyStore;
+
+    let dir = std::env::temp_dir().join(format!("rune-prekeys-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = Server::bind("127.0.0.1:0", &dir).unwrap().spawn().unwrap();
+
+    let bob = Keyring::generate();
+    let mallory = Keyring::generate();
+    let (_, bundle) = PrekeyStore::generate(&bob, 2).unwrap();
+    let (_, forged) = PrekeyStore::generate(&mallory, 2).unwrap();
+
+    let mut client = RelayClient::connect(server.addr()).unwrap();
+    assert!(client.claim_prekeys("bob").unwrap().is_none());
+    client.register("bob", &bob).unwrap();
+    // only bob can publish, and only a bundle signed by his key
+    assert!(client.publish_prekeys("bob", &bundle).is_err());
+    client.authenticate("bob", &bob).unwrap();
+    assert!(client.publish_prekeys("bob", &forged).is_err());
+    client.publish_prekeys("bob", &bundle).unwrap();
+
+    let claims: Vec<_> = (0..3)
+        .map(|_| {
+            let addr = server.addr();
+            std::thread::spawn(move || {
+                RelayClient::connect(addr)
+                    .unwrap()
+                    .claim_prekeys("bob")
+                    .unwrap()
+                    .unwrap()
+            })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect();
+    let mut claimed = Vec::new();
+    for claim in claims {
+        claim.verify(&bob.public).unwrap();
+        assert!(claim.one_time_prekeys.len() <= 1);
+        claimed.extend(claim.one_time_prekeys.into_iter().map(|p| p.id));
+    }
+    claimed.sort();
+    let mut published: Vec<u32> = bundle.one_time_prekeys.iter().map(|p| p.id).collect();
+    published.sort();
+    assert_eq!(claimed, published);
+
+    drop(server);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+use rune_core::crypto::keyring::Keyring;
+use rune_core::relay::{RelayClient, Request};
+use rune_server::Server;
+
+fn storage_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rune-server-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn queues_until_acknowledged() {
+    let dir = storage_dir("queue");
+    let server = Server::bind("127.0.0.1:0", &dir).unwrap().spawn().unwrap();
+
+    let mut alice = RelayClient::connect(server.addr()).unwrap();
+    alice.deliver("bob", b"first").unwrap();
+    alice.deliver("bob", b"second").unwrap();
+
+    let keyring = Keyring::generate();
+    let mut bob = RelayClient::connect(server.addr()).unwrap();
+    bob.register("bob", &keyring).unwrap();
+    bob.authenticate("bob", &keyring).unwrap();
+    let messages = bob.fetch("bob").unwrap();
+    let envelopes: Vec<&[u8]> = messages.iter().map(|m| m.envelope.as_slice()).collect();
+    assert_eq!(envelopes, [b"first".as_slice(), b"second".as_slice()]);
+
+    // fetching alone doesn't remove anything
+    assert_eq!(bob.fetch("bob").unwrap().len(), 2);
+
+    let ids: Vec<String> = messages.into_iter().map(|m| m.id).collect();
+    assert_eq!(bob.ack("bob", &ids[..1]).unwrap(), 1);
+    assert_eq!(bob.fetch("bob").unwrap()[0].envelope, b"second");
+    assert_eq!(bob.ack("bob", &ids).unwrap(), 1);
+    assert!(bob.fetch("bob").unwrap().is_empty());
+
+    server.shutdown().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_bad_usernames() {
+    let dir = storage_dir("usernames");
+    let server = Server::bind("127.0.0.1:0", &dir).unwrap().spawn().unwrap();
+
+    let mut client = RelayClient::connect(server.addr()).unwrap();
+    assert!(client.deliver("../escape", b"envelope").is_err());
+    assert!(client.fetch("").is_err());
+    // the connection is still usable after an error
+    client.deliver("bob", b"envelope").unwrap();
+
+    drop(server);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn only_the_recipient_fetches_or_acks() {
+    let dir = storage_dir("auth");
+    let server = Server::bind("127.0.0.1:0", &dir).unwrap().spawn().unwrap();
+
+    let bob = Keyring::generate();
+    let mallory = Keyring::generate();
+    let mut client = RelayClient::connect(server.addr()).unwrap();
+    client.register("bob", &bob).unwrap();
+    client.register("mallory", &mallory).unwrap();
+    let ids = vec![client.deliver("bob", b"for bob").unwrap()];
+
+    // not without authenticating, nor as someone else
+    assert!(client.fetch("bob").is_err());
+    assert!(client.ack("bob", &ids).is_err());
+    client.authenticate("mallory", &mallory).unwrap();
+    assert!(client.fetch("bob").is_err());
+    assert!(client.ack("bob", &ids).is_err());
+    assert!(client.fetch("mallory").unwrap().is_empty());
+    // nor by signing with another key, or without a challenge
+    assert!(client.authenticate("bob", &mallory).is_err());
+    let forged = Request::Authenticate {
+        username: "bob".to_string(),
+        signature: vec![0u8; 64],
+    };
+    assert!(client.request(&forged).is_err());
+    // an unregistered name can't be authenticated as
+    assert!(client.authenticate("carol", &mallory).is_err());
+
+    let mut bobs = RelayClient::connect(server.addr()).unwrap();
+    bobs.authenticate("bob", &bob).unwrap();
+    assert_eq!(bobs.fetch("bob").unwrap()[0].envelope, b"for bob");
+    assert_eq!(bobs.ack("bob", &ids).unwrap(), 1);
+
+    drop(server);
+    std::fs::remove_dir_all(dir).unwrap();
+}